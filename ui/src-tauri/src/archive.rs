@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::{flat_name, has_image_extension};
+
+/// Extracts the image entries of `zip_path` into `dest`, flattened the same way as
+/// recursive folder input. Non-images are skipped; absolute names are treated as
+/// relative. Entries whose names would escape `dest` through `..` are skipped and
+/// passed to `on_unsafe(name)`. `on_file(i, total, name)` is called after each
+/// extracted image. Returns staged file name -> entry name.
+pub(crate) fn extract_images(
+    zip_path: &Path,
+    dest: &Path,
+    mut on_file: impl FnMut(usize, usize, &str),
+    mut on_unsafe: impl FnMut(&str),
+) -> Result<HashMap<String, String>, String> {
+    let fail = |e: zip::result::ZipError| format!("Failed to read {}: {e}", zip_path.display());
+    let file =
+        File::open(zip_path).map_err(|e| format!("Failed to open {}: {e}", zip_path.display()))?;
+    let mut archive = ZipArchive::new(file).map_err(fail)?;
+
+    let mut images = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i).map_err(fail)?;
+        let Some(relative) = entry.enclosed_name() else {
+            on_unsafe(&String::from_utf8_lossy(entry.name_raw()));
+            continue;
+        };
+        let hidden = relative
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+        if entry.is_file() && !hidden && has_image_extension(&relative) {
+            images.push((i, relative));
+        }
+    }
+
+    let total = images.len();
+    let mut staged = HashMap::new();
+    for (n, (i, relative)) in images.into_iter().enumerate() {
+        let name = flat_name(&relative, |name| staged.contains_key(name));
+        let mut entry = archive.by_index(i).map_err(fail)?;
+        let out_path = dest.join(&name);
+        let mut out = File::create(&out_path)
+            .map_err(|e| format!("Failed to create {}: {e}", out_path.display()))?;
+        io::copy(&mut entry, &mut out)
+            .map_err(|e| format!("Failed to extract {}: {e}", relative.display()))?;
+        on_file(n + 1, total, &name);
+        staged.insert(name, relative.to_string_lossy().to_string());
+    }
+    Ok(staged)
+}
+
+/// Writes `files` into a new archive at `out`, each under its file name.
+/// `on_file(i, total, name)` is called after each file is compressed.
+pub(crate) fn zip_files(
+    files: &[PathBuf],
+    out: &Path,
+    mut on_file: impl FnMut(usize, usize, &str),
+) -> Result<(), String> {
+    let fail = |e: zip::result::ZipError| format!("Failed to write {}: {e}", out.display());
+    let file = File::create(out).map_err(|e| format!("Failed to create {}: {e}", out.display()))?;
+    let mut writer = ZipWriter::new(file);
+    for (i, path) in files.iter().enumerate() {
+        let name = path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .ok_or_else(|| format!("Invalid file path: {}", path.display()))?;
+        let bytes =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        writer
+            .start_file(name.as_str(), SimpleFileOptions::default())
+            .map_err(fail)?;
+        writer
+            .write_all(&bytes)
+            .map_err(|e| format!("Failed to write {}: {e}", out.display()))?;
+        on_file(i + 1, files.len(), &name);
+    }
+    writer.finish().map_err(fail)?;
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Shared between a running job and [`cancel`]. The job polls it and stops itself.
+#[derive(Debug, Default)]
+pub(crate) struct CancelToken {
+    /// `Some` once cancelled; the inner value is the caller's reason, if any.
+    reason: Mutex<Option<Option<String>>>,
+    /// Set while the job waits for its turn and has no process yet.
+    queued: AtomicBool,
+}
+
+impl CancelToken {
+    /// `Some` once the run was cancelled, carrying the event/error message to report.
+    pub(crate) fn message(&self) -> Option<String> {
+        let reason = self.reason.lock().unwrap_or_else(|e| e.into_inner());
+        reason
+            .as_ref()
+            .map(|reason| cancelled_message(reason.as_deref()))
+    }
+
+    pub(crate) fn set_queued(&self, queued: bool) {
+        self.queued.store(queued, Ordering::SeqCst);
+    }
+}
+
+/// What a cancelled job was doing when [`cancel`] reached it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum CancelledState {
+    /// Started; its process, if any, is killed.
+    Running,
+    /// Still waiting to start; it leaves the queue without ever spawning a process.
+    Queued,
+}
+
+/// Every cancelled run's error starts with this, so callers can tell a cancellation
+/// from a failure.
+pub(crate) const CANCELLED_PREFIX: &str = "Cancelled";
+
+pub(crate) fn cancelled_message(reason: Option<&str>) -> String {
+    match reason.map(str::trim) {
+        Some(reason) if !reason.is_empty() => format!("{CANCELLED_PREFIX}: {reason}"),
+        _ => format!("{CANCELLED_PREFIX} by user"),
+    }
+}
+
+static TOKENS: Mutex<Option<HashMap<String, Arc<CancelToken>>>> = Mutex::new(None);
+
+/// Makes `run_id` cancellable until the guard is dropped. A stale entry from an earlier
+/// run with the same id is replaced.
+pub(crate) fn register(run_id: &str) -> CancelGuard {
+    let token = Arc::new(CancelToken::default());
+    TOKENS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(run_id.to_string(), token.clone());
+    CancelGuard {
+        run_id: run_id.to_string(),
+        token,
+    }
+}
+
+pub(crate) fn token(run_id: &str) -> Option<Arc<CancelToken>> {
+    TOKENS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|tokens| tokens.get(run_id).cloned())
+}
+
+/// Asks the run to stop. Returns `None` if no such run is in flight.
+pub(crate) fn cancel(run_id: &str, reason: Option<String>) -> Option<CancelledState> {
+    let token = token(run_id)?;
+    token
+        .reason
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert(reason);
+    Some(if token.queued.load(Ordering::SeqCst) {
+        CancelledState::Queued
+    } else {
+        CancelledState::Running
+    })
+}
+
+pub(crate) struct CancelGuard {
+    run_id: String,
+    token: Arc<CancelToken>,
+}
+
+impl CancelGuard {
+    pub(crate) fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        let mut tokens = TOKENS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tokens) = tokens.as_mut() {
+            // A newer run may have taken over the id; leave its token alone.
+            if tokens
+                .get(&self.run_id)
+                .is_some_and(|t| Arc::ptr_eq(t, &self.token))
+            {
+                tokens.remove(&self.run_id);
+            }
+        }
+    }
+}
+
+/// Kills `child` together with the stage scripts it started. On Unix the child must
+/// lead its own process group, see `pipeline_command`.
+pub(crate) fn kill_process_tree(child: &mut Child) {
+    let pid = child.id().to_string();
+    let killed = if cfg!(windows) {
+        Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid])
+            .output()
+    } else {
+        Command::new("kill")
+            .args(["-KILL", "--", &format!("-{pid}")])
+            .output()
+    };
+    if !killed.is_ok_and(|out| out.status.success()) {
+        let _ = child.kill();
+    }
+    let _ = child.wait();
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+
+use crate::gpu::{self, CudaDevice, DeviceProbe};
+use crate::{models, VersionInfo, KEY_PYTHON_MODULES};
+
+/// One line of the setup checklist.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Check {
+    pub(crate) name: String,
+    pub(crate) passed: bool,
+    /// Whether a failure keeps runs from working. GPU problems don't: runs fall back
+    /// to the CPU.
+    pub(crate) required: bool,
+    pub(crate) detail: String,
+    /// What to do about a failure.
+    pub(crate) fix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Diagnosis {
+    /// Every required check passed.
+    pub(crate) ready: bool,
+    pub(crate) checks: Vec<Check>,
+    pub(crate) gpus: Vec<CudaDevice>,
+    pub(crate) version: VersionInfo,
+}
+
+fn check(name: &str, required: bool, result: Result<String, (String, String)>) -> Check {
+    let (passed, detail, fix) = match result {
+        Ok(detail) => (true, detail, None),
+        Err((detail, fix)) => (false, detail, Some(fix)),
+    };
+    Check {
+        name: name.to_string(),
+        passed,
+        required,
+        detail,
+        fix,
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ModuleCheck {
+    pub(crate) module: &'static str,
+    /// What to `pip install` when it is missing.
+    pub(crate) package: &'static str,
+    pub(crate) found: bool,
+}
+
+/// What a quick look at an interpreter found, without running the pipeline.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Environment {
+    /// e.g. `3.10.12`.
+    pub(crate) python_version: String,
+    pub(crate) modules: Vec<ModuleCheck>,
+}
+
+impl Environment {
+    pub(crate) fn missing(&self) -> Vec<&'static str> {
+        self.modules
+            .iter()
+            .filter(|m| !m.found)
+            .map(|m| m.module)
+            .collect()
+    }
+}
+
+/// Asks `python` for its version and which of [`KEY_PYTHON_MODULES`] it can import.
+/// Modules are only looked up, not imported, so this stays quick even with torch.
+pub(crate) fn check_environment(python: &str) -> Result<Environment, String> {
+    crate::check_interpreter(python)?;
+    let names: Vec<_> = KEY_PYTHON_MODULES.iter().map(|(m, _)| *m).collect();
+    let snippet = format!(
+        "import importlib.util, sys\nprint(sys.version.split()[0])\nfor m in {names:?}:\n    if importlib.util.find_spec(m) is None:\n        print(m)\n"
+    );
+    let output = Command::new(python)
+        .arg("-c")
+        .arg(snippet)
+        .output()
+        .map_err(|e| format!("Failed to start python \"{python}\": {e}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Python \"{python}\" exited with status: {}",
+            output.status
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines().map(str::trim);
+    let python_version = lines.next().unwrap_or_default().to_string();
+    let missing: Vec<_> = lines.collect();
+    Ok(Environment {
+        python_version,
+        modules: KEY_PYTHON_MODULES
+            .iter()
+            .map(|&(module, package)| ModuleCheck {
+                module,
+                package,
+                found: !missing.contains(&module),
+            })
+            .collect(),
+    })
+}
+
+/// Fails, naming the first missing package, unless `python` can run the pipeline.
+pub(crate) fn preflight(python: &str) -> Result<(), String> {
+    let environment = check_environment(python)?;
+    match environment.modules.iter().find(|m| !m.found) {
+        Some(m) => Err(format!(
+            "Python module '{}' is not installed for \"{python}\"; install it with `pip install {}`",
+            m.module, m.package
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Checks everything a run needs: the interpreter, its packages, the checkpoints and
+/// the GPUs. The Python probes run in parallel.
+pub(crate) fn run(root: &Path, python: &str) -> Diagnosis {
+    let interpreter = crate::check_interpreter(python);
+    let (modules, devices) = if interpreter.is_ok() {
+        thread::scope(|scope| {
+            let modules = scope.spawn(|| check_environment(python).map(|env| env.missing()));
+            let devices = scope.spawn(|| gpu::probe_devices(python));
+            (
+                modules
+                    .join()
+                    .unwrap_or_else(|_| Err("Check crashed".to_string())),
+                devices
+                    .join()
+                    .unwrap_or_else(|_| Err("Check crashed".to_string())),
+            )
+        })
+    } else {
+        let skipped = "Skipped: no usable interpreter".to_string();
+        (Err(skipped.clone()), Err(skipped))
+    };
+
+    let mut checks = vec![check(
+        "Python interpreter",
+        true,
+        interpreter
+            .map(|_| format!("Using {python}"))
+            .map_err(|e| (e, "Set the path to a Python 3 interpreter".to_string())),
+    )];
+    checks.push(check(
+        "Python packages",
+        true,
+        match modules {
+            Ok(missing) if missing.is_empty() => Ok("All required packages found".to_string()),
+            Ok(missing) => {
+                let packages: Vec<_> = KEY_PYTHON_MODULES
+                    .iter()
+                    .filter(|(m, _)| missing.contains(m))
+                    .map(|(_, package)| *package)
+                    .collect();
+                Err((
+                    format!("Missing: {}", missing.join(", ")),
+                    format!("pip install {}", packages.join(" ")),
+                ))
+            }
+            Err(e) => Err((e, "Check that the interpreter starts".to_string())),
+        },
+    ));
+    let missing_assets = models::missing_assets(root);
+    checks.push(check(
+        "Model weights",
+        true,
+        if missing_assets.is_empty() {
+            Ok("All checkpoints found".to_string())
+        } else {
+            let names: Vec<_> = missing_assets.iter().map(|a| a.name).collect();
+            Err((
+                format!("Missing: {}", names.join(", ")),
+                "Download them with ensure_models".to_string(),
+            ))
+        },
+    ));
+    let gpus = devices.as_ref().map(|p| p.cuda.clone()).unwrap_or_default();
+    checks.push(check(
+        "CUDA GPUs",
+        false,
+        match devices {
+            Ok(DeviceProbe { cuda, .. }) if !cuda.is_empty() => {
+                Ok(format!("{} GPU(s) found", cuda.len()))
+            }
+            Ok(DeviceProbe { mps: true, .. }) => Err((
+                "No CUDA GPUs detected; Apple MPS is available".to_string(),
+                "Select mps; stages that only support CUDA still run on the CPU".to_string(),
+            )),
+            Ok(_) => Err((
+                "Neither CUDA nor MPS is available".to_string(),
+                "Only CPU mode (GPU -1) will work, which is much slower".to_string(),
+            )),
+            Err(e) => Err((e, "Runs can still use the CPU (GPU -1)".to_string())),
+        },
+    ));
+
+    Diagnosis {
+        ready: checks.iter().all(|c| c.passed || !c.required),
+        checks,
+        gpus,
+        version: crate::version_info(),
+    }
+}
@@ -0,0 +1,145 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::cancel;
+
+/// Why a run failed, serialized to the webview as `{ kind, message, code }` so it can
+/// tell a missing input from a crashed script without parsing `message`, which holds
+/// the same text the String errors of the other commands do.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ModifyError {
+    InputNotFound {
+        path: String,
+    },
+    UnsupportedImage {
+        path: String,
+    },
+    /// The interpreter named by `python` is missing or can't be run.
+    InterpreterNotFound(String),
+    /// Spawning run.py failed.
+    PythonSpawnFailed(String),
+    /// run.py exited unsuccessfully. `code` is `None` when it was ended by a signal;
+    /// `message` includes the tail of its stderr.
+    PythonExited {
+        code: Option<i32>,
+        message: String,
+    },
+    /// run.py printed nothing for `stall_timeout_secs` and was killed.
+    Stalled(String),
+    /// Killed on its first error because of `stop_on_error`.
+    StoppedOnError(String),
+    /// run.py finished but `dir` holds no usable result.
+    NoOutput {
+        dir: String,
+    },
+    /// Starts with [`cancel::CANCELLED_PREFIX`].
+    Cancelled(String),
+    Other(String),
+}
+
+impl ModifyError {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::InputNotFound { .. } => "inputNotFound",
+            Self::UnsupportedImage { .. } => "unsupportedImage",
+            Self::InterpreterNotFound(_) => "interpreterNotFound",
+            Self::PythonSpawnFailed(_) => "pythonSpawnFailed",
+            Self::PythonExited { .. } => "pythonExited",
+            Self::Stalled(_) => "stalled",
+            Self::StoppedOnError(_) => "stoppedOnError",
+            Self::NoOutput { .. } => "noOutput",
+            Self::Cancelled(_) => "cancelled",
+            Self::Other(_) => "other",
+        }
+    }
+
+    /// Puts `context` in front of the message, e.g. which file a failed test run
+    /// tried.
+    pub(crate) fn context(self, context: &str) -> Self {
+        self.map_message(|message| format!("{context}: {message}"))
+    }
+
+    /// Rewrites the message with `add`, keeping the kind. Errors naming a path already
+    /// say where they happened, and a cancellation must keep its prefix, so those are
+    /// left alone.
+    pub(crate) fn map_message(self, add: impl FnOnce(String) -> String) -> Self {
+        match self {
+            Self::InterpreterNotFound(m) => Self::InterpreterNotFound(add(m)),
+            Self::PythonSpawnFailed(m) => Self::PythonSpawnFailed(add(m)),
+            Self::PythonExited { code, message } => Self::PythonExited {
+                code,
+                message: add(message),
+            },
+            Self::Stalled(m) => Self::Stalled(add(m)),
+            Self::StoppedOnError(m) => Self::StoppedOnError(add(m)),
+            Self::Other(m) => Self::Other(add(m)),
+            Self::InputNotFound { .. }
+            | Self::UnsupportedImage { .. }
+            | Self::NoOutput { .. }
+            | Self::Cancelled(_) => self,
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled(_))
+    }
+}
+
+impl fmt::Display for ModifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InputNotFound { path } => write!(f, "Input not found: {path}"),
+            Self::UnsupportedImage { path } => {
+                write!(f, "Selected file is not a supported image: {path}")
+            }
+            Self::NoOutput { dir } => write!(f, "No output image found under {dir}"),
+            Self::PythonExited { message, .. }
+            | Self::InterpreterNotFound(message)
+            | Self::PythonSpawnFailed(message)
+            | Self::Stalled(message)
+            | Self::StoppedOnError(message)
+            | Self::Cancelled(message)
+            | Self::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Serialize for ModifyError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Wire<'a> {
+            kind: &'a str,
+            message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            code: Option<i32>,
+        }
+        let code = match self {
+            Self::PythonExited { code, .. } => *code,
+            _ => None,
+        };
+        Wire {
+            kind: self.kind(),
+            message: self.to_string(),
+            code,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Failures from helpers that still report plain strings. Cancellation messages keep
+/// their kind, since every one starts with [`cancel::CANCELLED_PREFIX`].
+impl From<String> for ModifyError {
+    fn from(message: String) -> Self {
+        if message.starts_with(cancel::CANCELLED_PREFIX) {
+            Self::Cancelled(message)
+        } else {
+            Self::Other(message)
+        }
+    }
+}
+
+impl From<ModifyError> for String {
+    fn from(error: ModifyError) -> Self {
+        error.to_string()
+    }
+}
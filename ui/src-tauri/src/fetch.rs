@@ -0,0 +1,149 @@
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::{for_each_capped_line, models, IMAGE_EXTENSIONS};
+
+/// Largest image [`download_image`] accepts, in bytes.
+pub(crate) const MAX_DOWNLOAD_BYTES: u64 = 100 << 20;
+
+/// Downloads an image: `url folder limit extensions`. Redirects are followed and
+/// reported as `redirected <url>`; progress is printed as `progress <done> <total>`
+/// (total 0 when unknown) and the result as `saved <path>`. Failures exit with a
+/// one-line message.
+const FETCH_SNIPPET: &str = r#"
+import os, sys, time, urllib.error, urllib.parse, urllib.request
+url, folder, limit, extensions = sys.argv[1], sys.argv[2], int(sys.argv[3]), sys.argv[4].split(",")
+TYPES = {"image/jpeg": "jpg", "image/png": "png", "image/bmp": "bmp", "image/tiff": "tiff", "image/webp": "webp"}
+
+def fetch():
+    request = urllib.request.Request(url, headers={"User-Agent": "old-photos-restoration"})
+    with urllib.request.urlopen(request, timeout=30) as resp:
+        if resp.geturl() != url:
+            print("redirected %s" % resp.geturl(), flush=True)
+        kind = resp.headers.get_content_type()
+        if kind not in TYPES:
+            sys.exit("Not an image: the server sent %s" % kind)
+        total = int(resp.headers.get("Content-Length") or 0)
+        if total > limit:
+            sys.exit("Image is too large: %d bytes, the limit is %d" % (total, limit))
+        name = os.path.basename(urllib.parse.urlparse(resp.geturl()).path) or "image"
+        stem, ext = os.path.splitext(name)
+        if ext.lstrip(".").lower() not in extensions:
+            stem, ext = name, "." + TYPES[kind]
+        path, n = os.path.join(folder, stem + ext), 1
+        while os.path.exists(path):
+            path, n = os.path.join(folder, "%s_%d%s" % (stem, n, ext)), n + 1
+        done, last_time = 0, 0.0
+        with open(path + ".part", "wb") as out:
+            while True:
+                chunk = resp.read(1 << 16)
+                if not chunk:
+                    break
+                done += len(chunk)
+                if done > limit:
+                    out.close()
+                    os.remove(path + ".part")
+                    sys.exit("Image is too large: over %d bytes" % limit)
+                out.write(chunk)
+                if time.monotonic() - last_time >= 0.5:
+                    last_time = time.monotonic()
+                    print("progress %d %d" % (done, total), flush=True)
+        os.replace(path + ".part", path)
+        print("progress %d %d" % (done, total), flush=True)
+        print("saved %s" % path, flush=True)
+
+try:
+    fetch()
+except urllib.error.HTTPError as e:
+    sys.exit("The server answered %d %s" % (e.code, e.reason))
+except urllib.error.URLError as e:
+    sys.exit("Could not reach the server: %s" % e.reason)
+"#;
+
+/// Whether `input` is an http(s) URL rather than a local path.
+pub(crate) fn is_url(input: &str) -> bool {
+    let scheme = input
+        .trim()
+        .split_once("://")
+        .map(|(s, _)| s.to_ascii_lowercase());
+    matches!(scheme.as_deref(), Some("http" | "https"))
+}
+
+/// Downloads the image at the http(s) `url` into `folder` with `python`, passing
+/// progress messages to `emit`. Returns the saved file, named after the URL.
+pub(crate) fn download_image(
+    python: &str,
+    url: &str,
+    folder: &Path,
+    emit: &dyn Fn(String),
+) -> Result<PathBuf, String> {
+    if !is_url(url) {
+        return Err(format!("Not an http(s) URL: {url}"));
+    }
+    std::fs::create_dir_all(folder)
+        .map_err(|e| format!("Failed to create {}: {e}", folder.display()))?;
+
+    let mut child = Command::new(python)
+        .arg("-c")
+        .arg(FETCH_SNIPPET)
+        .arg(url)
+        .arg(folder)
+        .arg(MAX_DOWNLOAD_BYTES.to_string())
+        .arg(IMAGE_EXTENSIONS.join(","))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to start python: {e}"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| "Failed to capture python stdout".to_string())?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| "Failed to capture python stderr".to_string())?;
+    let err_handle = thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    });
+
+    emit(format!("Downloading {url}..."));
+    let mut saved = None;
+    for_each_capped_line(BufReader::new(stdout), |line, _| {
+        if let Some(target) = line.strip_prefix("redirected ") {
+            emit(format!("Redirected to {target}"));
+        } else if let Some(rest) = line.strip_prefix("progress ") {
+            let mut parts = rest.split(' ').filter_map(|p| p.parse::<u64>().ok());
+            if let (Some(done), Some(total)) = (parts.next(), parts.next()) {
+                emit(match total {
+                    0 => format!("Downloaded {:.1} MiB", models::mib(done)),
+                    _ => format!(
+                        "Downloaded {:.1} of {:.1} MiB",
+                        models::mib(done),
+                        models::mib(total)
+                    ),
+                });
+            }
+        } else if let Some(path) = line.strip_prefix("saved ") {
+            saved = Some(PathBuf::from(path.trim()));
+        }
+    });
+    let stderr = err_handle.join().unwrap_or_default();
+    let status = child.wait().map_err(|e| format!("Process error: {e}"))?;
+
+    match saved {
+        Some(path) if status.success() => Ok(path),
+        _ => {
+            let detail = stderr
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .map(|line| line.trim().to_string())
+                .unwrap_or_else(|| format!("python exited with {status}"));
+            Err(format!("Failed to download {url}: {detail}"))
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Prints one JSON object per visible CUDA device (none when torch reports CUDA as
+/// unavailable), then `{"mps": <bool>}` for Apple's Metal backend.
+const PROBE_SNIPPET: &str = r#"
+import json, torch
+count = torch.cuda.device_count() if torch.cuda.is_available() else 0
+for i in range(count):
+    try:
+        free = torch.cuda.mem_get_info(i)[0]
+    except Exception:
+        free = None
+    print(json.dumps({"index": i, "name": torch.cuda.get_device_name(i), "freeBytes": free}))
+mps = getattr(torch.backends, "mps", None)
+print(json.dumps({"mps": bool(mps and mps.is_available())}))
+"#;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CudaDevice {
+    pub(crate) index: u32,
+    pub(crate) name: String,
+    /// Currently free memory; `None` on torch versions without `mem_get_info`.
+    pub(crate) free_bytes: Option<u64>,
+}
+
+/// What the interpreter's torch can run on besides the CPU.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DeviceProbe {
+    pub(crate) cuda: Vec<CudaDevice>,
+    /// Apple Silicon's Metal backend is available.
+    pub(crate) mps: bool,
+}
+
+impl DeviceProbe {
+    pub(crate) fn parse(stdout: &str) -> Result<Self, String> {
+        let mut probe = Self::default();
+        for line in stdout.lines().filter(|l| l.trim_start().starts_with('{')) {
+            let value: serde_json::Value =
+                serde_json::from_str(line).map_err(|e| format!("Invalid GPU probe output: {e}"))?;
+            match value.get("mps") {
+                Some(mps) => probe.mps = mps.as_bool().unwrap_or(false),
+                None => probe.cuda.push(
+                    serde_json::from_value(value)
+                        .map_err(|e| format!("Invalid GPU probe output: {e}"))?,
+                ),
+            }
+        }
+        Ok(probe)
+    }
+}
+
+/// Asks the given interpreter's torch which CUDA devices it can see.
+pub(crate) fn probe_cuda_devices(python: &str) -> Result<Vec<CudaDevice>, String> {
+    probe_devices(python).map(|probe| probe.cuda)
+}
+
+/// Asks the given interpreter's torch which CUDA devices it can see and whether MPS is
+/// available.
+pub(crate) fn probe_devices(python: &str) -> Result<DeviceProbe, String> {
+    let output = Command::new(python)
+        .arg("-c")
+        .arg(PROBE_SNIPPET)
+        .output()
+        .map_err(|e| format!("Failed to start python: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last = stderr.lines().last().unwrap_or_default().trim().to_string();
+        return Err(format!("GPU probe failed: {last}"));
+    }
+
+    DeviceProbe::parse(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// One choice for `ModifyPhotoArgs::gpu`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GpuInfo {
+    /// What to pass as `gpu`.
+    pub(crate) gpu: String,
+    /// The CUDA device index; `None` for the CPU and MPS.
+    pub(crate) index: Option<u32>,
+    pub(crate) name: String,
+}
+
+/// Every device `probe` found, CUDA devices first, then MPS, then the CPU, which is
+/// always there. Without a probe, e.g. when torch isn't importable, only the CPU.
+pub(crate) fn gpu_options(probe: Option<DeviceProbe>) -> Vec<GpuInfo> {
+    let probe = probe.unwrap_or_default();
+    let mut options: Vec<_> = probe
+        .cuda
+        .into_iter()
+        .map(|device| GpuInfo {
+            gpu: device.index.to_string(),
+            index: Some(device.index),
+            name: device.name,
+        })
+        .collect();
+    if probe.mps {
+        options.push(GpuInfo {
+            gpu: "mps".to_string(),
+            index: None,
+            name: "Apple MPS".to_string(),
+        });
+    }
+    options.push(GpuInfo {
+        gpu: "-1".to_string(),
+        index: None,
+        name: "CPU".to_string(),
+    });
+    options
+}
+
+/// The value for run.py's `--GPU` flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GpuSelection {
+    /// Empty input: `--GPU` is omitted so run.py falls back to its own default ("6,7").
+    Default,
+    /// `-1`: run on the CPU.
+    Cpu,
+    /// `auto`: the device with the most free memory, see [`auto_select`]. If left
+    /// unresolved it falls back to device 0.
+    Auto,
+    /// One or more CUDA device indices, e.g. `0` or `0,1`.
+    Devices(Vec<u32>),
+    /// `mps`: Apple Silicon. run.py's scripts only address CUDA devices, so run.py gets
+    /// `--GPU -1` and `PYTORCH_ENABLE_MPS_FALLBACK=1`; only scripts that pick MPS up
+    /// themselves use it, the rest run on the CPU.
+    Mps,
+}
+
+impl GpuSelection {
+    pub(crate) fn parse(raw: &str) -> Result<Self, String> {
+        let raw = raw.trim();
+        if raw.is_empty() {
+            return Ok(Self::Default);
+        }
+        if raw == "-1" {
+            return Ok(Self::Cpu);
+        }
+        if raw.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        if raw.eq_ignore_ascii_case("mps") {
+            return Ok(Self::Mps);
+        }
+
+        let mut indices = Vec::new();
+        for part in raw.split(',') {
+            let part = part.trim();
+            let index = part.parse::<u32>().map_err(|_| {
+                format!("Invalid GPU value \"{raw}\": expected -1 for CPU, auto, mps, an index like 0, or a list like 0,1")
+            })?;
+            if !indices.contains(&index) {
+                indices.push(index);
+            }
+        }
+        Ok(Self::Devices(indices))
+    }
+
+    /// Rejects indices that are not present on this machine.
+    pub(crate) fn check_device_count(&self, count: usize, mps: bool) -> Result<(), String> {
+        let Self::Devices(indices) = self else {
+            return Ok(());
+        };
+        if count == 0 {
+            return Err(if mps {
+                "No CUDA GPUs detected; this machine has Apple MPS instead, select mps or use \
+                 -1 to run on the CPU"
+                    .to_string()
+            } else {
+                "No CUDA GPUs detected and MPS is not available; only CPU mode (-1) will work"
+                    .to_string()
+            });
+        }
+        match indices.iter().find(|&&i| i as usize >= count) {
+            Some(i) => Err(format!(
+                "GPU {i} not found; detected {count} GPU(s) (valid indices 0-{})",
+                count - 1
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn includes(&self, index: u32) -> bool {
+        matches!(self, Self::Devices(indices) if indices.contains(&index))
+    }
+
+    /// The device run.py's scripts should report binding to (`cpu` or `cuda:<first
+    /// index>`), or `None` when run.py picks by itself.
+    pub(crate) fn expected_device(&self) -> Option<String> {
+        match self {
+            Self::Default => None,
+            Self::Cpu | Self::Mps => Some("cpu".to_string()),
+            Self::Auto => Some("cuda:0".to_string()),
+            Self::Devices(indices) => indices.first().map(|i| format!("cuda:{i}")),
+        }
+    }
+
+    /// The normalized `--GPU` argument, or `None` to leave run.py's default in place.
+    pub(crate) fn to_arg(&self) -> Option<String> {
+        match self {
+            Self::Default => None,
+            Self::Cpu | Self::Mps => Some("-1".to_string()),
+            Self::Auto => Some("0".to_string()),
+            Self::Devices(indices) => Some(
+                indices
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+        }
+    }
+}
+
+/// Picks the CUDA device with the most free memory. Falls back to device 0 when free
+/// memory can't be read and to the CPU when the probe fails or finds no device. Returns
+/// the selection and a message describing the choice.
+pub(crate) fn auto_select(python: &str) -> (GpuSelection, String) {
+    let DeviceProbe { cuda: devices, mps } = match probe_devices(python) {
+        Ok(probe) => probe,
+        Err(e) => return (GpuSelection::Cpu, format!("Auto GPU: {e}; using CPU")),
+    };
+    let best = devices
+        .iter()
+        .filter(|d| d.free_bytes.is_some())
+        .max_by_key(|d| d.free_bytes)
+        .or_else(|| devices.first());
+    match best {
+        Some(device) => {
+            let free = device
+                .free_bytes
+                .map(|b| format!(", {:.1} GiB free", b as f64 / (1u64 << 30) as f64))
+                .unwrap_or_default();
+            (
+                GpuSelection::Devices(vec![device.index]),
+                format!(
+                    "Auto GPU: selected {} ({}{free})",
+                    device.index, device.name
+                ),
+            )
+        }
+        None if mps => (
+            GpuSelection::Mps,
+            "Auto GPU: no CUDA devices detected; using Apple MPS where the scripts support it"
+                .to_string(),
+        ),
+        None => (
+            GpuSelection::Cpu,
+            "Auto GPU: neither CUDA nor MPS is available; only CPU mode will work".to_string(),
+        ),
+    }
+}
+
+/// Lets only one GPU-using run.py process run at a time across all jobs, so two jobs
+/// sharing a card can't run out of memory by overlapping their stages.
+#[derive(Debug)]
+pub(crate) struct GpuSemaphore {
+    busy: Mutex<bool>,
+    released: Condvar,
+}
+
+/// How often a waiting job wakes up to check whether it was cancelled.
+const WAIT_SLICE: Duration = Duration::from_millis(200);
+
+impl GpuSemaphore {
+    pub(crate) const fn new() -> Self {
+        Self {
+            busy: Mutex::new(false),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until the GPU is free. `cancelled` is polled while waiting; when it
+    /// returns a message, waiting stops and that message is returned as the error.
+    pub(crate) fn acquire(
+        &self,
+        cancelled: impl Fn() -> Option<String>,
+    ) -> Result<GpuPermit<'_>, String> {
+        let mut busy = self.busy.lock().unwrap_or_else(|e| e.into_inner());
+        while *busy {
+            if let Some(message) = cancelled() {
+                return Err(message);
+            }
+            busy = self
+                .released
+                .wait_timeout(busy, WAIT_SLICE)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        *busy = true;
+        Ok(GpuPermit { semaphore: self })
+    }
+
+    /// Whether a permit is currently held.
+    pub(crate) fn is_busy(&self) -> bool {
+        *self.busy.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Held while a process uses the GPU; dropping it, on any path, frees the GPU.
+pub(crate) struct GpuPermit<'a> {
+    semaphore: &'a GpuSemaphore,
+}
+
+impl Drop for GpuPermit<'_> {
+    fn drop(&mut self) {
+        *self
+            .semaphore
+            .busy
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = false;
+        self.semaphore.released.notify_one();
+    }
+}
+
+pub(crate) static GPU_SLOT: GpuSemaphore = GpuSemaphore::new();
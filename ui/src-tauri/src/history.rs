@@ -0,0 +1,354 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    resolve_output_folder, run_dir_name, ModifyError, ModifyPhotoArgs, ModifyPhotoResult,
+    OutputItem, StageClock, StageTiming,
+};
+
+/// How many of the latest matching runs [`stage_averages`] averages over, so the
+/// estimate follows changes like a new GPU.
+const ETA_SAMPLE_RUNS: usize = 20;
+
+/// Serializes read-modify-write cycles on the history file.
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// One finished run, successful or not.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RunRecord {
+    pub(crate) run_id: String,
+    /// Seconds since the Unix epoch.
+    pub(crate) started_at: u64,
+    pub(crate) finished_at: u64,
+    pub(crate) success: bool,
+    /// Failed because it was cancelled.
+    #[serde(default)]
+    pub(crate) cancelled: bool,
+    /// The highest stage (1-3, 4 = final) the run got to.
+    #[serde(default)]
+    pub(crate) stage_reached: Option<u8>,
+    /// Time spent in each stage until the run ended, including runs that failed.
+    #[serde(default)]
+    pub(crate) stage_durations: Vec<StageTiming>,
+    pub(crate) output_path: Option<String>,
+    pub(crate) error: Option<String>,
+    /// The settings the run used, with defaults filled in and paths made absolute so
+    /// the run can be repeated later.
+    pub(crate) args: ModifyPhotoArgs,
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub(crate) fn history_path(root: &Path) -> PathBuf {
+    root.join("output_gui").join(".history.json")
+}
+
+/// All records, oldest first. A missing or corrupt file reads as empty.
+pub(crate) fn load(root: &Path) -> Vec<RunRecord> {
+    fs::read_to_string(history_path(root))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// The last `limit` records, oldest first.
+pub(crate) fn recent(root: &Path, limit: usize) -> Vec<RunRecord> {
+    let mut records = load(root);
+    records.drain(..records.len().saturating_sub(limit));
+    records
+}
+
+pub(crate) fn find(root: &Path, run_id: &str) -> Option<RunRecord> {
+    load(root).into_iter().rev().find(|r| r.run_id == run_id)
+}
+
+/// Average time per stage (0-4) of the latest successful runs that had the same `hr`
+/// and `with_scratch` as `args`, or `None` when there are none.
+pub(crate) fn stage_averages(root: &Path, args: &ModifyPhotoArgs) -> Option<[Duration; 5]> {
+    let records = load(root);
+    let samples: Vec<_> = records
+        .iter()
+        .rev()
+        .filter(|r| r.success && !r.stage_durations.is_empty())
+        .filter(|r| r.args.hr == args.hr && r.args.with_scratch == args.with_scratch)
+        .take(ETA_SAMPLE_RUNS)
+        .collect();
+    if samples.is_empty() {
+        return None;
+    }
+    let mut totals = [0u64; 5];
+    for timing in samples.iter().flat_map(|r| &r.stage_durations) {
+        if let Some(total) = totals.get_mut(usize::from(timing.stage)) {
+            *total += timing.millis;
+        }
+    }
+    Some(totals.map(|total| Duration::from_millis(total / samples.len() as u64)))
+}
+
+/// Time left for a run that has spent `in_stage` in `stage`: what the stage usually
+/// takes beyond that, or nothing once it overran, plus the usual time of the later
+/// stages.
+pub(crate) fn remaining(averages: &[Duration; 5], stage: u8, in_stage: Duration) -> Duration {
+    let stage = usize::from(stage).min(averages.len() - 1);
+    averages[stage].saturating_sub(in_stage) + averages[stage + 1..].iter().sum::<Duration>()
+}
+
+/// Appends `record`, writing through a temp file so a crash can't leave half a file.
+pub(crate) fn append(root: &Path, record: RunRecord) -> Result<(), String> {
+    let _guard = HISTORY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut records = load(root);
+    records.push(record);
+    save(root, &records)
+}
+
+/// Points every record whose output was `old` at `new`. Returns how many changed.
+pub(crate) fn replace_output_path(root: &Path, old: &str, new: &str) -> Result<usize, String> {
+    let _guard = HISTORY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut records = load(root);
+    let mut changed = 0;
+    for record in &mut records {
+        if record.output_path.as_deref() == Some(old) {
+            record.output_path = Some(new.to_string());
+            changed += 1;
+        }
+    }
+    if changed > 0 {
+        save(root, &records)?;
+    }
+    Ok(changed)
+}
+
+/// Callers must hold [`HISTORY_LOCK`].
+fn save(root: &Path, records: &[RunRecord]) -> Result<(), String> {
+    let path = history_path(root);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+    }
+    let text = serde_json::to_string_pretty(records)
+        .map_err(|e| format!("Failed to serialize history: {e}"))?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text).map_err(|e| format!("Failed to write history: {e}"))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("Failed to write history: {e}"))
+}
+
+/// One run.py process a run started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RunCommand {
+    pub(crate) argv: Vec<String>,
+    /// `None` while running, or when it was killed or ended by a signal.
+    pub(crate) exit_code: Option<i32>,
+}
+
+/// What `output_folder/run_<run_id>.json` holds: a finished run, for scripts that
+/// drive the app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RunSummary {
+    pub(crate) run_id: String,
+    pub(crate) success: bool,
+    pub(crate) cancelled: bool,
+    pub(crate) error: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub(crate) started_at: u64,
+    pub(crate) finished_at: u64,
+    /// The input files the outputs were made from, or just the input path when there
+    /// are no outputs to tell.
+    pub(crate) inputs: Vec<String>,
+    pub(crate) gpu: String,
+    pub(crate) with_scratch: bool,
+    pub(crate) hr: bool,
+    /// Every run.py process started, in order.
+    pub(crate) commands: Vec<RunCommand>,
+    pub(crate) outputs: Vec<OutputItem>,
+    pub(crate) args: ModifyPhotoArgs,
+}
+
+/// Commands started so far by each run that has a [`RecordGuard`].
+static COMMANDS: Mutex<Option<HashMap<String, Vec<RunCommand>>>> = Mutex::new(None);
+
+/// Records that `run_id` started run.py with `argv`.
+pub(crate) fn note_command(run_id: &str, argv: Vec<String>) {
+    let mut commands = COMMANDS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(list) = commands.as_mut().and_then(|c| c.get_mut(run_id)) {
+        list.push(RunCommand {
+            argv,
+            exit_code: None,
+        });
+    }
+}
+
+/// Records how the last command [`note_command`] saw for `run_id` ended.
+pub(crate) fn note_exit(run_id: &str, exit_code: Option<i32>) {
+    let mut commands = COMMANDS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(last) = commands
+        .as_mut()
+        .and_then(|c| c.get_mut(run_id))
+        .and_then(|list| list.last_mut())
+    {
+        last.exit_code = exit_code;
+    }
+}
+
+pub(crate) fn summary_path(output_folder: &Path, run_id: &str) -> PathBuf {
+    output_folder.join(format!("run_{}.json", run_dir_name(run_id)))
+}
+
+/// The summary written for `run_id`, found through its history record.
+pub(crate) fn read_summary(root: &Path, run_id: &str) -> Result<RunSummary, String> {
+    let record = find(root, run_id).ok_or_else(|| format!("No run with id {run_id} in history"))?;
+    let path = summary_path(&resolve_output_folder(root, &record.args), run_id);
+    let text =
+        fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    serde_json::from_str(&text).map_err(|e| format!("Invalid run summary {}: {e}", path.display()))
+}
+
+fn write_summary(root: &Path, summary: &RunSummary) -> Result<(), String> {
+    let folder = resolve_output_folder(root, &summary.args);
+    fs::create_dir_all(&folder)
+        .map_err(|e| format!("Failed to create {}: {e}", folder.display()))?;
+    let path = summary_path(&folder, &summary.run_id);
+    let text = serde_json::to_string_pretty(summary)
+        .map_err(|e| format!("Failed to serialize run summary: {e}"))?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text).map_err(|e| format!("Failed to write run summary: {e}"))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("Failed to write run summary: {e}"))
+}
+
+/// Appends a run's record when dropped, so every run leaves one however it ends:
+/// with the result passed to [`RecordGuard::finish`], or as failed when a panic
+/// unwinds past the guard.
+pub(crate) struct RecordGuard {
+    root: PathBuf,
+    record: RunRecord,
+    /// 0 until an event with a stage is seen.
+    stage_reached: AtomicU8,
+    clock: StageClock,
+    outputs: Vec<OutputItem>,
+    /// From [`stage_averages`], looked up when the run starts.
+    averages: Option<[Duration; 5]>,
+}
+
+impl RecordGuard {
+    pub(crate) fn start(root: &Path, args: ModifyPhotoArgs) -> Self {
+        COMMANDS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashMap::new)
+            .insert(args.run_id.clone(), Vec::new());
+        Self {
+            root: root.to_path_buf(),
+            averages: stage_averages(root, &args),
+            record: RunRecord {
+                run_id: args.run_id.clone(),
+                started_at: unix_now(),
+                finished_at: 0,
+                success: false,
+                cancelled: false,
+                stage_reached: None,
+                stage_durations: Vec::new(),
+                output_path: None,
+                error: None,
+                args,
+            },
+            stage_reached: AtomicU8::new(0),
+            clock: StageClock::start(),
+            outputs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn reached_stage(&self, stage: u8) {
+        self.stage_reached.fetch_max(stage, Ordering::Relaxed);
+        self.clock.observe(stage, false);
+    }
+
+    /// Seconds the run probably has left, see [`remaining`].
+    pub(crate) fn eta_secs(&self) -> Option<u64> {
+        let averages = self.averages.as_ref()?;
+        let (stage, in_stage) = self.clock.current()?;
+        let left = remaining(averages, stage, in_stage);
+        // Rounded up, so it only reads 0 once the estimate has run out.
+        Some(left.as_secs() + u64::from(left.subsec_nanos() > 0))
+    }
+
+    pub(crate) fn finish(mut self, result: &Result<ModifyPhotoResult, ModifyError>) {
+        let record = &mut self.record;
+        match result {
+            Ok(result) => {
+                record.success = true;
+                record.output_path = Some(result.output_path.clone());
+                self.outputs = result.outputs.clone();
+            }
+            Err(e) => {
+                record.cancelled = e.is_cancelled();
+                record.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+impl Drop for RecordGuard {
+    fn drop(&mut self) {
+        let record = &mut self.record;
+        record.finished_at = unix_now();
+        record.stage_reached = Some(self.stage_reached.load(Ordering::Relaxed)).filter(|&s| s > 0);
+        record.stage_durations = self.clock.durations();
+        if !record.success && record.error.is_none() {
+            record.error = Some(if thread::panicking() {
+                "The run crashed unexpectedly".to_string()
+            } else {
+                "The run ended without a result".to_string()
+            });
+        }
+        if let Err(e) = append(&self.root, record.clone()) {
+            eprintln!("Failed to record run history: {e}");
+        }
+
+        let commands = COMMANDS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+            .and_then(|c| c.remove(&record.run_id))
+            .unwrap_or_default();
+        let mut inputs: Vec<String> = Vec::new();
+        for input in self.outputs.iter().filter_map(|o| o.input.clone()) {
+            if !inputs.contains(&input) {
+                inputs.push(input);
+            }
+        }
+        if inputs.is_empty() {
+            inputs.push(record.args.input_path.clone());
+        }
+        let summary = RunSummary {
+            run_id: record.run_id.clone(),
+            success: record.success,
+            cancelled: record.cancelled,
+            error: record.error.clone(),
+            started_at: record.started_at,
+            finished_at: record.finished_at,
+            inputs,
+            gpu: record.args.gpu.clone(),
+            with_scratch: record.args.with_scratch,
+            hr: record.args.hr,
+            commands,
+            outputs: std::mem::take(&mut self.outputs),
+            args: record.args.clone(),
+        };
+        if let Err(e) = write_summary(&self.root, &summary) {
+            eprintln!("Failed to write the run summary: {e}");
+        }
+    }
+}
@@ -0,0 +1,622 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{
+    DynamicImage, ImageBuffer, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, Rgb, RgbImage,
+};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::ColorType;
+
+use crate::font;
+
+pub(crate) fn open_image(path: &Path) -> Result<DynamicImage, String> {
+    image::open(path).map_err(|e| format!("Failed to open image {}: {e}", path.display()))
+}
+
+/// Scales `img` so its longest edge is `max_edge`, preserving aspect ratio.
+pub(crate) fn resize_to_longest_edge(img: &DynamicImage, max_edge: u32) -> DynamicImage {
+    img.resize(max_edge, max_edge, FilterType::Lanczos3)
+}
+
+/// Like [`resize_to_longest_edge`], but leaves images that already fit as they are.
+pub(crate) fn shrink_to_fit(img: DynamicImage, max_edge: u32) -> DynamicImage {
+    if img.width().max(img.height()) > max_edge {
+        resize_to_longest_edge(&img, max_edge)
+    } else {
+        img
+    }
+}
+
+pub(crate) fn parse_format(name: &str) -> Result<ImageFormat, String> {
+    match name.trim().to_ascii_lowercase().as_str() {
+        "png" => Ok(ImageFormat::Png),
+        "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
+        "webp" => Ok(ImageFormat::WebP),
+        "bmp" => Ok(ImageFormat::Bmp),
+        "tif" | "tiff" => Ok(ImageFormat::Tiff),
+        other => Err(format!("Unsupported image format: {other}")),
+    }
+}
+
+/// Saves `img` as `format`, dropping the alpha channel for formats that can't store it.
+pub(crate) fn save_image(
+    img: &DynamicImage,
+    path: &Path,
+    format: ImageFormat,
+) -> Result<(), String> {
+    let result = match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8()).save_with_format(path, format),
+        _ => img.save_with_format(path, format),
+    };
+    result.map_err(|e| format!("Failed to write {}: {e}", path.display()))
+}
+
+/// Rewrites `path` as `format` under the same stem with that format's extension and
+/// removes the original. JPEG is written at `quality`; WebP is always lossless. Returns
+/// the new file.
+pub(crate) fn convert_format(
+    path: &Path,
+    format: ImageFormat,
+    quality: u8,
+) -> Result<PathBuf, String> {
+    if ImageFormat::from_path(path).ok() == Some(format) {
+        return Ok(path.to_path_buf());
+    }
+    let img = open_image(path)?;
+    let exif = read_exif(path)?;
+    let ext = format.extensions_str().first().copied().unwrap_or("png");
+    let out = path.with_extension(ext);
+    match exif {
+        Some(exif) => save_with_exif(&img, &out, format, quality, exif)?,
+        None if format == ImageFormat::Jpeg => {
+            save_with_exif(&img, &out, format, quality, Vec::new())?
+        }
+        None => save_image(&img, &out, format)?,
+    }
+    fs::remove_file(path).map_err(|e| format!("Failed to remove {}: {e}", path.display()))?;
+    Ok(out)
+}
+
+/// The raw EXIF block embedded in `path`, `None` if it has none.
+pub(crate) fn read_exif(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    let fail = |e: image::ImageError| format!("Failed to read {}: {e}", path.display());
+    ImageReader::open(path)
+        .map_err(|e| format!("Failed to open {}: {e}", path.display()))?
+        .with_guessed_format()
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?
+        .into_decoder()
+        .map_err(fail)?
+        .exif_metadata()
+        .map_err(fail)
+}
+
+/// Saves `img` as PNG, JPEG at `quality` or lossless WebP with `exif` embedded; an
+/// empty `exif` embeds nothing.
+fn save_with_exif(
+    img: &DynamicImage,
+    path: &Path,
+    format: ImageFormat,
+    quality: u8,
+    exif: Vec<u8>,
+) -> Result<(), String> {
+    fn encode(
+        img: &DynamicImage,
+        mut encoder: impl ImageEncoder,
+        exif: Vec<u8>,
+    ) -> Result<(), String> {
+        if !exif.is_empty() {
+            encoder.set_exif_metadata(exif).map_err(|e| e.to_string())?;
+        }
+        img.write_with_encoder(encoder).map_err(|e| e.to_string())
+    }
+    let file = BufWriter::new(
+        File::create(path).map_err(|e| format!("Failed to create {}: {e}", path.display()))?,
+    );
+    match format {
+        ImageFormat::Png => encode(img, PngEncoder::new(file), exif),
+        ImageFormat::Jpeg => encode(
+            &DynamicImage::ImageRgb8(img.to_rgb8()),
+            JpegEncoder::new_with_quality(file, quality),
+            exif,
+        ),
+        ImageFormat::WebP => encode(img, WebPEncoder::new_lossless(file), exif),
+        other => Err(format!("{other:?} files can't carry EXIF metadata here")),
+    }
+    .map_err(|e| format!("Failed to write {}: {e}", path.display()))
+}
+
+/// Copies the EXIF block of `source`, orientation included, into `dest`, which is
+/// re-encoded with it: losslessly for PNG and WebP, at quality 95 for JPEG. Returns
+/// false, leaving `dest` alone, when `source` has no EXIF.
+pub(crate) fn copy_exif(source: &Path, dest: &Path) -> Result<bool, String> {
+    let Some(exif) = read_exif(source)? else {
+        return Ok(false);
+    };
+    let format = ImageFormat::from_path(dest)
+        .map_err(|e| format!("Unknown image format {}: {e}", dest.display()))?;
+    let img = open_image(dest)?;
+    let tmp = dest.with_extension(format!(
+        "tmp.{}",
+        dest.extension().unwrap_or_default().to_string_lossy()
+    ));
+    save_with_exif(&img, &tmp, format, 95, exif).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+    fs::rename(&tmp, dest).map_err(|e| format!("Failed to replace {}: {e}", dest.display()))?;
+    Ok(true)
+}
+
+/// Encodes `img` as PNG in memory.
+pub(crate) fn encode_png(img: &DynamicImage) -> Result<Vec<u8>, String> {
+    let mut bytes = Cursor::new(Vec::new());
+    img.write_to(&mut bytes, ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode PNG: {e}"))?;
+    Ok(bytes.into_inner())
+}
+
+/// What [`export_sizes`] wrote, and the sizes it left out.
+#[derive(Debug, Default)]
+pub(crate) struct SizeExport {
+    pub(crate) outputs: Vec<PathBuf>,
+    /// Zero, or larger than the source's longest edge.
+    pub(crate) skipped: Vec<u32>,
+}
+
+/// Writes one copy of `path` per requested longest-edge size next to the source, named
+/// `<stem>_<size>.<ext>`. Sizes larger than the source are skipped rather than upscaled.
+pub(crate) fn export_sizes(
+    path: &Path,
+    sizes: &[u32],
+    format: ImageFormat,
+) -> Result<SizeExport, String> {
+    let img = open_image(path)?;
+    let longest = img.width().max(img.height());
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .ok_or_else(|| "Invalid image path".to_string())?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let ext = format.extensions_str().first().copied().unwrap_or("png");
+
+    let mut export = SizeExport::default();
+    for &size in sizes {
+        if size == 0 || size > longest {
+            export.skipped.push(size);
+            continue;
+        }
+        let out = dir.join(format!("{stem}_{size}.{ext}"));
+        save_image(&resize_to_longest_edge(&img, size), &out, format)?;
+        export.outputs.push(out);
+    }
+    Ok(export)
+}
+
+/// Decodes every page of a TIFF. Only 8- and 16-bit gray, gray+alpha, RGB and RGBA
+/// pages are supported; `image` itself only reads the first page.
+pub(crate) fn read_tiff_pages(path: &Path) -> Result<Vec<DynamicImage>, String> {
+    let fail = |e: tiff::TiffError| format!("Failed to read TIFF {}: {e}", path.display());
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+    let mut decoder = Decoder::new(BufReader::new(file)).map_err(fail)?;
+
+    let mut pages = Vec::new();
+    loop {
+        let (width, height) = decoder.dimensions().map_err(fail)?;
+        let color = decoder.colortype().map_err(fail)?;
+        let data = decoder.read_image().map_err(fail)?;
+        let page = match (color, data) {
+            (ColorType::Gray(8), DecodingResult::U8(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma8)
+            }
+            (ColorType::GrayA(8), DecodingResult::U8(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8)
+            }
+            (ColorType::RGB(8), DecodingResult::U8(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+            }
+            (ColorType::RGBA(8), DecodingResult::U8(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+            }
+            (ColorType::Gray(16), DecodingResult::U16(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma16)
+            }
+            (ColorType::GrayA(16), DecodingResult::U16(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA16)
+            }
+            (ColorType::RGB(16), DecodingResult::U16(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb16)
+            }
+            (ColorType::RGBA(16), DecodingResult::U16(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba16)
+            }
+            (color, _) => {
+                return Err(format!(
+                    "Unsupported TIFF page {} in {}: {color:?}",
+                    pages.len() + 1,
+                    path.display()
+                ))
+            }
+        };
+        let page = page.ok_or_else(|| {
+            format!(
+                "TIFF page {} in {} has the wrong size",
+                pages.len() + 1,
+                path.display()
+            )
+        })?;
+        pages.push(page);
+
+        if !decoder.more_images() {
+            return Ok(pages);
+        }
+        decoder.next_image().map_err(fail)?;
+    }
+}
+
+/// Writes `pages` as one multi-page RGB TIFF.
+pub(crate) fn write_multipage_tiff(path: &Path, pages: &[DynamicImage]) -> Result<(), String> {
+    let fail = |e: tiff::TiffError| format!("Failed to write {}: {e}", path.display());
+    let file =
+        File::create(path).map_err(|e| format!("Failed to create {}: {e}", path.display()))?;
+    let mut encoder = TiffEncoder::new(file).map_err(fail)?;
+    for page in pages {
+        let rgb = page.to_rgb8();
+        encoder
+            .write_image::<colortype::RGB8>(rgb.width(), rgb.height(), rgb.as_raw())
+            .map_err(fail)?;
+    }
+    Ok(())
+}
+
+/// Reported for identical images, whose PSNR is infinite.
+pub(crate) const MAX_PSNR: f64 = 100.0;
+
+/// Luma of `b` at the size of `a`, so images of different sizes can be compared.
+fn luma_pair(a: &DynamicImage, b: &DynamicImage) -> (image::GrayImage, image::GrayImage) {
+    let a = a.to_luma8();
+    let b = if b.width() == a.width() && b.height() == a.height() {
+        b.to_luma8()
+    } else {
+        b.resize_exact(a.width(), a.height(), FilterType::Lanczos3)
+            .to_luma8()
+    };
+    (a, b)
+}
+
+/// Peak signal-to-noise ratio of the luma channels in dB, capped at [`MAX_PSNR`].
+pub(crate) fn psnr(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    let (a, b) = luma_pair(a, b);
+    let n = a.as_raw().len().max(1) as f64;
+    let mse = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| (x as f64 - y as f64).powi(2))
+        .sum::<f64>()
+        / n;
+    if mse == 0.0 {
+        return MAX_PSNR;
+    }
+    (10.0 * (255.0f64.powi(2) / mse).log10()).min(MAX_PSNR)
+}
+
+/// Mean structural similarity of the luma channels over 8x8 blocks, from -1 to 1.
+pub(crate) fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    const BLOCK: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (a, b) = luma_pair(a, b);
+
+    let mut total = 0.0;
+    let mut blocks = 0usize;
+    for y0 in (0..a.height()).step_by(BLOCK as usize) {
+        for x0 in (0..a.width()).step_by(BLOCK as usize) {
+            let mut pixels = Vec::new();
+            for y in y0..(y0 + BLOCK).min(a.height()) {
+                for x in x0..(x0 + BLOCK).min(a.width()) {
+                    pixels.push((a.get_pixel(x, y)[0] as f64, b.get_pixel(x, y)[0] as f64));
+                }
+            }
+            let n = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|p| p.1).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for (x, y) in &pixels {
+                var_a += (x - mean_a).powi(2);
+                var_b += (y - mean_b).powi(2);
+                cov += (x - mean_a) * (y - mean_b);
+            }
+            var_a /= n;
+            var_b /= n;
+            cov /= n;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + C1) * (var_a + var_b + C2));
+            blocks += 1;
+        }
+    }
+    if blocks == 0 {
+        return 1.0;
+    }
+    total / blocks as f64
+}
+
+/// What [`postprocess`] does to an output.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Postprocess {
+    /// Re-encode even if nothing else changes, which drops any embedded ICC profile so
+    /// viewers fall back to sRGB (pixel values are kept as they are).
+    pub(crate) reencode: bool,
+    /// Turn the image into a single luma channel.
+    pub(crate) grayscale: bool,
+    /// Downscale images whose longest edge is larger than this, preserving aspect ratio.
+    pub(crate) max_edge: Option<u32>,
+    /// Strength of a [`denoise`] pass, applied after any downscaling.
+    pub(crate) denoise: Option<u8>,
+}
+
+impl Postprocess {
+    /// Whether every file gets rewritten, rather than only oversized ones.
+    pub(crate) fn rewrites_all(&self) -> bool {
+        self.reencode || self.grayscale || self.denoise.is_some()
+    }
+}
+
+/// Supported [`denoise`] strengths; others are clamped into this range.
+pub(crate) const DENOISE_STRENGTHS: std::ops::RangeInclusive<u8> = 1..=10;
+const DENOISE_RADIUS: i64 = 2;
+const DENOISE_SIGMA_SPACE: f32 = 1.5;
+
+/// Edge-preserving bilateral filter: each pixel becomes a weighted mean of its 5x5
+/// neighbourhood, where neighbours that differ a lot in color count for little, so
+/// grain is smoothed while edges stay sharp. `strength` (see [`DENOISE_STRENGTHS`])
+/// sets how large a difference still counts as noise.
+pub(crate) fn denoise(img: &RgbImage, strength: u8) -> RgbImage {
+    let strength = strength.clamp(*DENOISE_STRENGTHS.start(), *DENOISE_STRENGTHS.end());
+    let sigma_range = f32::from(strength) * 4.0;
+    // Indexed by the summed absolute channel difference, 0..=765.
+    let range_weights: Vec<f32> = (0..=765)
+        .map(|d| {
+            let d = d as f32 / 3.0;
+            (-(d * d) / (2.0 * sigma_range * sigma_range)).exp()
+        })
+        .collect();
+    let mut offsets = Vec::new();
+    for dy in -DENOISE_RADIUS..=DENOISE_RADIUS {
+        for dx in -DENOISE_RADIUS..=DENOISE_RADIUS {
+            let d2 = (dx * dx + dy * dy) as f32;
+            let weight = (-d2 / (2.0 * DENOISE_SIGMA_SPACE * DENOISE_SIGMA_SPACE)).exp();
+            offsets.push((dx, dy, weight));
+        }
+    }
+
+    let (width, height) = img.dimensions();
+    RgbImage::from_fn(width, height, |x, y| {
+        let center = img.get_pixel(x, y).0;
+        let mut sum = [0f32; 3];
+        let mut total = 0f32;
+        for &(dx, dy, spatial) in &offsets {
+            let nx = (i64::from(x) + dx).clamp(0, i64::from(width) - 1) as u32;
+            let ny = (i64::from(y) + dy).clamp(0, i64::from(height) - 1) as u32;
+            let pixel = img.get_pixel(nx, ny).0;
+            let diff: usize = (0..3).map(|c| center[c].abs_diff(pixel[c]) as usize).sum();
+            let weight = spatial * range_weights[diff];
+            for c in 0..3 {
+                sum[c] += weight * f32::from(pixel[c]);
+            }
+            total += weight;
+        }
+        Rgb(sum.map(|v| (v / total).round() as u8))
+    })
+}
+
+/// Rewrites `path` in place as `settings` asks. Multi-page TIFFs keep every page. A file
+/// nothing applies to (see [`Postprocess::rewrites_all`], and already within
+/// `max_edge`) is left untouched. Denoised images are stored as 8-bit RGB. Returns
+/// whether anything was downscaled.
+pub(crate) fn postprocess(path: &Path, settings: Postprocess) -> Result<bool, String> {
+    let format = ImageFormat::from_path(path)
+        .map_err(|e| format!("Unknown image format {}: {e}", path.display()))?;
+    let Postprocess {
+        grayscale,
+        max_edge,
+        denoise: strength,
+        ..
+    } = settings;
+    let oversized =
+        |img: &DynamicImage| max_edge.is_some_and(|edge| img.width().max(img.height()) > edge);
+    let convert = |img: DynamicImage| {
+        let img = match max_edge {
+            Some(edge) if oversized(&img) => resize_to_longest_edge(&img, edge),
+            _ => img,
+        };
+        let img = match strength {
+            Some(strength) => DynamicImage::ImageRgb8(denoise(&img.to_rgb8(), strength)),
+            None => img,
+        };
+        if grayscale {
+            img.grayscale()
+        } else {
+            img
+        }
+    };
+    let tmp = path.with_extension(format!(
+        "tmp.{}",
+        path.extension().unwrap_or_default().to_string_lossy()
+    ));
+
+    let resized;
+    if format == ImageFormat::Tiff {
+        let pages = read_tiff_pages(path)?;
+        resized = pages.iter().any(oversized);
+        if !settings.rewrites_all() && !resized {
+            return Ok(false);
+        }
+        let pages: Vec<_> = pages.into_iter().map(convert).collect();
+        if pages.len() > 1 {
+            write_multipage_tiff(&tmp, &pages)?;
+        } else {
+            pages
+                .iter()
+                .try_for_each(|page| save_image(page, &tmp, format))?;
+        }
+    } else {
+        let img = open_image(path)?;
+        resized = oversized(&img);
+        if !settings.rewrites_all() && !resized {
+            return Ok(false);
+        }
+        let img = convert(img);
+        if format == ImageFormat::Jpeg {
+            // The default quality of 75 would visibly degrade a restored photo.
+            let file = File::create(&tmp)
+                .map_err(|e| format!("Failed to create {}: {e}", tmp.display()))?;
+            let rgb_or_luma = if grayscale {
+                DynamicImage::ImageLuma8(img.to_luma8())
+            } else {
+                DynamicImage::ImageRgb8(img.to_rgb8())
+            };
+            rgb_or_luma
+                .write_with_encoder(JpegEncoder::new_with_quality(file, 95))
+                .map_err(|e| format!("Failed to write {}: {e}", tmp.display()))?;
+        } else {
+            save_image(&img, &tmp, format)?;
+        }
+    }
+    std::fs::rename(&tmp, path)
+        .map_err(|e| format!("Failed to replace {}: {e}", path.display()))?;
+    Ok(resized)
+}
+
+/// Thumbnail box of one contact sheet cell, in pixels.
+const SHEET_CELL: u32 = 192;
+const SHEET_PADDING: u32 = 8;
+const CAPTION_SCALE: u32 = 2;
+const CAPTION_HEIGHT: u32 = font::GLYPH_HEIGHT * CAPTION_SCALE + 6;
+/// Rows per sheet; longer lists continue on further sheets.
+pub(crate) const SHEET_ROWS: usize = 6;
+
+/// `name` cut down to `max` characters, ending in `..` when shortened.
+fn caption(name: &str, max: usize) -> String {
+    if name.chars().count() <= max {
+        return name.to_string();
+    }
+    let kept: String = name.chars().take(max.saturating_sub(2)).collect();
+    format!("{kept}..")
+}
+
+/// Draws thumbnails of `paths` in grids of `columns` cells, each captioned with its
+/// file name, and writes one PNG per [`SHEET_ROWS`] rows into `out_dir` as
+/// `<prefix>_<n>.png`. Unreadable images get an empty cell.
+pub(crate) fn contact_sheets(
+    paths: &[PathBuf],
+    columns: u32,
+    out_dir: &Path,
+    prefix: &str,
+) -> Result<Vec<PathBuf>, String> {
+    let cell_w = SHEET_CELL + SHEET_PADDING;
+    let cell_h = SHEET_CELL + CAPTION_HEIGHT + SHEET_PADDING;
+    let max_chars = (SHEET_CELL / (font::ADVANCE * CAPTION_SCALE)) as usize;
+    let per_sheet = columns as usize * SHEET_ROWS;
+
+    let mut sheets = Vec::new();
+    for (page, chunk) in paths.chunks(per_sheet).enumerate() {
+        let rows = chunk.len().div_ceil(columns as usize) as u32;
+        let mut sheet = RgbImage::from_pixel(
+            columns * cell_w + SHEET_PADDING,
+            rows * cell_h + SHEET_PADDING,
+            Rgb([32, 32, 32]),
+        );
+        for (i, path) in chunk.iter().enumerate() {
+            let x = SHEET_PADDING + (i as u32 % columns) * cell_w;
+            let y = SHEET_PADDING + (i as u32 / columns) * cell_h;
+            if let Ok(img) = image::open(path) {
+                let thumb = img.thumbnail(SHEET_CELL, SHEET_CELL).to_rgb8();
+                let offset_x = x + (SHEET_CELL - thumb.width()) / 2;
+                let offset_y = y + (SHEET_CELL - thumb.height()) / 2;
+                image::imageops::replace(&mut sheet, &thumb, offset_x.into(), offset_y.into());
+            }
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            font::draw_text(
+                &mut sheet,
+                x,
+                y + SHEET_CELL + 4,
+                &caption(&name, max_chars),
+                CAPTION_SCALE,
+                Rgb([220, 220, 220]),
+            );
+        }
+
+        let out = out_dir.join(format!("{prefix}_{}.png", page + 1));
+        sheet
+            .save_with_format(&out, ImageFormat::Png)
+            .map_err(|e| format!("Failed to write {}: {e}", out.display()))?;
+        sheets.push(out);
+    }
+    Ok(sheets)
+}
+
+/// Folder, inside the folder being previewed, that [`thumbnail_folder`] caches into.
+pub(crate) const THUMBS_DIR: &str = ".thumbs";
+
+/// Writes a thumbnail of at most `max_edge` pixels for every image in `folder` to
+/// `folder/.thumbs/<file name>`, using all CPUs. Thumbnails newer than their source
+/// are kept. Returns the thumbnail paths in file name order.
+pub(crate) fn thumbnail_folder(folder: &Path, max_edge: u32) -> Result<Vec<PathBuf>, String> {
+    let sources: Vec<_> = crate::list_input_files(folder)?
+        .into_iter()
+        .filter(|p| crate::has_image_extension(p))
+        .collect();
+    let thumbs_dir = folder.join(THUMBS_DIR);
+    fs::create_dir_all(&thumbs_dir)
+        .map_err(|e| format!("Failed to create {}: {e}", thumbs_dir.display()))?;
+
+    let make = |source: &Path| -> Result<PathBuf, String> {
+        let thumb = thumbs_dir.join(source.file_name().unwrap_or_default());
+        let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+        if let (Some(thumb_time), Some(source_time)) = (modified(&thumb), modified(source)) {
+            if thumb_time >= source_time {
+                return Ok(thumb);
+            }
+        }
+        let format = ImageFormat::from_path(source)
+            .map_err(|e| format!("Unknown image format {}: {e}", source.display()))?;
+        save_image(
+            &shrink_to_fit(open_image(source)?, max_edge),
+            &thumb,
+            format,
+        )?;
+        Ok(thumb)
+    };
+
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(sources.len());
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; sources.len()]);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(source) = sources.get(i) else {
+                    break;
+                };
+                let result = make(source);
+                results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err("Thumbnail worker stopped".to_string())))
+        .collect()
+}
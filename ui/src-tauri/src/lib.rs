@@ -1,93 +1,1183 @@
-use tauri::Emitter;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
 use std::fs;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
+use tauri::{Emitter, Manager};
+
+#[cfg(feature = "zip-archives")]
+mod archive;
+mod cancel;
+mod diagnose;
+mod error;
+mod fetch;
+mod font;
+mod gpu;
+mod history;
+mod imaging;
+mod manifest;
+mod models;
+mod options;
+mod queue;
+mod runlog;
+mod settings;
+mod throttle;
+
+use error::ModifyError;
+use gpu::GpuSelection;
+use history::RunRecord;
 
 #[tauri::command]
 fn greet(name: &str) -> String {
     format!("Hello, {}! You've been greeted from Rust!", name)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ModifyPhotoArgs {
     run_id: String,
     input_path: String,
     output_folder: Option<String>,
+    /// `-1` for CPU, `auto`, a device index, or a comma-separated list. Empty keeps
+    /// run.py's default.
     gpu: String,
     with_scratch: bool,
     hr: bool,
     python: String,
+    /// Optional file or named pipe that receives every progress event as NDJSON.
+    event_pipe: Option<String>,
+    /// The stages a customized run.py has, in order. Unset means the usual four, see
+    /// [`StageLayout`].
+    #[serde(default)]
+    pipeline: Option<Vec<StageSpec>>,
+    /// For folder inputs, run run.py once per file so one bad image can't abort the batch.
+    #[serde(default)]
+    isolate_files: bool,
+    /// Wipe the stage directories before running. When false, results accumulate across
+    /// runs in the same output folder for incremental batches; run.py does not skip inputs
+    /// it has seen before, so an output with the same name is simply overwritten.
+    #[serde(default = "default_true")]
+    clear_previous: bool,
+    /// For folder inputs, also pick up images in nested subfolders. They are staged
+    /// flat under path-derived names and mapped back in the result.
+    #[serde(default)]
+    recursive: bool,
+    /// With `recursive`, also copy every output to `<output>/structured/<subfolders>`,
+    /// mirroring where its input sat below the input folder.
+    #[serde(default)]
+    preserve_structure: bool,
+    /// How to choose the returned output when final_output holds several files.
+    #[serde(default)]
+    output_selection: Option<SelectionStrategy>,
+    /// Kill run.py as soon as it reports a failed image instead of letting it finish
+    /// the rest of the batch.
+    #[serde(default)]
+    stop_on_error: bool,
+    /// Report run.py's CPU and memory use once a second.
+    #[serde(default)]
+    monitor_proc: bool,
+    /// Restore each page of a multi-page TIFF input as its own image.
+    #[serde(default)]
+    split_tiff_pages: bool,
+    /// With `split_tiff_pages`, assemble the restored pages into `final_output/<stem>.tif`
+    /// and return that as the output.
+    #[serde(default)]
+    recombine_tiff: bool,
+    /// Give the run its own `<output_folder>/<run_id>` folder so runs never share or
+    /// clear each other's stage folders.
+    #[serde(default)]
+    isolate_runs: bool,
+    /// How many times the input has already been through the pipeline: 0 for a fresh
+    /// input, 1 when it is an earlier run's output, and so on. Set by [`reprocess`].
+    #[serde(default)]
+    reprocess_depth: u32,
+    /// For folder inputs, restore the first image on its own and only start the batch
+    /// if that works.
+    #[serde(default)]
+    test_first: bool,
+    /// Overrides how often the event loop checks whether run.py has exited; values
+    /// below 20 ms are raised to 20.
+    #[serde(default)]
+    poll_interval_ms: Option<u64>,
+    /// Run folder inputs this many files at a time, one run.py process per chunk, so a
+    /// crash only loses the current chunk. Ignored when `isolate_files` is set.
+    #[serde(default)]
+    chunk_size: Option<usize>,
+    /// Re-encode each output without its embedded color profile, so viewers show it as
+    /// sRGB.
+    #[serde(default)]
+    convert_srgb: bool,
+    /// Convert each output to grayscale, for black-and-white photos.
+    #[serde(default)]
+    force_grayscale: bool,
+    /// Downscale outputs whose longest edge is larger than this many pixels, keeping
+    /// their aspect ratio. Smaller outputs are left as they are.
+    #[serde(default)]
+    max_output_edge: Option<u32>,
+    /// Run an edge-preserving denoise filter over each output, 1 (light) to 10 (strong);
+    /// other values are clamped into that range.
+    #[serde(default)]
+    denoise: Option<u8>,
+    /// Copy each input's EXIF metadata, including its orientation, into its output, so
+    /// rotated scans display upright. Inputs without EXIF leave their output alone.
+    #[serde(default = "default_true")]
+    preserve_metadata: bool,
+    /// Re-encode the outputs as `png` (run.py's own format, left alone), `jpeg` or
+    /// `webp`. The converted files replace run.py's.
+    #[serde(default)]
+    output_format: Option<String>,
+    /// JPEG quality for `output_format: "jpeg"`, 1-100; other values are clamped with a
+    /// warning. Defaults to [`DEFAULT_JPEG_QUALITY`].
+    #[serde(default)]
+    jpeg_quality: Option<u8>,
+    /// Cap on the threads torch and its math libraries use, through `OMP_NUM_THREADS`
+    /// and `MKL_NUM_THREADS`, so CPU runs leave cores free on shared machines.
+    #[serde(default)]
+    cpu_threads: Option<usize>,
+    /// Directory run.py is started in, for forks that resolve checkpoints relative to
+    /// a different folder. Relative paths are taken from the project root, which is
+    /// also the default. run.py itself is always the project root's.
+    #[serde(default)]
+    working_dir: Option<String>,
+    /// Keep the stage 1-3 folders and the `_gui_input` copies after a successful run.
+    /// By default they are deleted once the outputs are in final_output; a failed run
+    /// always keeps them for inspection.
+    #[serde(default)]
+    keep_intermediates: bool,
+    /// When a GPU run fails because CUDA ran out of memory, run it once more on the CPU.
+    /// The stage folders are cleared again first if `clear_previous` is set. Applies to
+    /// runs in a single run.py process, not to `isolate_files` or `chunk_size` batches.
+    #[serde(default)]
+    cpu_fallback: bool,
+    /// Refuse to start, before anything in the output folder is cleared, when its
+    /// filesystem has less free space than this. Defaults to [`DEFAULT_MIN_FREE_BYTES`];
+    /// 0 turns the check off.
+    #[serde(default)]
+    min_free_bytes: Option<u64>,
+    /// Kill run.py and fail the run after this many seconds without a line of output,
+    /// for scripts that occasionally hang. Unset waits forever.
+    #[serde(default)]
+    stall_timeout_secs: Option<u64>,
+    /// Extra environment variables for run.py, e.g. `CUDA_VISIBLE_DEVICES`. They
+    /// override inherited ones; `PYTHONUNBUFFERED` is always 1.
+    #[serde(default)]
+    env: Option<HashMap<String, String>>,
+    /// A conda environment whose `bin` (`Scripts` on Windows) goes first on run.py's
+    /// `PATH`, so its libraries load.
+    #[serde(default)]
+    conda_prefix: Option<String>,
+    /// Only work out the run.py command and return it as the result's `command`;
+    /// nothing is spawned, copied or cleared.
+    #[serde(default)]
+    dry_run: bool,
+    /// Save a copy of each output's input in `originals`, beside final_output, named
+    /// `<output stem>_original.<input extension>`.
+    #[serde(default)]
+    keep_original_copy: bool,
+    /// For folder inputs, only process files whose name matches this glob, e.g. `*.jpg`.
+    #[serde(default)]
+    include_glob: Option<String>,
+    /// For folder inputs, skip files whose name matches this glob.
+    #[serde(default)]
+    exclude_glob: Option<String>,
+    /// A command line to run after a successful run, with the output folder appended
+    /// as its last argument. Only runs when `allow_post_hook` is also set.
+    #[serde(default)]
+    post_hook: Option<String>,
+    #[serde(default)]
+    allow_post_hook: bool,
+    /// Run at most one GPU-using run.py process at a time across all jobs; others wait
+    /// for the GPU to be released. CPU runs are never held back.
+    #[serde(default)]
+    serialize_gpu: bool,
+    /// Write `manifest.json` describing every output into the output folder.
+    #[serde(default = "default_true")]
+    write_manifest: bool,
+    /// How many times dlib upsamples the image before looking for faces (0-3). This and
+    /// the other face settings are only passed if the installed run.py accepts them.
+    #[serde(default)]
+    face_upsample: Option<u32>,
+    /// Detection score adjustment; higher finds fewer, surer faces (-1.0 to 1.0).
+    #[serde(default)]
+    face_threshold: Option<f32>,
+    /// Keep at most this many faces per image.
+    #[serde(default)]
+    max_faces: Option<u32>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum SelectionStrategy {
+    /// Most recently modified file.
+    #[default]
+    Newest,
+    /// Largest file on disk.
+    Largest,
+    /// First file in name order.
+    ByName,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Default)]
 #[serde(rename_all = "camelCase")]
 struct ModifyPhotoResult {
+    run_id: String,
     output_path: String,
+    /// Every image in final_output with the input it was made from. A single-image
+    /// run lists just `output_path`.
+    outputs: Vec<OutputItem>,
+    /// `(file name, error)` for every file that failed in an isolated batch.
+    failed: Vec<(String, String)>,
+    /// For recursive inputs: output path -> the nested input it was produced from.
+    source_paths: HashMap<String, String>,
+    /// With `preserve_structure`: output path -> its copy in the mirrored folder tree.
+    structured_paths: HashMap<String, String>,
+    /// With `keep_original_copy`: output path -> the copy of its input saved next to it.
+    original_copies: HashMap<String, String>,
+    /// The archive of restored images written by `restore_from_zip` with `rezip`.
+    archive_path: Option<String>,
+    /// Stage number (1-3, 4 = final) -> images that stage produced.
+    stage_counts: HashMap<u8, usize>,
+    /// Why the post-run hook failed. The restoration itself still succeeded.
+    hook_error: Option<String>,
+    manifest_path: Option<String>,
+    /// Images stage 3 found no faces in, so their face enhancement was a no-op.
+    no_faces: Vec<String>,
+    /// Images run.py skipped because they already had outputs.
+    skipped: Vec<String>,
+    /// Outputs downscaled to `max_output_edge`.
+    resized: Vec<String>,
+    reprocess_depth: u32,
+    /// Time spent in each stage, 0 (startup) to 4 (final, up to "Done"); stages that
+    /// never ran report 0.
+    stage_durations: Vec<StageTiming>,
+    /// Small PNG copies of the input and the output for a before/after card. Not made
+    /// for folder inputs, and `None` if writing them failed.
+    input_thumb: Option<String>,
+    output_thumb: Option<String>,
+    /// `output_folder/logs/run_<run_id>.log`: every line run.py printed, timestamped.
+    /// `None` if the log couldn't be opened.
+    log_path: Option<String>,
+    /// What a `dry_run` would have started. Only set for dry runs, which leave every
+    /// other field empty.
+    command: Option<PipelineCommandLine>,
 }
 
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
+struct PipelineCommandLine {
+    argv: Vec<String>,
+    working_dir: String,
+    /// Environment variables set on top of the app's own.
+    env: BTreeMap<String, String>,
+    /// `argv` quoted for the platform's shell, ready to paste into a terminal.
+    shell: String,
+}
+
+/// One stage of a customized run.py, see [`ModifyPhotoArgs::pipeline`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct StageSpec {
+    /// The N of its `Running Stage N` banner.
+    stage: u8,
+    /// The folder under the output folder it writes to.
+    output_dir: String,
+    /// Set on the one stage whose folder holds the results.
+    #[serde(default)]
+    is_final: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct StageTiming {
+    stage: u8,
+    millis: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct OutputItem {
+    /// The input with the same file stem, or `None` for outputs no input of this run
+    /// accounts for, e.g. ones left by an earlier run.
+    input: Option<String>,
+    output: String,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
 struct ProgressEvent {
     run_id: String,
     stage: Option<u8>,
     message: String,
     is_error: bool,
+    /// Set when a log line was recognized as a known, fixable problem.
+    diagnostic: Option<Diagnostic>,
+    /// Set on the periodic resource samples taken when `monitor_proc` is on; these
+    /// events carry no message.
+    proc_stats: Option<ProcStats>,
+    /// Set on the liveness events sent every [`HEARTBEAT_INTERVAL`] while run.py is
+    /// running: seconds since it started. These events carry no message.
+    elapsed_secs: Option<u64>,
+    /// Set on output lines from the post-run hook rather than from run.py.
+    hook: bool,
+    /// Set on the event sent for each image run.py skipped as already processed.
+    skipped: bool,
+    /// Set when log lines were dropped to keep the UI responsive: how many since the
+    /// last such event.
+    omitted: Option<usize>,
+    /// Set on the event sent when run.py reports which device it bound to, e.g. `cuda:0`
+    /// or `cpu`.
+    device_selected: Option<String>,
+    /// Coarse overall progress of a folder batch, 0-100, from how many outputs have
+    /// appeared in final_output. Sent without a message whenever it changes.
+    percent: Option<u8>,
+    /// Progress within `stage`, 0-100, when the line carries a counter; see
+    /// [`stage_percent_from_line`].
+    stage_percent: Option<f32>,
+    /// Set on a line run.py redraws in place with `\r`, such as a tqdm bar. It replaces
+    /// the previous in-place line rather than adding to the log.
+    in_place: bool,
+    /// For folder inputs: which image, 1-based, the current stage is working on, from
+    /// run.py's per-image "processing" lines. Unset when the log doesn't say.
+    file_index: Option<usize>,
+    /// How many images the folder has; set together with `file_index`.
+    file_total: Option<usize>,
+    /// Estimated seconds left, from the average stage durations of earlier successful
+    /// runs with the same `hr` and `with_scratch`. Unset without such history.
+    eta_secs: Option<u64>,
+    /// Set on the event announcing a finished stage's result image. It is delivered
+    /// as a `stage_preview` event instead of as progress.
+    preview: Option<PreviewEvent>,
+}
+
+/// The newest image a stage left behind, sent once per stage when the next one starts.
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct PreviewEvent {
+    run_id: String,
+    /// The stage that produced the image.
+    stage: u8,
+    image_path: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ProcStats {
+    /// Percent of one core, so it can exceed 100 on multi-threaded work.
+    cpu_percent: f32,
+    rss_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Diagnostic {
+    kind: String,
+    detail: String,
+    hint: String,
+}
+
+/// Python packages the pipeline can't start without, with their pip names.
+const KEY_PYTHON_MODULES: [(&str, &str); 5] = [
+    ("torch", "torch"),
+    ("torchvision", "torchvision"),
+    ("cv2", "opencv-python"),
+    ("PIL", "Pillow"),
+    ("dlib", "dlib"),
+];
+
+/// Recognizes `ModuleNotFoundError: No module named 'torch'` and friends for the
+/// packages in [`KEY_PYTHON_MODULES`].
+fn missing_module_from_line(line: &str) -> Option<Diagnostic> {
+    if !line.contains("ModuleNotFoundError") && !line.contains("ImportError") {
+        return None;
+    }
+    let rest = &line[line.find("No module named")? + "No module named".len()..];
+    let name = rest.trim().trim_matches(|c| c == '\'' || c == '"');
+    let top_level = name.split('.').next().unwrap_or(name);
+    let (module, package) = KEY_PYTHON_MODULES
+        .iter()
+        .find(|(module, _)| *module == top_level)?;
+    Some(Diagnostic {
+        kind: "missing_module".to_string(),
+        detail: format!("Python module '{module}' is not installed"),
+        hint: format!("pip install {package}"),
+    })
+}
+
+/// The stage a `Running Stage N` banner announces, matched case-insensitively with any
+/// whitespace between the words, so `running stage 2:` counts too. Only the numbers in
+/// `stages` count.
+fn stage_from_line(line: &str, stages: &[u8]) -> Option<u8> {
+    let line = line.to_ascii_lowercase();
+    line.match_indices("running").find_map(|(at, word)| {
+        let rest = &line[at + word.len()..];
+        let rest = rest.strip_prefix(char::is_whitespace)?.trim_start();
+        let rest = rest.strip_prefix("stage")?;
+        let rest = rest.strip_prefix(char::is_whitespace)?.trim_start();
+        let digits = &rest[..rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len())];
+        let stage = digits.parse().ok()?;
+        stages.contains(&stage).then_some(stage)
+    })
+}
+
+/// Progress within the current stage, 0-100, from counters such as `0120/0500` or
+/// `Processing image 3 of 12`. Lines without one give `None`.
+fn stage_percent_from_line(line: &str) -> Option<f32> {
+    let count = |s: &str| s.parse::<u32>().ok();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let counter = words
+        .iter()
+        .find_map(|word| {
+            let (current, total) = word.split_once('/')?;
+            let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+            (digits(current) && digits(total)).then(|| (count(current), count(total)))
+        })
+        .or_else(|| {
+            words.windows(3).find(|w| w[1] == "of").map(|w| {
+                (
+                    count(w[0]),
+                    count(w[2].trim_end_matches([',', '.', ':', ')'])),
+                )
+            })
+        });
+    match counter? {
+        (Some(current), Some(total)) if total > 0 => {
+            Some((current as f32 / total as f32 * 100.0).min(100.0))
+        }
+        _ => None,
+    }
+}
+
+/// Adds up how long a run spends in each stage, from the stages of the events it
+/// emits. Isolated batches go through the stages once per chunk; each pass counts.
+/// A run that fails keeps its timings so far in the run history.
+struct StageClock {
+    /// The stage being timed and when it began; `None` once the run is done.
+    current: Mutex<Option<(u8, Instant)>>,
+    totals: Mutex<[Duration; 5]>,
 }
 
-fn stage_from_line(line: &str) -> Option<u8> {
-    if line.contains("Running Stage 1") {
-        return Some(1);
+impl StageClock {
+    fn start() -> Self {
+        Self {
+            current: Mutex::new(Some((0, Instant::now()))),
+            totals: Mutex::new([Duration::ZERO; 5]),
+        }
     }
-    if line.contains("Running Stage 2") {
-        return Some(2);
+
+    /// Switches to `stage`, or stops the clock when `done`.
+    fn observe(&self, stage: u8, done: bool) {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let Some((running, since)) = *current else {
+            return;
+        };
+        if running == stage && !done {
+            return;
+        }
+        if let Some(total) = self
+            .totals
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(usize::from(running))
+        {
+            *total += since.elapsed();
+        }
+        *current = (!done).then(|| (stage, Instant::now()));
     }
-    if line.contains("Running Stage 3") {
-        return Some(3);
+
+    /// The stage being timed and how long it has run so far.
+    fn current(&self) -> Option<(u8, Duration)> {
+        let current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        current.map(|(stage, since)| (stage, since.elapsed()))
     }
-    if line.contains("Running Stage 4") {
-        return Some(4);
+
+    fn durations(&self) -> Vec<StageTiming> {
+        self.observe(0, true);
+        let totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        (0u8..)
+            .zip(totals.iter())
+            .map(|(stage, total)| StageTiming {
+                stage,
+                millis: total.as_millis() as u64,
+            })
+            .collect()
     }
-    None
 }
 
 fn emit_progress(app: &tauri::AppHandle, event: ProgressEvent) {
     let _ = app.emit("modify_progress", event);
 }
 
+/// Mirrors progress events as newline-delimited JSON into a file or named pipe so
+/// external tooling can follow a run without a webview. A FIFO must already have a
+/// reader when the run starts, see [`EventPipe::open`]. If the reader goes away mid-run
+/// the pipe is dropped and the Tauri events keep flowing.
+struct EventPipe {
+    path: PathBuf,
+    file: Mutex<Option<fs::File>>,
+}
+
+impl EventPipe {
+    /// Fails right away when `path` is a FIFO nobody reads, instead of blocking the run
+    /// until a reader shows up.
+    fn open(path: &str) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        let mut options = fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NONBLOCK);
+        let file = options.open(&path).map_err(|e| {
+            #[cfg(unix)]
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                return format!("No reader on event pipe {}", path.display());
+            }
+            format!("Failed to open event pipe {}: {e}", path.display())
+        })?;
+        // Only the open must not block; a slow reader may hold up writes as before.
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            let fd = file.as_raw_fd();
+            // SAFETY: `fd` is the open descriptor owned by `file`.
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                if flags >= 0 {
+                    libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+                }
+            }
+        }
+        Ok(Self {
+            path,
+            file: Mutex::new(Some(file)),
+        })
+    }
+
+    fn write(&self, event: &ProgressEvent) {
+        let mut guard = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(file) = guard.as_mut() else {
+            return;
+        };
+        let result = serde_json::to_string(event)
+            .map_err(std::io::Error::from)
+            .and_then(|line| file.write_all(format!("{line}\n").as_bytes()));
+        if let Err(e) = result {
+            eprintln!(
+                "Event pipe {} closed, continuing without it: {e}",
+                self.path.display()
+            );
+            *guard = None;
+        }
+    }
+}
+
 fn project_root() -> Result<PathBuf, String> {
-    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    let ui_dir = manifest_dir
-        .parent()
-        .ok_or_else(|| "Unable to locate ui directory".to_string())?;
-    let root_dir = ui_dir
+    project_root_from(Path::new(env!("CARGO_MANIFEST_DIR")))
+}
+
+/// The nearest folder at or above `manifest_dir` that holds run.py. Without one, the
+/// expected layout `<root>/ui/src-tauri` is assumed.
+fn project_root_from(manifest_dir: &Path) -> Result<PathBuf, String> {
+    if let Some(root) = manifest_dir
+        .ancestors()
+        .find(|dir| dir.join("run.py").is_file())
+    {
+        return Ok(root.to_path_buf());
+    }
+    manifest_dir
         .parent()
-        .ok_or_else(|| "Unable to locate project root directory".to_string())?;
-    Ok(root_dir.to_path_buf())
+        .and_then(Path::parent)
+        .filter(|root| !root.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            format!(
+                "Unable to locate the project root from CARGO_MANIFEST_DIR {}: expected it \
+                 to be <project>/ui/src-tauri, and no folder above it contains run.py",
+                manifest_dir.display()
+            )
+        })
 }
 
 fn ensure_single_image_folder(input_path: &Path, output_folder: &Path) -> Result<PathBuf, String> {
+    copy_to_input_folder(
+        std::slice::from_ref(&input_path.to_path_buf()),
+        output_folder,
+    )
+}
+
+/// Copies `files` into a fresh `output_folder/_gui_input` and returns that folder.
+fn copy_to_input_folder(files: &[PathBuf], output_folder: &Path) -> Result<PathBuf, String> {
     let input_dir = output_folder.join("_gui_input");
     if input_dir.exists() {
         fs::remove_dir_all(&input_dir).map_err(|e| format!("Failed to clear _gui_input: {e}"))?;
     }
     fs::create_dir_all(&input_dir).map_err(|e| format!("Failed to create _gui_input: {e}"))?;
 
-    let file_name = input_path
-        .file_name()
-        .ok_or_else(|| "Invalid input file path".to_string())?;
-    let dst = input_dir.join(file_name);
-    fs::copy(input_path, &dst).map_err(|e| format!("Failed to copy input file: {e}"))?;
+    for file in files {
+        let file_name = file
+            .file_name()
+            .ok_or_else(|| "Invalid input file path".to_string())?;
+        let dst = input_dir.join(file_name);
+        copy_retrying_locked(file, || fs::copy(file, &dst), is_file_locked)?;
+    }
     Ok(input_dir)
 }
 
-fn pick_latest_file(dir_path: &Path) -> Result<Option<PathBuf>, String> {
+/// Windows' ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION: another program has the
+/// file open without sharing it.
+const LOCKED_FILE_ERRORS: [i32; 2] = [32, 33];
+/// A lock held by e.g. an image viewer that is just closing is often gone a moment
+/// later, so a locked copy is retried this many times in total.
+const LOCKED_COPY_ATTEMPTS: u32 = 3;
+const LOCKED_COPY_DELAY: Duration = Duration::from_millis(300);
+
+fn is_file_locked(e: &std::io::Error) -> bool {
+    cfg!(windows)
+        && e.raw_os_error()
+            .is_some_and(|code| LOCKED_FILE_ERRORS.contains(&code))
+}
+
+/// Runs `copy` of the input `src`, retrying while `is_locked` says the file is in use.
+fn copy_retrying_locked(
+    src: &Path,
+    mut copy: impl FnMut() -> std::io::Result<u64>,
+    is_locked: impl Fn(&std::io::Error) -> bool,
+) -> Result<(), String> {
+    let mut attempt = 1;
+    loop {
+        match copy() {
+            Ok(_) => return Ok(()),
+            Err(e) if is_locked(&e) => {
+                if attempt == LOCKED_COPY_ATTEMPTS {
+                    return Err(format!(
+                        "Input file is in use by another program: {}",
+                        src.display()
+                    ));
+                }
+                attempt += 1;
+                thread::sleep(LOCKED_COPY_DELAY);
+            }
+            Err(e) => return Err(format!("Failed to copy {}: {e}", src.display())),
+        }
+    }
+}
+
+/// Whether `path` starts like an image the `image` crate can read. Only the header is
+/// parsed, so a file truncated past it still passes.
+fn has_image_header(path: &Path) -> bool {
+    image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .is_ok_and(|reader| reader.format().is_some() && reader.into_dimensions().is_ok())
+}
+
+/// The name `path` should have given the format its contents actually are, or `None`
+/// when the extension already fits or the format isn't recognized.
+fn sniffed_name(path: &Path) -> Option<String> {
+    let mut header = [0u8; 64];
+    let len = fs::File::open(path)
+        .and_then(|mut f| std::io::Read::read(&mut f, &mut header))
+        .ok()?;
+    let format = image::guess_format(&header[..len]).ok()?;
+    let extensions = format.extensions_str();
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    if ext.as_deref().is_some_and(|e| extensions.contains(&e)) {
+        return None;
+    }
+    let stem = match ext {
+        Some(_) => path.file_stem()?,
+        None => path.file_name()?,
+    };
+    Some(format!(
+        "{}.{}",
+        stem.to_string_lossy(),
+        extensions.first()?
+    ))
+}
+
+/// Renames staged copies whose extension doesn't match their contents, keeping
+/// `staged` (staged name -> original) in step.
+fn fix_staged_extensions(
+    staging: &Path,
+    staged: &mut HashMap<String, PathBuf>,
+    run_id: &str,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<(), String> {
+    for file in list_input_files(staging)? {
+        let Some(fixed) = sniffed_name(&file) else {
+            continue;
+        };
+        let target = file.with_file_name(&fixed);
+        if target.exists() {
+            continue;
+        }
+        fs::rename(&file, &target)
+            .map_err(|e| format!("Failed to rename {}: {e}", file.display()))?;
+        let name = file
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some(source) = staged.remove(&name) {
+            staged.insert(fixed.clone(), source);
+        }
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: Some(0),
+            message: format!("{name} is really {fixed}; renamed for processing"),
+            ..Default::default()
+        });
+    }
+    Ok(())
+}
+
+fn is_tiff(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("tif") || e.eq_ignore_ascii_case("tiff"))
+}
+
+fn tiff_page_stem(stem: &str, index: usize) -> String {
+    format!("{stem}_p{:03}", index + 1)
+}
+
+/// Writes each page of a multi-page TIFF as `<stem>_pNNN.png` into `_gui_input`.
+/// Returns the folder and the page count, or `None` for a single-page TIFF.
+fn stage_tiff_pages(
+    input_path: &Path,
+    output_folder: &Path,
+    run_id: &str,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<Option<(PathBuf, usize)>, String> {
+    let pages = imaging::read_tiff_pages(input_path)?;
+    if pages.len() <= 1 {
+        return Ok(None);
+    }
+    let stem = input_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .ok_or_else(|| "Invalid input file path".to_string())?;
+
+    let input_dir = output_folder.join("_gui_input");
+    if input_dir.exists() {
+        fs::remove_dir_all(&input_dir).map_err(|e| format!("Failed to clear _gui_input: {e}"))?;
+    }
+    fs::create_dir_all(&input_dir).map_err(|e| format!("Failed to create _gui_input: {e}"))?;
+    let total = pages.len();
+    for (i, page) in pages.iter().enumerate() {
+        let dst = input_dir.join(format!("{}.png", tiff_page_stem(&stem, i)));
+        imaging::save_image(page, &dst, image::ImageFormat::Png)?;
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: Some(0),
+            message: format!("Extracted page {}/{total}", i + 1),
+            ..Default::default()
+        });
+    }
+    Ok(Some((input_dir, total)))
+}
+
+/// Reassembles restored pages from `final_dir` into `final_dir/<stem>.tif`, in page
+/// order. Pages without an output are left out with a warning.
+fn recombine_tiff_pages(
+    final_dir: &Path,
+    stem: &str,
+    total: usize,
+    run_id: &str,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<PathBuf, String> {
+    let outputs = list_input_files(final_dir)?;
+    let mut pages = Vec::new();
+    for i in 0..total {
+        let page_stem = tiff_page_stem(stem, i);
+        let found = outputs.iter().find(|p| {
+            p.file_stem()
+                .is_some_and(|s| s.to_string_lossy() == page_stem)
+        });
+        let Some(path) = found else {
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: Some(4),
+                message: format!("Page {}/{total} has no output; leaving it out", i + 1),
+                is_error: true,
+                ..Default::default()
+            });
+            continue;
+        };
+        pages.push(imaging::open_image(path)?);
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: Some(4),
+            message: format!("Recombined page {}/{total}", i + 1),
+            ..Default::default()
+        });
+    }
+    if pages.is_empty() {
+        return Err("No restored pages to recombine".to_string());
+    }
+    let out = final_dir.join(format!("{stem}.tif"));
+    imaging::write_multipage_tiff(&out, &pages)?;
+    Ok(out)
+}
+
+/// Extensions treated as images when scanning input folders and picking outputs.
+pub(crate) const IMAGE_EXTENSIONS: [&str; 7] = ["png", "jpg", "jpeg", "bmp", "tif", "tiff", "webp"];
+
+pub(crate) fn has_image_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Every non-hidden image below `dir_path`, sorted. Symlinked folders are not followed.
+fn collect_images_recursive(dir_path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir_path).map_err(|e| format!("Failed to read dir: {e}"))?;
+    let mut paths: Vec<PathBuf> = entries
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Failed to read dir entry: {e}"))?;
+    paths.sort();
+    for p in paths {
+        let hidden = p
+            .file_name()
+            .and_then(|s| s.to_str())
+            .is_some_and(|name| name.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        let Ok(meta) = fs::symlink_metadata(&p) else {
+            continue;
+        };
+        if meta.is_dir() {
+            collect_images_recursive(&p, files)?;
+        } else if p.is_file() && has_image_extension(&p) {
+            files.push(p);
+        }
+    }
+    Ok(())
+}
+
+/// `relative` as a single file name with `__` between its components, with a `_<n>`
+/// suffix added while `taken` reports the name as already used.
+pub(crate) fn flat_name(relative: &Path, taken: impl Fn(&str) -> bool) -> String {
+    let flat = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("__");
+    let mut name = flat.clone();
+    let mut n = 1;
+    while taken(&name) {
+        let path = Path::new(&flat);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = path.extension().unwrap_or_default().to_string_lossy();
+        name = format!("{stem}_{n}.{ext}");
+        n += 1;
+    }
+    name
+}
+
+const NOT_INCLUDED: &str = "does not match include_glob";
+const EXCLUDED: &str = "matches exclude_glob";
+
+/// `include_glob` / `exclude_glob` from the run arguments, matched case-insensitively
+/// against file names.
+#[derive(Debug, Default)]
+struct NameFilter {
+    include: Option<glob::Pattern>,
+    exclude: Option<glob::Pattern>,
+}
+
+impl NameFilter {
+    fn from_args(args: &ModifyPhotoArgs) -> Result<Self, String> {
+        let parse = |raw: &Option<String>, field: &str| match raw.as_deref().map(str::trim) {
+            Some(p) if !p.is_empty() => glob::Pattern::new(p)
+                .map(Some)
+                .map_err(|e| format!("Invalid {field} \"{p}\": {e}")),
+            _ => Ok(None),
+        };
+        Ok(Self {
+            include: parse(&args.include_glob, "include_glob")?,
+            exclude: parse(&args.exclude_glob, "exclude_glob")?,
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        self.include.is_some() || self.exclude.is_some()
+    }
+
+    /// Why `path` is filtered out, or `None` if it passes both patterns.
+    fn rejects(&self, path: &Path) -> Option<&'static str> {
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        let name_matches = |pattern: &glob::Pattern| {
+            path.file_name()
+                .is_some_and(|n| pattern.matches_with(&n.to_string_lossy(), options))
+        };
+        if self.include.as_ref().is_some_and(|p| !name_matches(p)) {
+            Some(NOT_INCLUDED)
+        } else if self.exclude.as_ref().is_some_and(name_matches) {
+            Some(EXCLUDED)
+        } else {
+            None
+        }
+    }
+
+    /// Keeps the files that pass both patterns. Also returns how many files the include
+    /// and the exclude pattern each removed.
+    fn apply(&self, files: Vec<PathBuf>) -> (Vec<PathBuf>, usize, usize) {
+        let (mut not_included, mut excluded) = (0, 0);
+        let kept = files
+            .into_iter()
+            .filter(|path| match self.rejects(path) {
+                Some(NOT_INCLUDED) => {
+                    not_included += 1;
+                    false
+                }
+                Some(_) => {
+                    excluded += 1;
+                    false
+                }
+                None => true,
+            })
+            .collect();
+        (kept, not_included, excluded)
+    }
+}
+
+/// The files of the folder `input_path` a run picks up: nested images with
+/// `recursive`, otherwise every non-hidden file, before `filter` is applied.
+fn folder_inputs(input_path: &Path, args: &ModifyPhotoArgs) -> Result<Vec<PathBuf>, String> {
+    if args.recursive {
+        let mut files = Vec::new();
+        collect_images_recursive(input_path, &mut files)?;
+        Ok(files)
+    } else {
+        list_input_files(input_path)
+    }
+}
+
+/// Copies `files`, all below `input_dir`, into `output_folder/_gui_input`, naming each
+/// after its relative path (`2019/trip/a.png` becomes `2019__trip__a.png`). Returns the
+/// staging folder and a map of staged file name -> original path.
+fn stage_files(
+    input_dir: &Path,
+    files: &[PathBuf],
+    output_folder: &Path,
+) -> Result<(PathBuf, HashMap<String, PathBuf>), String> {
+    let staging = output_folder.join("_gui_input");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(|e| format!("Failed to clear _gui_input: {e}"))?;
+    }
+    fs::create_dir_all(&staging).map_err(|e| format!("Failed to create _gui_input: {e}"))?;
+
+    let mut sources = HashMap::new();
+    for file in files {
+        let relative = file.strip_prefix(input_dir).unwrap_or(file);
+        let name = flat_name(relative, |name| sources.contains_key(name));
+        let dst = staging.join(&name);
+        copy_retrying_locked(file, || fs::copy(file, &dst), is_file_locked)?;
+        sources.insert(name, file.clone());
+    }
+    Ok((staging, sources))
+}
+
+/// Matches files in `final_dir` to staged inputs by file stem, since the pipeline may
+/// change the extension. Returns output path -> original input path.
+fn map_outputs_to_sources(
+    final_dir: &Path,
+    staged: &HashMap<String, PathBuf>,
+) -> Result<HashMap<String, String>, String> {
+    if staged.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let by_stem: HashMap<String, &PathBuf> = staged
+        .iter()
+        .map(|(name, src)| {
+            let stem = Path::new(name).file_stem().unwrap_or_default();
+            (stem.to_string_lossy().to_string(), src)
+        })
+        .collect();
+
+    let mut mapping = HashMap::new();
+    for output in list_input_files(final_dir)? {
+        let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+        if let Some(src) = by_stem.get(stem.as_ref()) {
+            mapping.insert(
+                output.to_string_lossy().to_string(),
+                src.to_string_lossy().to_string(),
+            );
+        }
+    }
+    Ok(mapping)
+}
+
+/// Quality for `output_format: "jpeg"` when `jpeg_quality` isn't set; the encoder's
+/// own default of 75 visibly degrades a restored photo.
+const DEFAULT_JPEG_QUALITY: u8 = 95;
+
+/// The format to convert outputs to, `None` for run.py's PNGs as they are.
+fn output_format(args: &ModifyPhotoArgs) -> Result<Option<image::ImageFormat>, String> {
+    let Some(name) = args.output_format.as_deref() else {
+        return Ok(None);
+    };
+    match imaging::parse_format(name) {
+        Ok(image::ImageFormat::Png) => Ok(None),
+        Ok(format @ (image::ImageFormat::Jpeg | image::ImageFormat::WebP)) => Ok(Some(format)),
+        _ => Err(format!(
+            "Unsupported output_format {name:?}: use png, jpeg or webp"
+        )),
+    }
+}
+
+/// Folder under the output folder for the before/after thumbnails of single-image runs.
+const COMPARISON_THUMBS_DIR: &str = "_thumbs";
+const COMPARISON_THUMB_EDGE: u32 = 512;
+
+/// Writes `<stem>_before.png` and `<stem>_after.png`, no larger than
+/// [`COMPARISON_THUMB_EDGE`], into [`COMPARISON_THUMBS_DIR`], named after `output`.
+fn comparison_thumbs(
+    input: &Path,
+    output: &Path,
+    output_folder: &Path,
+) -> Result<(PathBuf, PathBuf), String> {
+    let dir = output_folder.join(COMPARISON_THUMBS_DIR);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let thumb = |source: &Path, suffix: &str| {
+        let path = dir.join(format!("{stem}_{suffix}.png"));
+        let img = imaging::shrink_to_fit(imaging::open_image(source)?, COMPARISON_THUMB_EDGE);
+        imaging::save_image(&img, &path, image::ImageFormat::Png)?;
+        Ok::<_, String>(path)
+    };
+    Ok((thumb(input, "before")?, thumb(output, "after")?))
+}
+
+/// Appended to the stem of the input copies written with `keep_original_copy`.
+const ORIGINAL_SUFFIX: &str = "_original";
+
+/// Where those copies go, under the output folder. They stay out of final_output so
+/// nothing that lists the outputs takes them for results.
+const ORIGINALS_DIR: &str = "originals";
+
+/// Appended to the input's stem to name a single-image run's output.
+const RESTORED_SUFFIX: &str = "_restored";
+
+/// Renames a single-image run's `output` to `<input stem>_restored.<output extension>`,
+/// so its name matches the picked file whatever run.py called it. An existing file of
+/// that name is kept and a numeric suffix added instead.
+fn rename_after_input(output: &Path, input: &Path) -> Result<PathBuf, String> {
+    let (Some(dir), Some(stem)) = (output.parent(), input.file_stem()) else {
+        return Ok(output.to_path_buf());
+    };
+    let stem = stem.to_string_lossy();
+    let ext = output
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut renamed = dir.join(format!("{stem}{RESTORED_SUFFIX}{ext}"));
+    let mut n = 2;
+    while renamed != output && renamed.exists() {
+        renamed = dir.join(format!("{stem}{RESTORED_SUFFIX}_{n}{ext}"));
+        n += 1;
+    }
+    if renamed != output {
+        fs::rename(output, &renamed)
+            .map_err(|e| format!("Failed to rename {}: {e}", output.display()))?;
+    }
+    Ok(renamed)
+}
+
+/// Folder under the output that [`mirror_input_structure`] copies into.
+const STRUCTURED_DIR: &str = "structured";
+
+/// Copies each output in `source_paths` (output -> original input) to
+/// `output_folder/structured/<input's path below input_dir>`, keeping the output's
+/// extension. Returns output path -> copy.
+fn mirror_input_structure(
+    source_paths: &HashMap<String, String>,
+    input_dir: &Path,
+    output_folder: &Path,
+) -> Result<HashMap<String, String>, String> {
+    let structured = output_folder.join(STRUCTURED_DIR);
+    let mut copies = HashMap::new();
+    for (output, source) in source_paths {
+        let output = Path::new(output);
+        let relative = Path::new(source)
+            .strip_prefix(input_dir)
+            .map_err(|_| format!("{source} is not inside {}", input_dir.display()))?;
+        let mut dest = structured.join(relative);
+        if let Some(ext) = output.extension() {
+            dest.set_extension(ext);
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+        }
+        fs::copy(output, &dest).map_err(|e| format!("Failed to copy {}: {e}", output.display()))?;
+        copies.insert(
+            output.to_string_lossy().to_string(),
+            dest.to_string_lossy().to_string(),
+        );
+    }
+    Ok(copies)
+}
+
+/// Picks one non-hidden image from `dir_path` that `accept` allows, using `strategy`.
+fn select_output_file(
+    dir_path: &Path,
+    strategy: SelectionStrategy,
+    accept: impl Fn(&Path) -> bool,
+) -> Result<Option<PathBuf>, String> {
+    if strategy == SelectionStrategy::Newest {
+        return pick_latest_file(dir_path, accept);
+    }
+    if !dir_path.is_dir() {
+        return Ok(None);
+    }
+
+    let candidates = list_input_files(dir_path)?
+        .into_iter()
+        .filter(|p| has_image_extension(p) && accept(p));
+    match strategy {
+        SelectionStrategy::ByName => Ok(candidates.min()),
+        _ => {
+            let mut largest: Option<(u64, PathBuf)> = None;
+            for p in candidates {
+                let size = fs::metadata(&p)
+                    .map_err(|e| format!("Failed to stat file {}: {e}", p.display()))?
+                    .len();
+                if largest.as_ref().is_none_or(|(cur, _)| size > *cur) {
+                    largest = Some((size, p));
+                }
+            }
+            Ok(largest.map(|(_, p)| p))
+        }
+    }
+}
+
+/// Newest non-hidden image in `dir_path` for which `accept` returns true. Files without
+/// one of the [`IMAGE_EXTENSIONS`], like logs some run.py versions leave behind, are
+/// never picked. Files modified at the same instant, common on filesystems with coarse
+/// timestamps, go to the name that sorts last, so the pick doesn't depend on directory
+/// order. Times compare at the full precision the platform records, nanoseconds on
+/// most Unix filesystems.
+fn pick_latest_file(
+    dir_path: &Path,
+    accept: impl Fn(&Path) -> bool,
+) -> Result<Option<PathBuf>, String> {
     if !dir_path.is_dir() {
         return Ok(None);
     }
@@ -105,7 +1195,7 @@ fn pick_latest_file(dir_path: &Path) -> Result<Option<PathBuf>, String> {
             .and_then(|s| s.to_str())
             .unwrap_or_default()
             .to_string();
-        if name.starts_with('.') {
+        if name.starts_with('.') || !has_image_extension(&p) || !accept(&p) {
             continue;
         }
         let modified = entry
@@ -113,199 +1203,7295 @@ fn pick_latest_file(dir_path: &Path) -> Result<Option<PathBuf>, String> {
             .and_then(|m| m.modified())
             .map_err(|e| format!("Failed to stat file {name}: {e}"))?;
 
-        match &latest {
-            None => latest = Some((modified, p)),
-            Some((cur, _)) => {
-                if modified > *cur {
-                    latest = Some((modified, p));
-                }
-            }
+        if latest
+            .as_ref()
+            .is_none_or(|(cur, cur_path)| (modified, &p) > (*cur, cur_path))
+        {
+            latest = Some((modified, p));
         }
     }
 
     Ok(latest.map(|(_, p)| p))
 }
 
-#[tauri::command]
-async fn modify_photo(app: tauri::AppHandle, args: ModifyPhotoArgs) -> Result<ModifyPhotoResult, String> {
-    tauri::async_runtime::spawn_blocking(move || {
-        let app = app;
-        let run_id = args.run_id.clone();
-
-        emit_progress(
-            &app,
-            ProgressEvent {
-                run_id: run_id.clone(),
-                stage: Some(0),
-                message: "Starting...".to_string(),
-                is_error: false,
-            },
-        );
+const STAGE_DIRS: [&str; 4] = [
+    "stage_1_restore_output",
+    "stage_2_detection_output",
+    "stage_3_face_output",
+    "final_output",
+];
 
-        let root = project_root()?;
+/// Where each stage leaves one image per item it processed: restored photos, detected
+/// face crops, enhanced faces and final results.
+const STAGE_RESULT_DIRS: [(u8, &str); 4] = [
+    (1, "stage_1_restore_output/restored_image"),
+    (2, "stage_2_detection_output"),
+    (3, "stage_3_face_output/each_img"),
+    (4, "final_output"),
+];
 
-        let output_folder = match args.output_folder {
-            Some(of) if !of.trim().is_empty() => {
-                let p = PathBuf::from(of);
-                if p.is_absolute() {
-                    p
-                } else {
-                    root.join(p)
-                }
-            }
-            _ => root.join("output_gui"),
-        };
-        fs::create_dir_all(&output_folder)
-            .map_err(|e| format!("Failed to create output folder: {e}"))?;
+/// The stages run.py is expected to go through and the folders they write: the four
+/// of [`STAGE_DIRS`], or a [`ModifyPhotoArgs::pipeline`]. The app's own stage 0
+/// (startup) and 4 (finishing up) events don't depend on it.
+#[derive(Debug, Clone, PartialEq)]
+struct StageLayout {
+    stages: Vec<StageSpec>,
+    /// `(stage, folder)` holding one image per item that stage processed, in order.
+    result_dirs: Vec<(u8, String)>,
+}
 
-        let input_path = PathBuf::from(args.input_path);
-        if !input_path.exists() {
-            return Err(format!("Input not found: {}", input_path.display()));
+impl Default for StageLayout {
+    fn default() -> Self {
+        Self {
+            stages: (1..)
+                .zip(STAGE_DIRS)
+                .map(|(stage, dir)| StageSpec {
+                    stage,
+                    output_dir: dir.to_string(),
+                    is_final: stage == 4,
+                })
+                .collect(),
+            result_dirs: STAGE_RESULT_DIRS
+                .iter()
+                .map(|&(stage, dir)| (stage, dir.to_string()))
+                .collect(),
         }
+    }
+}
 
-        let input_folder = if input_path.is_dir() {
-            input_path
-        } else {
-            ensure_single_image_folder(&input_path, &output_folder)?
+impl StageLayout {
+    fn from_args(args: &ModifyPhotoArgs) -> Result<Self, String> {
+        let Some(stages) = &args.pipeline else {
+            return Ok(Self::default());
         };
-
-        let stage_dirs = [
-            output_folder.join("stage_1_restore_output"),
-            output_folder.join("stage_2_detection_output"),
-            output_folder.join("stage_3_face_output"),
-            output_folder.join("final_output"),
-        ];
-        for dir in stage_dirs {
-            if dir.exists() {
-                fs::remove_dir_all(&dir)
-                    .map_err(|e| format!("Failed to clear {}: {e}", dir.display()))?;
-            }
-            fs::create_dir_all(&dir)
-                .map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+        let invalid = |why: String| Err(format!("Invalid pipeline: {why}"));
+        if stages.is_empty() {
+            return invalid("it has no stages".to_string());
         }
-
-        let final_dir = output_folder.join("final_output");
-
-        let run_py = root.join("run.py");
-        if !run_py.exists() {
-            return Err(format!("run.py not found: {}", run_py.display()));
-        }
-
-        let mut cmd = Command::new(&args.python);
-        cmd.current_dir(&root);
-        cmd.env("PYTHONUNBUFFERED", "1");
-        cmd.arg("-u");
-        cmd.arg(run_py);
-        cmd.arg("--input_folder").arg(input_folder);
-        cmd.arg("--output_folder").arg(&output_folder);
-        cmd.arg("--GPU").arg(args.gpu);
-        if args.with_scratch {
-            cmd.arg("--with_scratch");
+        if stages.iter().filter(|s| s.is_final).count() != 1 {
+            return invalid("exactly one stage must be final".to_string());
         }
-        if args.hr {
-            cmd.arg("--HR");
+        for (i, spec) in stages.iter().enumerate() {
+            if spec.stage == 0 {
+                return invalid("stages are numbered from 1".to_string());
+            }
+            let dir = Path::new(spec.output_dir.trim());
+            let inside = dir
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)));
+            if spec.output_dir.trim().is_empty() || !inside {
+                return invalid(format!(
+                    "stage {} output_dir must be a folder inside the output folder",
+                    spec.stage
+                ));
+            }
+            if stages[..i].iter().any(|s| s.stage == spec.stage) {
+                return invalid(format!("stage {} is listed twice", spec.stage));
+            }
+            if stages[..i]
+                .iter()
+                .any(|s| s.output_dir.trim() == spec.output_dir.trim())
+            {
+                return invalid(format!("{} is used by two stages", spec.output_dir));
+            }
         }
+        let stages: Vec<StageSpec> = stages
+            .iter()
+            .map(|s| StageSpec {
+                output_dir: s.output_dir.trim().to_string(),
+                ..s.clone()
+            })
+            .collect();
+        let result_dirs = stages
+            .iter()
+            .map(|s| (s.stage, s.output_dir.clone()))
+            .collect();
+        Ok(Self {
+            stages,
+            result_dirs,
+        })
+    }
 
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+    fn numbers(&self) -> Vec<u8> {
+        self.stages.iter().map(|s| s.stage).collect()
+    }
 
-        let mut child = cmd.spawn().map_err(|e| format!("Failed to start python: {e}"))?;
-        let stdout = child
-            .stdout
-            .take()
-            .ok_or_else(|| "Failed to capture python stdout".to_string())?;
-        let stderr = child
-            .stderr
-            .take()
-            .ok_or_else(|| "Failed to capture python stderr".to_string())?;
+    fn dirs(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|s| s.output_dir.as_str())
+    }
 
-        let (tx, rx) = mpsc::channel::<(bool, String)>();
-        let tx_out = tx.clone();
-        let tx_err = tx.clone();
+    fn final_dir(&self) -> &str {
+        self.stages
+            .iter()
+            .find(|s| s.is_final)
+            .map_or(STAGE_DIRS[3], |s| &s.output_dir)
+    }
+
+    /// The folders other than [`StageLayout::final_dir`].
+    fn intermediate_dirs(&self) -> impl Iterator<Item = &str> {
+        self.stages
+            .iter()
+            .filter(|s| !s.is_final)
+            .map(|s| s.output_dir.as_str())
+    }
+}
+
+/// Number of images each stage wrote under `output_folder`. A missing folder counts
+/// as 0.
+fn count_stage_outputs(output_folder: &Path, layout: &StageLayout) -> HashMap<u8, usize> {
+    layout
+        .result_dirs
+        .iter()
+        .map(|(stage, dir)| (*stage, count_images(&output_folder.join(dir))))
+        .collect()
+}
+
+/// Images directly in `dir`; 0 if it can't be read.
+fn count_images(dir: &Path) -> usize {
+    list_input_files(dir)
+        .map(|files| files.iter().filter(|f| has_image_extension(f)).count())
+        .unwrap_or(0)
+}
+
+/// Scratch folder used to run one file at a time when `isolate_files` is set, or one
+/// chunk at a time with `chunk_size`.
+const ISOLATED_RUN_DIR: &str = "_gui_batch";
+/// Where [`restore_from_zip`] extracts archive entries.
+const ZIP_STAGING_DIR: &str = "_gui_zip";
+
+/// Where a URL input is downloaded to for the length of its run.
+const DOWNLOAD_DIR: &str = "_gui_download";
+
+/// Deletes the stage folders but the final one, and `_gui_input`, from `output_folder`.
+fn remove_intermediates(output_folder: &Path, layout: &StageLayout) -> Result<(), String> {
+    let intermediates = layout
+        .intermediate_dirs()
+        .chain(TEMP_DIR_NAMES[..1].iter().copied());
+    for name in intermediates {
+        let dir = output_folder.join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)
+                .map_err(|e| format!("Failed to remove {}: {e}", dir.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn reset_stage_dirs(output_folder: &Path, layout: &StageLayout) -> Result<(), String> {
+    reset_stage_dirs_reporting(output_folder, layout, |_| {})
+}
+
+/// [`reset_stage_dirs`], calling `on_clear` with each existing stage folder's name
+/// before it is removed, since removing a large previous output can take a while.
+fn reset_stage_dirs_reporting(
+    output_folder: &Path,
+    layout: &StageLayout,
+    on_clear: impl Fn(&str),
+) -> Result<(), String> {
+    for name in layout.dirs() {
+        let dir = output_folder.join(name);
+        if dir.exists() {
+            on_clear(name);
+            fs::remove_dir_all(&dir)
+                .map_err(|e| format!("Failed to clear {}: {e}", dir.display()))?;
+        }
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    }
+    Ok(())
+}
+
+fn ensure_stage_dirs(output_folder: &Path, layout: &StageLayout) -> Result<(), String> {
+    for name in layout.dirs() {
+        let dir = output_folder.join(name);
+        fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Non-hidden files directly inside `dir_path`, sorted by name.
+fn list_input_files(dir_path: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir_path).map_err(|e| format!("Failed to read dir: {e}"))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read dir entry: {e}"))?;
+        let p = entry.path();
+        let hidden = p
+            .file_name()
+            .and_then(|s| s.to_str())
+            .is_some_and(|name| name.starts_with('.'));
+        if p.is_file() && !hidden {
+            files.push(p);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn copy_dir_files(src: &Path, dst: &Path) -> Result<(), String> {
+    fs::create_dir_all(dst).map_err(|e| format!("Failed to create {}: {e}", dst.display()))?;
+    for file in list_input_files(src)? {
+        let Some(name) = file.file_name() else {
+            continue;
+        };
+        let dst = dst.join(name);
+        copy_retrying_locked(&file, || fs::copy(&file, &dst), is_file_locked)?;
+    }
+    Ok(())
+}
+
+/// The face detection flags requested in `args`, range-checked, as `(flag, value)`.
+fn face_detection_flags(args: &ModifyPhotoArgs) -> Result<Vec<(&'static str, String)>, String> {
+    let mut flags = Vec::new();
+    if let Some(upsample) = args.face_upsample {
+        if upsample > 3 {
+            return Err(format!("face_upsample must be 0-3, got {upsample}"));
+        }
+        flags.push(("--face_upsample", upsample.to_string()));
+    }
+    if let Some(threshold) = args.face_threshold {
+        if !(-1.0..=1.0).contains(&threshold) {
+            return Err(format!(
+                "face_threshold must be between -1.0 and 1.0, got {threshold}"
+            ));
+        }
+        flags.push(("--face_threshold", threshold.to_string()));
+    }
+    if let Some(max_faces) = args.max_faces {
+        if max_faces == 0 {
+            return Err("max_faces must be at least 1".to_string());
+        }
+        flags.push(("--max_faces", max_faces.to_string()));
+    }
+    Ok(flags)
+}
+
+/// Extra run.py arguments for the optional settings in `args`. Flags the installed
+/// run.py doesn't accept are dropped with a warning, since argparse would reject them.
+fn optional_pipeline_args(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<Vec<String>, String> {
+    let flags = face_detection_flags(args)?;
+    if flags.is_empty() {
+        return Ok(Vec::new());
+    }
+    let supported = options::supported(root, &args.python)?;
+    let mut extra = Vec::new();
+    for (flag, value) in flags {
+        if supported.iter().any(|s| s == flag) {
+            extra.push(flag.to_string());
+            extra.push(value);
+        } else {
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message: format!("run.py does not support {flag}; ignoring it"),
+                is_error: true,
+                ..Default::default()
+            });
+        }
+    }
+    Ok(extra)
+}
+
+/// Checks that `python` names a runnable interpreter before anything is spawned. The
+/// errors quote the exact path tried, so a typo in a path with spaces or non-ASCII
+/// characters is easy to spot. Bare names like `python3` are looked up on `PATH`.
+fn check_interpreter(python: &str) -> Result<(), String> {
+    if python.trim().is_empty() {
+        return Err("No python interpreter set".to_string());
+    }
+    let path = Path::new(python);
+    if path.components().count() == 1 {
+        let exe_names = [python.to_string(), format!("{python}.exe")];
+        let on_path = std::env::var_os("PATH").is_some_and(|paths| {
+            std::env::split_paths(&paths)
+                .any(|dir| exe_names.iter().any(|name| is_executable(&dir.join(name))))
+        });
+        return if on_path {
+            Ok(())
+        } else {
+            Err(format!(
+                "Python interpreter \"{python}\" was not found on PATH"
+            ))
+        };
+    }
+    if !path.is_file() {
+        return Err(format!("Python interpreter not found: \"{python}\""));
+    }
+    if !is_executable(path) {
+        return Err(format!(
+            "Python interpreter is not executable: \"{python}\""
+        ));
+    }
+    Ok(())
+}
+
+fn is_executable(path: &Path) -> bool {
+    let Ok(meta) = fs::metadata(path) else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.is_file() && meta.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    {
+        meta.is_file()
+    }
+}
+
+/// Where run.py is started: `args.working_dir` if set, otherwise `root`.
+fn working_dir(root: &Path, args: &ModifyPhotoArgs) -> PathBuf {
+    match args.working_dir.as_deref().map(str::trim) {
+        Some(dir) if !dir.is_empty() => root.join(dir),
+        _ => root.to_path_buf(),
+    }
+}
+
+/// Rejects `env` entries no OS accepts, and a `conda_prefix` that isn't a folder.
+fn check_child_env(args: &ModifyPhotoArgs) -> Result<(), String> {
+    for (key, value) in args.env.iter().flatten() {
+        if key.is_empty() || key.contains(['=', '\0']) || value.contains('\0') {
+            return Err(format!("Invalid environment variable: {key:?}"));
+        }
+    }
+    if let Some(prefix) = args.conda_prefix.as_deref().map(user_path) {
+        if !prefix.is_dir() {
+            return Err(format!("Conda prefix not found: {}", prefix.display()));
+        }
+    }
+    Ok(())
+}
+
+fn pipeline_command(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    gpu: &GpuSelection,
+    extra_args: &[String],
+    input_folder: &Path,
+    output_folder: &Path,
+) -> Command {
+    let mut cmd = Command::new(&args.python);
+    cmd.current_dir(working_dir(root, args));
+    // Own process group, so cancelling can kill the stage scripts run.py starts too.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+    cmd.arg("-u");
+    cmd.arg(root.join("run.py"));
+    cmd.arg("--input_folder").arg(input_folder);
+    cmd.arg("--output_folder").arg(output_folder);
+    if let Some(gpu_arg) = gpu.to_arg() {
+        cmd.arg("--GPU").arg(gpu_arg);
+    }
+    if *gpu == GpuSelection::Mps {
+        cmd.env("PYTORCH_ENABLE_MPS_FALLBACK", "1");
+    }
+    if let Some(threads) = args.cpu_threads {
+        cmd.env("OMP_NUM_THREADS", threads.to_string());
+        cmd.env("MKL_NUM_THREADS", threads.to_string());
+    }
+    if let Some(env) = &args.env {
+        cmd.envs(env);
+    }
+    if let Some(prefix) = args.conda_prefix.as_deref().map(user_path) {
+        let bin = prefix.join(if cfg!(windows) { "Scripts" } else { "bin" });
+        let path = args
+            .env
+            .as_ref()
+            .and_then(|env| env.get("PATH"))
+            .map(OsString::from)
+            .or_else(|| std::env::var_os("PATH"))
+            .unwrap_or_default();
+        let paths = std::iter::once(bin).chain(std::env::split_paths(&path));
+        if let Ok(joined) = std::env::join_paths(paths) {
+            cmd.env("PATH", joined);
+        }
+    }
+    cmd.env("PYTHONUNBUFFERED", "1");
+    if args.with_scratch {
+        cmd.arg("--with_scratch");
+    }
+    if args.hr {
+        cmd.arg("--HR");
+    }
+    cmd.args(extra_args);
+    cmd
+}
+
+/// Quotes `arg` for a POSIX shell, or for `cmd.exe` on Windows, leaving plain words bare.
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else if cfg!(windows) {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// The command a run with `args` would start, resolved the way [`restore`] resolves it
+/// but without staging inputs or touching the output folder. A URL input isn't
+/// downloaded; the command reads it from where it would be staged.
+fn dry_run_command(root: &Path, args: &ModifyPhotoArgs) -> Result<PipelineCommandLine, String> {
+    let output_folder = resolve_output_folder(root, args);
+    check_output_folder(&output_folder)?;
+    let input_path = user_path(&args.input_path);
+    let staged = fetch::is_url(&args.input_path) || {
+        if !input_path.exists() {
+            return Err(format!("Input not found: {}", input_path.display()));
+        }
+        !input_path.is_dir()
+            || args.recursive
+            || NameFilter::from_args(args)?.is_active()
+            || folder_inputs(&input_path, args)?
+                .iter()
+                .any(|f| sniffed_name(f).is_some())
+    };
+    let input_folder = if staged {
+        output_folder.join("_gui_input")
+    } else {
+        input_path
+    };
+    check_interpreter(&args.python)?;
+    check_child_env(args)?;
+    let mut gpu = GpuSelection::parse(&args.gpu)?;
+    if gpu == GpuSelection::Auto {
+        gpu = gpu::auto_select(&args.python).0;
+    }
+    let extra_args = optional_pipeline_args(root, args, &|_| {})?;
+    let cmd = pipeline_command(root, args, &gpu, &extra_args, &input_folder, &output_folder);
+
+    let argv: Vec<String> = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|a| a.to_string_lossy().to_string())
+        .collect();
+    let env = cmd
+        .get_envs()
+        .filter_map(|(k, v)| {
+            Some((
+                k.to_string_lossy().to_string(),
+                v?.to_string_lossy().to_string(),
+            ))
+        })
+        .collect();
+    Ok(PipelineCommandLine {
+        shell: argv
+            .iter()
+            .map(|a| shell_quote(a))
+            .collect::<Vec<_>>()
+            .join(" "),
+        argv,
+        working_dir: working_dir(root, args).to_string_lossy().to_string(),
+        env,
+    })
+}
+
+/// Written to run.py's stdin right after spawn. A run.py that understands it answers
+/// with lines prefixed by [`PROGRESS_JSON_PREFIX`]; any other run.py ignores stdin.
+const PROGRESS_HANDSHAKE: &str = "bopbtl-progress: json v1\n";
+const PROGRESS_JSON_PREFIX: &str = "@progress ";
+/// How long to keep stdin open waiting for the first JSON progress line before
+/// settling on plain text parsing.
+const PROGRESS_HANDSHAKE_GRACE: Duration = Duration::from_secs(5);
+
+const LOADING_MODELS_MESSAGE: &str = "Loading models...";
+const LOADING_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A progress line sent by a run.py that accepted the stdin handshake.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StructuredProgress {
+    stage: Option<u8>,
+    message: Option<String>,
+    #[serde(default)]
+    is_error: bool,
+}
+
+fn parse_structured_progress(line: &str) -> Option<StructuredProgress> {
+    let payload = line.strip_prefix(PROGRESS_JSON_PREFIX)?;
+    serde_json::from_str(payload).ok()
+}
+
+/// What has been learned from one run.py process's output so far.
+#[derive(Debug, Default)]
+struct OutputTracker {
+    stage: Option<u8>,
+    /// Which banners count and where previews come from.
+    layout: StageLayout,
+    /// Set once run.py answered the stdin handshake with a JSON progress line.
+    structured: bool,
+    missing_module: Option<Diagnostic>,
+    /// The image run.py last said it was working on.
+    current_file: Option<String>,
+    /// The first sign of a failed image, with the file it concerned when known.
+    first_error: Option<(Option<String>, String)>,
+    report: ProcessReport,
+    /// The device run.py was asked to use; a different one in its output gets a warning.
+    expected_device: Option<String>,
+    /// run.py's `CUDA_VISIBLE_DEVICES`, named in that warning since it renumbers GPUs.
+    cuda_visible_devices: Option<String>,
+    /// The device run.py last said it is running on.
+    device: Option<String>,
+    /// Set after the face script's "The main GPU is" line, whose index follows on the
+    /// next line.
+    awaiting_main_gpu: bool,
+    /// The last [`STDERR_TAIL_LINES`] lines of stderr, starting at the latest traceback
+    /// once one was printed.
+    stderr_tail: VecDeque<String>,
+    /// Whether `stderr_tail` starts with a traceback header.
+    in_traceback: bool,
+    /// The output folder whose stage results are announced as previews.
+    preview_dir: Option<PathBuf>,
+    /// Stages a preview was already sent for.
+    previewed: HashSet<u8>,
+    /// Images in the input folder, when it is one; enables `file_index` on events.
+    file_total: Option<usize>,
+    /// Images the current stage said it is processing, in order.
+    stage_files: Vec<String>,
+}
+
+/// Whether a failed run's error, which ends in its stderr, shows CUDA running out of
+/// memory.
+fn is_cuda_oom(error: &str) -> bool {
+    let error = error.to_ascii_lowercase();
+    error.contains("cuda out of memory")
+        || error.contains("cuda error: out of memory")
+        || error.contains("outofmemoryerror")
+}
+
+/// How much of stderr a failed run's error carries.
+const STDERR_TAIL_LINES: usize = 30;
+
+/// What run.py reported about individual images besides failures, in the order
+/// reported.
+#[derive(Debug, Default)]
+struct ProcessReport {
+    /// Images the face detector found no faces in.
+    no_faces: Vec<String>,
+    /// Images run.py left alone because they already had outputs.
+    skipped: Vec<String>,
+}
+
+impl ProcessReport {
+    fn extend(&mut self, other: ProcessReport) {
+        self.no_faces.extend(other.no_faces);
+        self.skipped.extend(other.skipped);
+    }
+}
+
+/// The file named by run.py's "Now you are processing X" / "processing X" lines.
+fn processing_file_from_line(line: &str) -> Option<&str> {
+    let line = line.trim();
+    line.strip_prefix("Now you are processing ")
+        .or_else(|| line.strip_prefix("processing "))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+/// The file named by the face scripts' "Warning: There is no face in X" line.
+fn no_face_file_from_line(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("Warning: There is no face in ")
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+/// The file named by a "skipping, already processed" line, as printed by run.py
+/// variants that skip images with existing outputs, e.g. `Skipping a.png: already
+/// processed` or `a.png already exists, skipping`.
+fn skipped_file_from_line(line: &str) -> Option<&str> {
+    let lower = line.to_ascii_lowercase();
+    if !lower.contains("already") || !lower.contains("skip") {
+        return None;
+    }
+    line.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| ",:;()'\"".contains(c)))
+        .find(|word| has_image_extension(Path::new(word)))
+}
+
+/// The device in the scripts' "running on GPU 0" / "running on CPU" lines, as `cuda:0`
+/// or `cpu`. A list such as `[0, 1]` reports its first, main device.
+fn device_from_line(line: &str) -> Option<String> {
+    let rest = line.trim().strip_prefix("running on ")?;
+    if rest == "CPU" {
+        return Some("cpu".to_string());
+    }
+    let index: String = rest
+        .strip_prefix("GPU ")?
+        .trim_start_matches(['[', ' '])
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    (!index.is_empty()).then(|| format!("cuda:{index}"))
+}
+
+/// Recognizes output that means an image failed: the per-image
+/// "Skip X due to an error" lines and Python tracebacks. Returns the file when the line
+/// names one.
+fn error_from_line(line: &str) -> Option<Option<&str>> {
+    let line = line.trim();
+    if let Some(rest) = line.strip_prefix("Skip ") {
+        if let Some(end) = rest.find(" due to an error") {
+            return Some(Some(rest[..end].trim()));
+        }
+    }
+    if line.starts_with("Traceback (most recent call last)") {
+        return Some(None);
+    }
+    None
+}
+
+impl OutputTracker {
+    fn new() -> Self {
+        Self {
+            stage: Some(0),
+            ..Default::default()
+        }
+    }
+
+    fn keep_stderr(&mut self, line: &str) {
+        if line
+            .trim_start()
+            .starts_with("Traceback (most recent call last)")
+        {
+            self.stderr_tail.clear();
+            self.in_traceback = true;
+        }
+        self.stderr_tail.push_back(line.trim_end().to_string());
+        if self.stderr_tail.len() > STDERR_TAIL_LINES {
+            // Keep the header; the frames right below it matter least.
+            let oldest = usize::from(self.in_traceback);
+            self.stderr_tail.remove(oldest);
+        }
+    }
+
+    /// Sends a preview of stage `stage - 1`'s newest result when run.py moves on to
+    /// `stage`, unless there is none or one was already sent.
+    fn preview_finished_stage(&mut self, stage: u8, run_id: &str, emit: &dyn Fn(ProgressEvent)) {
+        let Some(dir) = &self.preview_dir else {
+            return;
+        };
+        let Some((finished, result_dir)) = self
+            .layout
+            .result_dirs
+            .windows(2)
+            .find(|pair| pair[1].0 == stage)
+            .map(|pair| pair[0].clone())
+        else {
+            return;
+        };
+        if self.previewed.contains(&finished) {
+            return;
+        }
+        if let Ok(Some(image)) = pick_latest_file(&dir.join(result_dir), |_| true) {
+            self.previewed.insert(finished);
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: self.stage,
+                preview: Some(PreviewEvent {
+                    run_id: run_id.to_string(),
+                    stage: finished,
+                    image_path: image.to_string_lossy().to_string(),
+                }),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// The kept stderr lines as one block, for the error of a failed run.
+    fn stderr_summary(&self) -> Option<String> {
+        (!self.stderr_tail.is_empty()).then(|| Vec::from(self.stderr_tail.clone()).join("\n"))
+    }
+
+    fn handle_line(
+        &mut self,
+        run_id: &str,
+        is_error: bool,
+        line: String,
+        in_place: bool,
+        emit: &dyn Fn(ProgressEvent),
+    ) {
+        runlog::write(run_id, is_error, &line);
+        if let Some(progress) = parse_structured_progress(&line) {
+            self.structured = true;
+            if progress.stage.is_some() {
+                self.stage = progress.stage;
+            }
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: self.stage,
+                message: progress.message.unwrap_or_default(),
+                is_error: progress.is_error,
+                ..Default::default()
+            });
+            if let Some(stage) = progress.stage {
+                self.preview_finished_stage(stage, run_id, emit);
+            }
+            return;
+        }
+
+        if is_error {
+            self.keep_stderr(&line);
+        }
+        let new_stage = stage_from_line(&line, &self.layout.numbers());
+        if let Some(s) = new_stage {
+            if self.stage != Some(s) {
+                self.stage_files.clear();
+            }
+            self.stage = Some(s);
+        }
+        if let Some(name) = processing_file_from_line(&line) {
+            self.current_file = Some(name.to_string());
+            if !self.stage_files.iter().any(|n| n == name) {
+                self.stage_files.push(name.to_string());
+            }
+        }
+        let (file_index, file_total) = match self.file_total {
+            Some(total) if !self.stage_files.is_empty() => {
+                (Some(self.stage_files.len().min(total)), Some(total))
+            }
+            _ => (None, None),
+        };
+        if self.first_error.is_none() {
+            if let Some(file) = error_from_line(&line) {
+                let file = file
+                    .map(str::to_string)
+                    .or_else(|| self.current_file.clone());
+                self.first_error = Some((file, line.trim().to_string()));
+            }
+        }
+        let diagnostic = missing_module_from_line(&line);
+        // Both the detection and the warp-back script warn, so report each file once.
+        let no_face = no_face_file_from_line(&line)
+            .filter(|name| !self.report.no_faces.iter().any(|n| n == name))
+            .map(str::to_string);
+        let skipped = skipped_file_from_line(&line).map(str::to_string);
+        let device = if std::mem::take(&mut self.awaiting_main_gpu) {
+            let index = line.trim();
+            (!index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+                .then(|| format!("cuda:{index}"))
+        } else {
+            self.awaiting_main_gpu = line.trim() == "The main GPU is";
+            device_from_line(&line)
+        };
+        let stage_percent = stage_percent_from_line(&line);
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: self.stage,
+            message: line,
+            is_error,
+            in_place,
+            stage_percent,
+            file_index,
+            file_total,
+            ..Default::default()
+        });
+        if let Some(stage) = new_stage {
+            self.preview_finished_stage(stage, run_id, emit);
+        }
+        if let Some(name) = no_face {
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: self.stage,
+                message: format!(
+                    "No faces detected in {name} \u{2014} face enhancement skipped for this image"
+                ),
+                ..Default::default()
+            });
+            self.report.no_faces.push(name);
+        }
+        if let Some(name) = skipped {
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: self.stage,
+                message: format!("Skipped {name}: already processed"),
+                skipped: true,
+                ..Default::default()
+            });
+            self.report.skipped.push(name);
+        }
+        if let Some(device) = device.filter(|d| self.device.as_ref() != Some(d)) {
+            self.device = Some(device.clone());
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: self.stage,
+                message: format!("Pipeline is running on {device}"),
+                device_selected: Some(device.clone()),
+                ..Default::default()
+            });
+            if let Some(expected) = self.expected_device.as_ref().filter(|e| **e != device) {
+                let mut message =
+                    format!("Warning: run.py is using {device}, but {expected} was requested");
+                if let Some(visible) = &self.cuda_visible_devices {
+                    message.push_str(&format!(
+                        " (CUDA_VISIBLE_DEVICES={visible} renumbers the GPUs it can see)"
+                    ));
+                }
+                emit(ProgressEvent {
+                    run_id: run_id.to_string(),
+                    stage: self.stage,
+                    message,
+                    is_error: true,
+                    ..Default::default()
+                });
+            }
+        }
+        if let Some(diagnostic) = diagnostic {
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: self.stage,
+                message: format!(
+                    "{}; install it with `{}`",
+                    diagnostic.detail, diagnostic.hint
+                ),
+                is_error: true,
+                diagnostic: Some(diagnostic.clone()),
+                ..Default::default()
+            });
+            self.missing_module.get_or_insert(diagnostic);
+        }
+    }
+}
+
+/// How [`run_pipeline_process`] reacts to what run.py prints.
+#[derive(Debug, Clone)]
+struct ProcessOptions {
+    stop_on_error: bool,
+    monitor_proc: bool,
+    /// How long the event loop waits for output before checking whether run.py has
+    /// exited, see [`DEFAULT_POLL_INTERVAL`].
+    poll_interval: Duration,
+    cancel: Option<Arc<cancel::CancelToken>>,
+    /// Held for the lifetime of the process when set, see [`gpu::GpuSemaphore`].
+    gpu_slot: Option<&'static gpu::GpuSemaphore>,
+    watch_outputs: Option<OutputWatch>,
+    /// The device run.py should report binding to, see [`GpuSelection::expected_device`].
+    expected_device: Option<String>,
+    /// From `args.env`, else inherited.
+    cuda_visible_devices: Option<String>,
+    /// Output folder to send stage previews from, see [`PreviewEvent`].
+    preview_dir: Option<PathBuf>,
+    /// Longest run.py may go without printing a line before it is killed.
+    stall_timeout: Option<Duration>,
+    /// Images in the input folder, for `file_index` progress, see [`ProgressEvent`].
+    file_total: Option<usize>,
+    layout: StageLayout,
+}
+
+/// Estimates batch progress from the images appearing in a folder.
+#[derive(Debug, Clone)]
+struct OutputWatch {
+    dir: PathBuf,
+    /// Images already there when the run started, e.g. kept from earlier runs.
+    baseline: usize,
+    total: usize,
+}
+
+/// How often [`OutputWatch`] recounts its folder.
+const OUTPUT_WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl OutputWatch {
+    fn new(dir: PathBuf, total: usize) -> Self {
+        let baseline = count_images(&dir);
+        Self {
+            dir,
+            baseline,
+            total,
+        }
+    }
+
+    fn percent(&self) -> u8 {
+        let done = count_images(&self.dir).saturating_sub(self.baseline);
+        (done.min(self.total) * 100)
+            .checked_div(self.total)
+            .unwrap_or(0) as u8
+    }
+}
+
+/// Shorter intervals notice that run.py exited sooner but wake the loop more often
+/// while it is quiet; output itself is handled as soon as it arrives either way.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Lower bound for `poll_interval_ms`, so the loop never spins.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            stop_on_error: false,
+            monitor_proc: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            cancel: None,
+            gpu_slot: None,
+            watch_outputs: None,
+            expected_device: None,
+            cuda_visible_devices: None,
+            preview_dir: None,
+            stall_timeout: None,
+            file_total: None,
+            layout: StageLayout::default(),
+        }
+    }
+}
+
+impl ProcessOptions {
+    fn from_args(args: &ModifyPhotoArgs, gpu: &GpuSelection) -> Self {
+        Self {
+            stop_on_error: args.stop_on_error,
+            monitor_proc: args.monitor_proc,
+            poll_interval: args.poll_interval_ms.map_or(DEFAULT_POLL_INTERVAL, |ms| {
+                Duration::from_millis(ms).max(MIN_POLL_INTERVAL)
+            }),
+            cancel: cancel::token(&args.run_id),
+            gpu_slot: (args.serialize_gpu && *gpu != GpuSelection::Cpu).then_some(&gpu::GPU_SLOT),
+            watch_outputs: None,
+            expected_device: gpu.expected_device(),
+            cuda_visible_devices: args
+                .env
+                .as_ref()
+                .and_then(|env| env.get("CUDA_VISIBLE_DEVICES").cloned())
+                .or_else(|| std::env::var("CUDA_VISIBLE_DEVICES").ok()),
+            preview_dir: None,
+            stall_timeout: args.stall_timeout_secs.map(Duration::from_secs),
+            file_total: None,
+            // Checked before any process is started.
+            layout: StageLayout::from_args(args).unwrap_or_default(),
+        }
+    }
+}
+
+/// Longest line forwarded from run.py, in bytes. Anything beyond it is dropped so a
+/// runaway line can't grow without bound in memory or over IPC.
+const MAX_LINE_BYTES: usize = 16 * 1024;
+const TRUNCATED_MARKER: &str = "… [truncated]";
+
+/// Calls `f(line, in_place)` with each line of `reader`, like `BufRead::lines`, but
+/// never buffers more than [`MAX_LINE_BYTES`] of a line; longer lines end in
+/// [`TRUNCATED_MARKER`]. Invalid UTF-8 is replaced rather than ending the stream.
+/// Besides `\n` and `\r\n`, a lone `\r` ends a line too, with `in_place` set, so
+/// progress bars that redraw themselves come through as they update instead of as one
+/// huge line once they finish. Empty in-place lines are skipped.
+fn for_each_capped_line(mut reader: impl BufRead, mut f: impl FnMut(String, bool)) {
+    let mut line = Vec::new();
+    let mut truncated = false;
+    // The last read ended right after a `\r`; the next byte decides whether it was
+    // half of a `\r\n`.
+    let mut pending_cr = false;
+    let mut finish = |line: &mut Vec<u8>, truncated: &mut bool, in_place: bool| {
+        if in_place && line.is_empty() && !*truncated {
+            return;
+        }
+        let mut text = String::from_utf8_lossy(line).into_owned();
+        if *truncated {
+            text.push_str(TRUNCATED_MARKER);
+        }
+        line.clear();
+        *truncated = false;
+        f(text, in_place);
+    };
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        if buf.is_empty() {
+            if !line.is_empty() || truncated {
+                finish(&mut line, &mut truncated, pending_cr);
+            }
+            break;
+        }
+        if std::mem::take(&mut pending_cr) {
+            let crlf = buf[0] == b'\n';
+            if crlf {
+                reader.consume(1);
+            }
+            finish(&mut line, &mut truncated, !crlf);
+            continue;
+        }
+        let end = buf.iter().position(|&b| b == b'\n' || b == b'\r');
+        let chunk = &buf[..end.unwrap_or(buf.len())];
+        let room = MAX_LINE_BYTES.saturating_sub(line.len());
+        if chunk.len() > room {
+            line.extend_from_slice(&chunk[..room]);
+            truncated = true;
+        } else {
+            line.extend_from_slice(chunk);
+        }
+        // How much of `buf` was used, and whether the line ended in place.
+        let (used, ended) = match end {
+            None => (buf.len(), None),
+            Some(i) if buf[i] == b'\n' => (i + 1, Some(false)),
+            Some(i) => match buf.get(i + 1) {
+                Some(b'\n') => (i + 2, Some(false)),
+                Some(_) => (i + 1, Some(true)),
+                None => {
+                    pending_cr = true;
+                    (i + 1, None)
+                }
+            },
+        };
+        reader.consume(used);
+        if let Some(in_place) = ended {
+            finish(&mut line, &mut truncated, in_place);
+        }
+    }
+}
+
+const PROC_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Samples the CPU and memory use of process `pid` every [`PROC_SAMPLE_INTERVAL`]
+/// until the returned sender is dropped. Samples arrive on the returned receiver.
+fn spawn_proc_sampler(
+    pid: u32,
+) -> (
+    mpsc::Sender<()>,
+    mpsc::Receiver<ProcStats>,
+    thread::JoinHandle<()>,
+) {
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let pid = sysinfo::Pid::from_u32(pid);
+        let refresh = sysinfo::ProcessRefreshKind::nothing()
+            .with_cpu()
+            .with_memory();
+        let mut system = sysinfo::System::new();
+        // CPU usage is measured between two refreshes; prime the first one.
+        system.refresh_processes_specifics(sysinfo::ProcessesToUpdate::Some(&[pid]), true, refresh);
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(PROC_SAMPLE_INTERVAL)
+        {
+            system.refresh_processes_specifics(
+                sysinfo::ProcessesToUpdate::Some(&[pid]),
+                true,
+                refresh,
+            );
+            let Some(process) = system.process(pid) else {
+                break;
+            };
+            let stats = ProcStats {
+                cpu_percent: process.cpu_usage(),
+                rss_bytes: process.memory(),
+            };
+            if tx.send(stats).is_err() {
+                break;
+            }
+        }
+    });
+    (stop_tx, rx, handle)
+}
+
+/// Spawns run.py and streams its stdout/stderr as progress events until it exits.
+fn run_pipeline_process(
+    mut cmd: Command,
+    run_id: &str,
+    options: ProcessOptions,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<ProcessReport, ModifyError> {
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    let _gpu_permit = match options.gpu_slot {
+        Some(slot) => {
+            if slot.is_busy() {
+                emit(ProgressEvent {
+                    run_id: run_id.to_string(),
+                    stage: Some(0),
+                    message: "Waiting for another job to release the GPU...".to_string(),
+                    ..Default::default()
+                });
+            }
+            let cancelled = || options.cancel.as_ref().and_then(|c| c.message());
+            if let Some(token) = &options.cancel {
+                token.set_queued(true);
+            }
+            let permit = slot.acquire(cancelled);
+            if let Some(token) = &options.cancel {
+                token.set_queued(false);
+            }
+            Some(permit.inspect_err(|message| {
+                emit(ProgressEvent {
+                    run_id: run_id.to_string(),
+                    stage: Some(0),
+                    message: format!("{message} while waiting for the GPU; nothing was started"),
+                    is_error: true,
+                    ..Default::default()
+                });
+            })?)
+        }
+        None => None,
+    };
+
+    let argv = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|a| a.to_string_lossy().to_string())
+        .collect();
+    history::note_command(run_id, argv);
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| ModifyError::PythonSpawnFailed(format!("Failed to start python: {e}")))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| "Failed to capture python stdout".to_string())?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| "Failed to capture python stderr".to_string())?;
+
+    // A run.py that never reads stdin may exit before we write; that's fine.
+    let mut stdin = child.stdin.take();
+    if let Some(pipe) = stdin.as_mut() {
+        let _ = pipe
+            .write_all(PROGRESS_HANDSHAKE.as_bytes())
+            .and_then(|_| pipe.flush());
+    }
+    let spawned_at = Instant::now();
+    let mut last_heartbeat = spawned_at;
+    // run.py printing anything itself shows it is alive; the heartbeat stops then.
+    let mut printed = false;
+    let mut last_alive = spawned_at;
+    let mut last_watch = spawned_at;
+    let mut last_percent = 0;
+    emit(ProgressEvent {
+        run_id: run_id.to_string(),
+        stage: Some(0),
+        message: LOADING_MODELS_MESSAGE.to_string(),
+        ..Default::default()
+    });
+
+    let (tx, rx) = mpsc::channel::<(bool, String, bool)>();
+    let tx_out = tx.clone();
+    let tx_err = tx.clone();
+
+    let out_handle = thread::spawn(move || {
+        for_each_capped_line(BufReader::new(stdout), |line, in_place| {
+            let _ = tx_out.send((false, line, in_place));
+        });
+    });
+    let err_handle = thread::spawn(move || {
+        for_each_capped_line(BufReader::new(stderr), |line, in_place| {
+            let _ = tx_err.send((true, line, in_place));
+        });
+    });
+    drop(tx);
+
+    let sampler = options.monitor_proc.then(|| spawn_proc_sampler(child.id()));
+    let emit_samples = |samples: &mpsc::Receiver<ProcStats>, stage: Option<u8>| {
+        for stats in samples.try_iter() {
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage,
+                proc_stats: Some(stats),
+                ..Default::default()
+            });
+        }
+    };
+
+    let mut tracker = OutputTracker::new();
+    tracker.expected_device = options.expected_device.clone();
+    tracker.cuda_visible_devices = options.cuda_visible_devices.clone();
+    tracker.preview_dir = options.preview_dir.clone();
+    tracker.file_total = options.file_total;
+    tracker.layout = options.layout.clone();
+    // Set when the run has to be stopped early; holds the error to report.
+    let mut abort = None;
+    let mut last_line = Instant::now();
+    let status = loop {
+        if let Some(message) = options.cancel.as_ref().and_then(|c| c.message()) {
+            abort = Some(ModifyError::Cancelled(message));
+            break None;
+        }
+        if let Some((_, samples, _)) = &sampler {
+            emit_samples(samples, tracker.stage);
+        }
+        if !tracker.structured
+            && stdin.is_some()
+            && spawned_at.elapsed() >= PROGRESS_HANDSHAKE_GRACE
+        {
+            stdin = None;
+        }
+        // Importing torch and loading weights can take a while before "Running Stage 1";
+        // keep the UI visibly alive until then.
+        if tracker.stage == Some(0)
+            && !printed
+            && last_heartbeat.elapsed() >= LOADING_HEARTBEAT_INTERVAL
+        {
+            last_heartbeat = Instant::now();
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: Some(0),
+                message: format!(
+                    "{LOADING_MODELS_MESSAGE} ({}s)",
+                    spawned_at.elapsed().as_secs()
+                ),
+                ..Default::default()
+            });
+        }
+        if let Some(watch) = &options.watch_outputs {
+            if last_watch.elapsed() >= OUTPUT_WATCH_INTERVAL {
+                last_watch = Instant::now();
+                let percent = watch.percent();
+                if percent != last_percent {
+                    emit(ProgressEvent {
+                        run_id: run_id.to_string(),
+                        stage: tracker.stage,
+                        percent: Some(percent),
+                        ..Default::default()
+                    });
+                }
+                last_percent = percent;
+            }
+        }
+        if last_alive.elapsed() >= HEARTBEAT_INTERVAL {
+            last_alive = Instant::now();
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: tracker.stage,
+                elapsed_secs: Some(spawned_at.elapsed().as_secs()),
+                ..Default::default()
+            });
+        }
+        match rx.recv_timeout(options.poll_interval) {
+            Ok((is_error, line, in_place)) => {
+                last_line = Instant::now();
+                printed |= !is_error;
+                tracker.handle_line(run_id, is_error, line, in_place, emit);
+                if options.stop_on_error {
+                    if let Some((file, line)) = &tracker.first_error {
+                        abort = Some(ModifyError::StoppedOnError(match file {
+                            Some(file) => format!("Stopped on first error in {file}: {line}"),
+                            None => format!("Stopped on first error: {line}"),
+                        }));
+                        break None;
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if let Some(status) = child
+                    .try_wait()
+                    .map_err(|e| format!("Process error: {e}"))?
+                {
+                    break Some(status);
+                }
+                if let Some(limit) = options.stall_timeout.filter(|l| last_line.elapsed() >= *l) {
+                    abort = Some(ModifyError::Stalled(format!(
+                        "No output for {} seconds; process killed",
+                        limit.as_secs()
+                    )));
+                    break None;
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                drop(stdin.take());
+                break Some(child.wait().map_err(|e| format!("Process error: {e}"))?);
+            }
+        }
+    };
+    if abort.is_some() {
+        cancel::kill_process_tree(&mut child);
+        drop(stdin.take());
+    }
+
+    if let Some((stop, samples, handle)) = sampler {
+        drop(stop);
+        let _ = handle.join();
+        emit_samples(&samples, tracker.stage);
+    }
+
+    // The process can exit while its last lines are still in flight; drain them so
+    // nothing printed right before exit is lost.
+    let _ = out_handle.join();
+    let _ = err_handle.join();
+    for (is_error, line, in_place) in rx.try_iter() {
+        tracker.handle_line(run_id, is_error, line, in_place, emit);
+    }
+    history::note_exit(run_id, status.and_then(|s| s.code()));
+
+    if let Some(error) = abort {
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: tracker.stage,
+            message: error.to_string(),
+            is_error: true,
+            ..Default::default()
+        });
+        return Err(error);
+    }
+    let Some(status) = status else {
+        return Ok(tracker.report);
+    };
+    if !status.success() {
+        let mut message = format!("Python exited with status: {status}");
+        if let Some(diagnostic) = &tracker.missing_module {
+            message = format!(
+                "{message}. {}; install it with `{}`",
+                diagnostic.detail, diagnostic.hint
+            );
+        }
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: tracker.stage,
+            message: message.clone(),
+            is_error: true,
+            diagnostic: tracker.missing_module.clone(),
+            ..Default::default()
+        });
+        // The lines were already streamed one by one; the error gets them as a block.
+        if let Some(summary) = tracker.stderr_summary() {
+            message = format!("{message}\n{summary}");
+        }
+        return Err(ModifyError::PythonExited {
+            code: status.code(),
+            message,
+        });
+    }
+    Ok(tracker.report)
+}
+
+/// What a batch run produced besides its output images.
+#[derive(Debug, Default)]
+struct BatchOutcome {
+    /// `(file name, error)` per failed file.
+    failed: Vec<(String, ModifyError)>,
+    stage_counts: HashMap<u8, usize>,
+    report: ProcessReport,
+}
+
+/// Runs the pipeline once per file in `input_folder`, each in a fresh scratch output,
+/// and collects the results into `output_folder/final_output`. Stage counts are summed
+/// over all files. Errors only if every file failed.
+fn run_isolated_files(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    gpu: &GpuSelection,
+    extra_args: &[String],
+    input_folder: &Path,
+    output_folder: &Path,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<BatchOutcome, ModifyError> {
+    let files = list_input_files(input_folder)?;
+    if files.is_empty() {
+        return Err(format!("No input files found in {}", input_folder.display()).into());
+    }
+
+    let scratch = output_folder.join(ISOLATED_RUN_DIR);
+    let options = ProcessOptions::from_args(args, gpu);
+    let final_dir = output_folder.join(options.layout.final_dir());
+    let total = files.len();
+    let mut failed = Vec::new();
+    let mut stage_counts = HashMap::new();
+    let mut report = ProcessReport::default();
+    for (i, file) in files.iter().enumerate() {
+        if let Some(message) = options.cancel.as_ref().and_then(|c| c.message()) {
+            let _ = fs::remove_dir_all(&scratch);
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message: message.clone(),
+                is_error: true,
+                ..Default::default()
+            });
+            return Err(ModifyError::Cancelled(message));
+        }
+        let name = file
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        emit(ProgressEvent {
+            run_id: args.run_id.clone(),
+            stage: Some(0),
+            message: format!("File {}/{total}: starting {name}", i + 1),
+            is_error: false,
+            ..Default::default()
+        });
+
+        let result = reset_stage_dirs(&scratch, &options.layout)
+            .and_then(|_| ensure_single_image_folder(file, &scratch))
+            .map_err(ModifyError::from)
+            .and_then(|input| {
+                let cmd = pipeline_command(root, args, gpu, extra_args, &input, &scratch);
+                run_pipeline_process(cmd, &args.run_id, options.clone(), emit)
+            })
+            .and_then(|process| {
+                report.extend(process);
+                for (stage, count) in count_stage_outputs(&scratch, &options.layout) {
+                    *stage_counts.entry(stage).or_insert(0) += count;
+                }
+                Ok(copy_dir_files(
+                    &scratch.join(options.layout.final_dir()),
+                    &final_dir,
+                )?)
+            });
+
+        match result {
+            Ok(()) => emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(4),
+                message: format!("File {}/{total}: finished {name}", i + 1),
+                is_error: false,
+                ..Default::default()
+            }),
+            Err(e @ ModifyError::Cancelled(_)) => {
+                let _ = fs::remove_dir_all(&scratch);
+                return Err(e);
+            }
+            Err(e) => {
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(4),
+                    message: format!("File {}/{total}: failed {name}: {e}", i + 1),
+                    is_error: true,
+                    ..Default::default()
+                });
+                failed.push((name, e));
+                if args.stop_on_error {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(&scratch);
+
+    if args.stop_on_error {
+        if let Some((name, e)) = failed.first() {
+            return Err(ModifyError::StoppedOnError(format!(
+                "Stopped on first error in {name}: {e}"
+            )));
+        }
+    }
+
+    if failed.len() == total {
+        // Typed as the first failure, so e.g. a missing interpreter reads as one.
+        return Err(failed
+            .swap_remove(0)
+            .1
+            .map_message(|message| format!("All {total} files failed; first error: {message}")));
+    }
+    Ok(BatchOutcome {
+        failed,
+        stage_counts,
+        report,
+    })
+}
+
+/// Runs the pipeline on `input_folder` in chunks of `args.chunk_size` files, each in a fresh
+/// scratch output, copying every chunk's results into `output_folder/final_output` as
+/// soon as it finishes. A failed chunk stops the batch; earlier chunks' outputs stay.
+fn run_chunks(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    gpu: &GpuSelection,
+    extra_args: &[String],
+    input_folder: &Path,
+    output_folder: &Path,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<BatchOutcome, ModifyError> {
+    let chunk_size = args.chunk_size.unwrap_or(1);
+    if chunk_size == 0 {
+        return Err(ModifyError::Other(
+            "chunk_size must be at least 1".to_string(),
+        ));
+    }
+    let files = list_input_files(input_folder)?;
+    if files.is_empty() {
+        return Err(format!("No input files found in {}", input_folder.display()).into());
+    }
+
+    let scratch = output_folder.join(ISOLATED_RUN_DIR);
+    let options = ProcessOptions::from_args(args, gpu);
+    let final_dir = output_folder.join(options.layout.final_dir());
+    let total = files.len();
+    let chunks = total.div_ceil(chunk_size);
+    let mut done = 0;
+    let mut stage_counts = HashMap::new();
+    let mut report = ProcessReport::default();
+    for (i, chunk) in files.chunks(chunk_size).enumerate() {
+        let result = reset_stage_dirs(&scratch, &options.layout)
+            .and_then(|_| copy_to_input_folder(chunk, &scratch))
+            .map_err(ModifyError::from)
+            .and_then(|input| {
+                let cmd = pipeline_command(root, args, gpu, extra_args, &input, &scratch);
+                run_pipeline_process(cmd, &args.run_id, options.clone(), emit)
+            })
+            .and_then(|process| {
+                report.extend(process);
+                for (stage, count) in count_stage_outputs(&scratch, &options.layout) {
+                    *stage_counts.entry(stage).or_insert(0) += count;
+                }
+                Ok(copy_dir_files(
+                    &scratch.join(options.layout.final_dir()),
+                    &final_dir,
+                )?)
+            });
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&scratch);
+            if matches!(e, ModifyError::Cancelled(_)) {
+                return Err(e);
+            }
+            let message = format!(
+                "Chunk {}/{chunks} (files {}-{}) failed: {e}; the {i} completed chunk(s) are kept in {}",
+                i + 1,
+                done + 1,
+                done + chunk.len(),
+                final_dir.display()
+            );
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(4),
+                message: message.clone(),
+                is_error: true,
+                ..Default::default()
+            });
+            return Err(e.map_message(|_| message));
+        }
+
+        done += chunk.len();
+        emit(ProgressEvent {
+            run_id: args.run_id.clone(),
+            stage: Some(4),
+            message: format!("Chunk {}/{chunks} finished ({done}/{total} files)", i + 1),
+            is_error: false,
+            ..Default::default()
+        });
+    }
+    let _ = fs::remove_dir_all(&scratch);
+
+    Ok(BatchOutcome {
+        failed: Vec::new(),
+        stage_counts,
+        report,
+    })
+}
+
+/// Runs the pipeline on just the first file of `input_folder`, in the isolated-run
+/// scratch folder, to catch a broken environment before the whole batch starts. Its
+/// events are prefixed with "[test run]".
+fn run_test_image(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    gpu: &GpuSelection,
+    extra_args: &[String],
+    input_folder: &Path,
+    output_folder: &Path,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<(), ModifyError> {
+    let Some(first) = list_input_files(input_folder)?.into_iter().next() else {
+        return Ok(());
+    };
+    let name = first
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let test_emit = |mut event: ProgressEvent| {
+        event.message = format!("[test run] {}", event.message);
+        emit(event);
+    };
+    test_emit(ProgressEvent {
+        run_id: args.run_id.clone(),
+        stage: Some(0),
+        message: format!("Trying {name} before the full batch"),
+        ..Default::default()
+    });
+
+    let scratch = output_folder.join(ISOLATED_RUN_DIR);
+    let result = reset_stage_dirs(&scratch, &StageLayout::from_args(args)?)
+        .and_then(|_| ensure_single_image_folder(&first, &scratch))
+        .map_err(ModifyError::from)
+        .and_then(|input| {
+            let cmd = pipeline_command(root, args, gpu, extra_args, &input, &scratch);
+            run_pipeline_process(
+                cmd,
+                &args.run_id,
+                ProcessOptions::from_args(args, gpu),
+                &test_emit,
+            )
+        });
+    let _ = fs::remove_dir_all(&scratch);
+    result.map_err(|e| e.context(&format!("Test run on {name} failed")))?;
+
+    emit(ProgressEvent {
+        run_id: args.run_id.clone(),
+        stage: Some(0),
+        message: format!("Test run on {name} passed; starting the full batch"),
+        ..Default::default()
+    });
+    Ok(())
+}
+
+/// Scratch folders the GUI creates inside an output folder.
+const TEMP_DIR_NAMES: [&str; 4] = [
+    "_gui_input",
+    ISOLATED_RUN_DIR,
+    ZIP_STAGING_DIR,
+    DOWNLOAD_DIR,
+];
+
+/// Tracks which output folders are in use so cleanup never touches a live run.
+#[derive(Default)]
+struct RunRegistry {
+    /// run_id -> output folder of every run currently in flight.
+    active: Mutex<HashMap<String, PathBuf>>,
+    /// Every output folder used since the app started.
+    output_roots: Mutex<HashSet<PathBuf>>,
+    /// Orders runs that share an output folder.
+    queue: queue::FolderQueue,
+}
+
+/// Keeps a run registered in [`RunRegistry`] for as long as it is alive.
+struct ActiveRunGuard {
+    app: tauri::AppHandle,
+    run_id: String,
+}
+
+impl ActiveRunGuard {
+    fn register(app: &tauri::AppHandle, run_id: &str, output_folder: &Path) -> Self {
+        let registry = app.state::<RunRegistry>();
+        registry
+            .active
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(run_id.to_string(), output_folder.to_path_buf());
+        registry
+            .output_roots
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(output_folder.to_path_buf());
+        Self {
+            app: app.clone(),
+            run_id: run_id.to_string(),
+        }
+    }
+}
+
+impl Drop for ActiveRunGuard {
+    fn drop(&mut self) {
+        self.app
+            .state::<RunRegistry>()
+            .active
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.run_id);
+    }
+}
+
+/// How many GPU runs may be in flight at once unless [`set_concurrency`] says otherwise.
+const DEFAULT_MAX_CONCURRENT: usize = 1;
+static MAX_CONCURRENT: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_CONCURRENT);
+static GPU_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the [`MAX_CONCURRENT`] GPU run slots, given back when dropped.
+struct GpuRunSlot;
+
+impl GpuRunSlot {
+    fn acquire() -> Result<Self, String> {
+        let cap = MAX_CONCURRENT.load(Ordering::SeqCst);
+        GPU_RUNS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < cap).then_some(n + 1)
+            })
+            .map(|_| Self)
+            .map_err(|_| format!("Too many concurrent runs; cap is {cap}"))
+    }
+}
+
+impl Drop for GpuRunSlot {
+    fn drop(&mut self) {
+        GPU_RUNS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Sets how many GPU runs may be in flight at once; further runs are rejected rather
+/// than queued. Raise it only with enough GPUs or memory for the extra runs.
+#[tauri::command]
+fn set_concurrency(n: usize) -> Result<(), String> {
+    if n == 0 {
+        return Err("Concurrency must be at least 1".to_string());
+    }
+    MAX_CONCURRENT.store(n, Ordering::SeqCst);
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CleanupReport {
+    removed: usize,
+    bytes_reclaimed: u64,
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .map_while(Result::ok)
+                .map(|entry| dir_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Removes scratch folders left behind by crashed runs from every known output folder,
+/// skipping folders that belong to a run still in flight.
+#[tauri::command]
+fn cleanup_temp(registry: tauri::State<'_, RunRegistry>) -> Result<CleanupReport, String> {
+    let mut roots = registry
+        .output_roots
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    roots.insert(project_root()?.join("output_gui"));
+    // Hold the lock for the whole sweep so a run can't start in a folder mid-cleanup.
+    let active = registry.active.lock().unwrap_or_else(|e| e.into_inner());
+    let busy: HashSet<&PathBuf> = active.values().collect();
+
+    let mut report = CleanupReport {
+        removed: 0,
+        bytes_reclaimed: 0,
+    };
+    for root in roots.iter().filter(|root| !busy.contains(root)) {
+        for name in TEMP_DIR_NAMES {
+            let dir = root.join(name);
+            if !dir.is_dir() {
+                continue;
+            }
+            let size = dir_size(&dir);
+            fs::remove_dir_all(&dir)
+                .map_err(|e| format!("Failed to remove {}: {e}", dir.display()))?;
+            report.removed += 1;
+            report.bytes_reclaimed += size;
+        }
+    }
+    Ok(report)
+}
+
+/// Whether `path` fully decodes. A killed or OOM run can leave 0-byte or truncated
+/// files whose header still looks valid, so the whole image is decoded.
+fn is_decodable_image(path: &Path) -> bool {
+    image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map(|reader| reader.decode().is_ok())
+        .unwrap_or(false)
+}
+
+/// Non-hidden files in `dir_path` that fail to decode as images.
+fn find_corrupt_images(dir_path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !dir_path.is_dir() {
+        return Ok(Vec::new());
+    }
+    Ok(list_input_files(dir_path)?
+        .into_iter()
+        .filter(|p| !is_decodable_image(p))
+        .collect())
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedSizes {
+    outputs: Vec<String>,
+    /// Requested sizes that were zero or larger than the source, in request order.
+    skipped: Vec<u32>,
+}
+
+/// Writes resized copies of a finished output, one per requested longest-edge size.
+/// Sizes larger than the source are skipped and listed in `skipped` rather than
+/// upscaled.
+#[tauri::command]
+async fn export_sizes(
+    path: String,
+    sizes: Vec<u32>,
+    format: String,
+) -> Result<ExportedSizes, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let format = imaging::parse_format(&format)?;
+        let export = imaging::export_sizes(Path::new(&path), &sizes, format)?;
+        Ok(ExportedSizes {
+            outputs: export
+                .outputs
+                .into_iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect(),
+            skipped: export.skipped,
+        })
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// A path as typed into the UI: surrounding whitespace is dropped and a leading `~`
+/// stands for the home directory.
+fn user_path(text: &str) -> PathBuf {
+    let text = text.trim();
+    let rest = match text.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => rest,
+        _ => return PathBuf::from(text),
+    };
+    match std::env::home_dir() {
+        Some(home) => home.join(rest.trim_start_matches(['/', '\\'])),
+        None => PathBuf::from(text),
+    }
+}
+
+/// The output folder the user chose, or `root/output_gui`.
+fn base_output_folder(root: &Path, args: &ModifyPhotoArgs) -> PathBuf {
+    match &args.output_folder {
+        Some(of) if !of.trim().is_empty() => {
+            let p = user_path(of);
+            if p.is_absolute() {
+                p
+            } else {
+                root.join(p)
+            }
+        }
+        _ => root.join("output_gui"),
+    }
+}
+
+/// `run_id` reduced to characters that are safe in a single path component.
+fn run_dir_name(run_id: &str) -> String {
+    let name: String = run_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() {
+        "run".to_string()
+    } else {
+        name
+    }
+}
+
+/// Where a run writes its stage folders: the base output folder, or with
+/// `isolate_runs` a `<run_id>` folder inside it.
+fn resolve_output_folder(root: &Path, args: &ModifyPhotoArgs) -> PathBuf {
+    let base = base_output_folder(root, args);
+    if args.isolate_runs {
+        base.join(run_dir_name(&args.run_id))
+    } else {
+        base
+    }
+}
+
+/// Free space a run needs unless `min_free_bytes` says otherwise; the stage outputs of
+/// an HR batch can take several gigabytes.
+const DEFAULT_MIN_FREE_BYTES: u64 = 2 << 30;
+
+/// Bytes available on the filesystem holding `path`, `None` if no listed disk does.
+fn free_space(path: &Path) -> Option<u64> {
+    let path = fs::canonicalize(path).ok()?;
+    sysinfo::Disks::new_with_refreshed_list()
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// Fails when `folder`'s filesystem has less than `required` bytes free. Passes when
+/// the free space can't be determined.
+fn check_free_space(folder: &Path, required: u64) -> Result<(), String> {
+    let gib = |bytes: u64| format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64);
+    match free_space(folder) {
+        Some(free) if free < required => Err(format!(
+            "Insufficient disk space: {} free, {} required",
+            gib(free),
+            gib(required)
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Rejects an output folder that is really a file. Symlinks are followed, so a link to
+/// a file is rejected too; a path that doesn't exist yet is fine.
+fn check_output_folder(path: &Path) -> Result<(), String> {
+    match fs::canonicalize(path) {
+        Ok(real) if !real.is_dir() => Err(format!(
+            "Output path is a file, not a folder: {}",
+            path.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The `--flag` names the installed run.py accepts, so the UI can hide settings it
+/// would reject. Cached per interpreter.
+#[tauri::command]
+async fn pipeline_options(python: String) -> Result<Vec<String>, String> {
+    tauri::async_runtime::spawn_blocking(move || options::supported(&project_root()?, &python))
+        .await
+        .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// Checks a candidate output folder before a run, returning its resolved path.
+#[tauri::command]
+fn validate_output_folder(output_folder: String) -> Result<String, String> {
+    let root = project_root()?;
+    let folder = user_path(&output_folder);
+    let folder = if folder.is_absolute() {
+        folder
+    } else {
+        root.join(folder)
+    };
+    check_output_folder(&folder)?;
+    Ok(folder.to_string_lossy().to_string())
+}
+
+/// The body of [`modify_photo`] without the Tauri plumbing: runs `root/run.py` for
+/// `args` and locates the result, reporting progress through `emit`.
+fn run_pipeline(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    emit: impl Fn(ProgressEvent),
+) -> Result<ModifyPhotoResult, ModifyError> {
+    let clock = StageClock::start();
+    let timed = |event: ProgressEvent| {
+        if let Some(stage) = event.stage {
+            clock.observe(stage, stage == 4 && event.message == "Done");
+        }
+        emit(event)
+    };
+    let mut result = restore(root, args, &timed)?;
+    result.stage_durations = clock.durations();
+    Ok(result)
+}
+
+fn restore(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<ModifyPhotoResult, ModifyError> {
+    emit(ProgressEvent {
+        run_id: args.run_id.clone(),
+        stage: Some(0),
+        message: "Starting...".to_string(),
+        is_error: false,
+        ..Default::default()
+    });
+
+    let output_folder = resolve_output_folder(root, args);
+    check_output_folder(&output_folder)?;
+    fs::create_dir_all(&output_folder)
+        .map_err(|e| format!("Failed to create output folder: {e}"))?;
+    check_free_space(
+        &output_folder,
+        args.min_free_bytes.unwrap_or(DEFAULT_MIN_FREE_BYTES),
+    )?;
+    let log = runlog::open(&output_folder, &args.run_id)
+        .inspect_err(|e| {
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message: format!("{e}; the run is not logged to disk"),
+                is_error: true,
+                ..Default::default()
+            })
+        })
+        .ok();
+
+    let input_path = user_path(&args.input_path);
+    if !input_path.exists() {
+        return Err(ModifyError::InputNotFound {
+            path: input_path.display().to_string(),
+        });
+    }
+
+    let input_is_dir = input_path.is_dir();
+    if !input_is_dir && !has_image_header(&input_path) {
+        return Err(ModifyError::UnsupportedImage {
+            path: input_path.display().to_string(),
+        });
+    }
+    if input_is_dir
+        && !folder_inputs(&input_path, args)?
+            .iter()
+            .any(|f| has_image_header(f))
+    {
+        return Err(format!("No supported images in {}", input_path.display()).into());
+    }
+    let mut staged_sources = HashMap::new();
+    let tiff_pages = if !input_is_dir && args.split_tiff_pages && is_tiff(&input_path) {
+        stage_tiff_pages(&input_path, &output_folder, &args.run_id, emit)?
+    } else {
+        None
+    };
+    // Split TIFF pages are processed like a folder of images.
+    let batch_input = input_is_dir || tiff_pages.is_some();
+    let filter = NameFilter::from_args(args)?;
+    let input_folder = if let Some((staging, _)) = &tiff_pages {
+        staging.clone()
+    } else if input_is_dir && (args.recursive || filter.is_active()) {
+        let mut files = folder_inputs(&input_path, args)?;
+        if filter.is_active() {
+            let (kept, not_included, excluded) = filter.apply(files);
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message: format!(
+                    "Filters removed {not_included} file(s) not matching include_glob and \
+                     {excluded} matching exclude_glob; {} left",
+                    kept.len()
+                ),
+                ..Default::default()
+            });
+            if kept.is_empty() {
+                return Err("No input files left after applying the filters"
+                    .to_string()
+                    .into());
+            }
+            files = kept;
+        }
+        let (staging, sources) = stage_files(&input_path, &files, &output_folder)?;
+        if args.recursive {
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message: format!("Collected {} images from nested folders", sources.len()),
+                ..Default::default()
+            });
+        }
+        staged_sources = sources;
+        staging
+    } else if input_is_dir {
+        let files = folder_inputs(&input_path, args)?;
+        if files.iter().any(|f| sniffed_name(f).is_some()) {
+            // Mislabeled files have to be renamed, so work on copies.
+            let (staging, sources) = stage_files(&input_path, &files, &output_folder)?;
+            staged_sources = sources;
+            staging
+        } else {
+            input_path.clone()
+        }
+    } else {
+        ensure_single_image_folder(&input_path, &output_folder)?
+    };
+    if input_folder != input_path {
+        fix_staged_extensions(&input_folder, &mut staged_sources, &args.run_id, emit)?;
+    }
+
+    check_interpreter(&args.python).map_err(ModifyError::InterpreterNotFound)?;
+    let cwd = working_dir(root, args);
+    if !cwd.is_dir() {
+        return Err(format!("Working directory not found: {}", cwd.display()).into());
+    }
+    if args.max_output_edge == Some(0) {
+        return Err("max_output_edge must be at least 1".to_string().into());
+    }
+    if args.stall_timeout_secs == Some(0) {
+        return Err("stall_timeout_secs must be at least 1".to_string().into());
+    }
+    check_child_env(args)?;
+    let layout = StageLayout::from_args(args)?;
+    let convert_to = output_format(args)?;
+    let jpeg_quality = match args.jpeg_quality {
+        Some(quality) if convert_to == Some(image::ImageFormat::Jpeg) => {
+            let clamped = quality.clamp(1, 100);
+            if clamped != quality {
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(0),
+                    message: format!("jpeg_quality {quality} is out of range; using {clamped}"),
+                    is_error: true,
+                    ..Default::default()
+                });
+            }
+            clamped
+        }
+        _ => DEFAULT_JPEG_QUALITY,
+    };
+    if let Some(threads) = args.cpu_threads {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        if !(1..=cores).contains(&threads) {
+            return Err(format!(
+                "cpu_threads must be between 1 and {cores} (the number of CPU cores)"
+            )
+            .into());
+        }
+        emit(ProgressEvent {
+            run_id: args.run_id.clone(),
+            stage: Some(0),
+            message: format!("Limiting CPU threads to {threads} of {cores}"),
+            ..Default::default()
+        });
+    }
+    let mut gpu = GpuSelection::parse(&args.gpu)?;
+    if gpu == GpuSelection::Auto {
+        let (selected, message) = gpu::auto_select(&args.python);
+        gpu = selected;
+        emit(ProgressEvent {
+            run_id: args.run_id.clone(),
+            stage: Some(0),
+            message,
+            ..Default::default()
+        });
+    } else if gpu == GpuSelection::Mps {
+        let message = match gpu::probe_devices(&args.python) {
+            Ok(probe) if !probe.mps => {
+                return Err(
+                    "Apple MPS is not available with this Python's torch; use -1 for the CPU"
+                        .to_string()
+                        .into(),
+                )
+            }
+            Ok(_) => "Using Apple MPS; run.py's scripts that only support CUDA run on the CPU"
+                .to_string(),
+            Err(e) => format!("Skipping GPU validation: {e}"),
+        };
+        emit(ProgressEvent {
+            run_id: args.run_id.clone(),
+            stage: Some(0),
+            message,
+            ..Default::default()
+        });
+    } else if matches!(gpu, GpuSelection::Devices(_)) {
+        match gpu::probe_devices(&args.python) {
+            Ok(probe) => {
+                let devices = probe.cuda;
+                gpu.check_device_count(devices.len(), probe.mps)?;
+                let names = devices
+                    .iter()
+                    .filter(|d| gpu.includes(d.index))
+                    .map(|d| format!("{} ({})", d.index, d.name))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(0),
+                    message: format!("Using GPU {names}"),
+                    is_error: false,
+                    ..Default::default()
+                });
+            }
+            Err(e) => emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message: format!("Skipping GPU validation: {e}"),
+                is_error: true,
+                ..Default::default()
+            }),
+        }
+    }
+
+    if args.clear_previous {
+        let status = |message: String| {
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message,
+                ..Default::default()
+            })
+        };
+        status("Clearing previous outputs...".to_string());
+        reset_stage_dirs_reporting(&output_folder, &layout, |name| {
+            status(format!("Clearing {name}..."))
+        })?;
+        status("Ready".to_string());
+    } else {
+        ensure_stage_dirs(&output_folder, &layout)?;
+    }
+
+    let final_dir = output_folder.join(layout.final_dir());
+
+    let run_py = root.join("run.py");
+    if !run_py.exists() {
+        return Err(format!("run.py not found: {}", run_py.display()).into());
+    }
+
+    let extra_args = optional_pipeline_args(root, args, emit)?;
+    if args.test_first && batch_input {
+        run_test_image(
+            root,
+            args,
+            &gpu,
+            &extra_args,
+            &input_folder,
+            &output_folder,
+            emit,
+        )?;
+    }
+    let BatchOutcome {
+        failed,
+        stage_counts,
+        report,
+    } = if args.isolate_files && batch_input {
+        run_isolated_files(
+            root,
+            args,
+            &gpu,
+            &extra_args,
+            &input_folder,
+            &output_folder,
+            emit,
+        )?
+    } else if args.chunk_size.is_some() && batch_input {
+        run_chunks(
+            root,
+            args,
+            &gpu,
+            &extra_args,
+            &input_folder,
+            &output_folder,
+            emit,
+        )?
+    } else {
+        let total = list_input_files(&input_folder)?.len();
+        let run_on = |gpu: &GpuSelection| {
+            let cmd = pipeline_command(root, args, gpu, &extra_args, &input_folder, &output_folder);
+            let mut options = ProcessOptions::from_args(args, gpu);
+            options.preview_dir = Some(output_folder.clone());
+            if batch_input {
+                options.watch_outputs = Some(OutputWatch::new(final_dir.clone(), total));
+                options.file_total = Some(total);
+            }
+            run_pipeline_process(cmd, &args.run_id, options, emit)
+        };
+        let report = match run_on(&gpu) {
+            Err(e)
+                if args.cpu_fallback && gpu != GpuSelection::Cpu && is_cuda_oom(&e.to_string()) =>
+            {
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(0),
+                    message: "GPU failed, retrying on CPU".to_string(),
+                    is_error: true,
+                    ..Default::default()
+                });
+                if args.clear_previous {
+                    reset_stage_dirs(&output_folder, &layout)?;
+                }
+                run_on(&GpuSelection::Cpu)?
+            }
+            result => result?,
+        };
+        BatchOutcome {
+            failed: Vec::new(),
+            stage_counts: count_stage_outputs(&output_folder, &layout),
+            report,
+        }
+    };
+
+    let corrupt = find_corrupt_images(&final_dir)?;
+    for path in &corrupt {
+        emit(ProgressEvent {
+            run_id: args.run_id.clone(),
+            stage: Some(4),
+            message: format!("Skipping corrupt or truncated output: {}", path.display()),
+            is_error: true,
+            ..Default::default()
+        });
+    }
+    if !batch_input {
+        if let Some(path) = corrupt.first() {
+            return Err(format!("Output image is corrupt or truncated: {}", path.display()).into());
+        }
+    }
+    let latest = match tiff_pages {
+        Some((_, total)) if args.recombine_tiff => {
+            let stem = input_path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            recombine_tiff_pages(&final_dir, &stem, total, &args.run_id, emit)?
+        }
+        _ => {
+            let strategy = args.output_selection.unwrap_or_default();
+            select_output_file(&final_dir, strategy, |p| !corrupt.iter().any(|c| c == p))?
+                .ok_or_else(|| ModifyError::NoOutput {
+                    dir: final_dir.display().to_string(),
+                })?
+        }
+    };
+    // Batches are matched to their inputs through `source_paths` instead.
+    let latest = if input_is_dir {
+        latest
+    } else {
+        rename_after_input(&latest, &input_path)?
+    };
+
+    // Output file stem -> the input it was made from. Staged inputs keep their staged
+    // name's stem, which is what run.py names the outputs after.
+    let stem_of = |p: &Path| p.file_stem().map(|s| s.to_string_lossy().to_string());
+    let mut sources_by_stem: HashMap<String, PathBuf> = if !staged_sources.is_empty() {
+        staged_sources
+            .iter()
+            .filter_map(|(name, src)| Some((stem_of(Path::new(name))?, src.clone())))
+            .collect()
+    } else {
+        let originals = if input_is_dir {
+            list_input_files(&input_path)?
+        } else {
+            vec![input_path.clone()]
+        };
+        originals
+            .into_iter()
+            .filter_map(|p| Some((stem_of(&p)?, p)))
+            .collect()
+    };
+    if let (false, Some(stem)) = (input_is_dir, stem_of(&latest)) {
+        sources_by_stem.insert(stem, input_path.clone());
+    }
+
+    let settings = imaging::Postprocess {
+        reencode: args.convert_srgb,
+        grayscale: args.force_grayscale,
+        max_edge: args.max_output_edge,
+        denoise: args.denoise,
+    };
+    let mut resized = Vec::new();
+    if settings.rewrites_all() || settings.max_edge.is_some() {
+        let outputs: Vec<_> = list_input_files(&final_dir)?
+            .into_iter()
+            .filter(|p| has_image_extension(p) && !corrupt.contains(p))
+            .collect();
+        for (i, path) in outputs.iter().enumerate() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let progress = format!("{}/{}", i + 1, outputs.len());
+            let (message, is_error) = match imaging::postprocess(path, settings) {
+                Ok(true) => {
+                    resized.push(path.to_string_lossy().to_string());
+                    let edge = settings.max_edge.unwrap_or_default();
+                    (
+                        format!("Post-processed {progress}: {name} (resized to fit {edge}px)"),
+                        false,
+                    )
+                }
+                Ok(false) if !settings.rewrites_all() => continue,
+                Ok(false) => (format!("Post-processed {progress}: {name}"), false),
+                Err(e) => (format!("Post-processing {name} failed: {e}"), true),
+            };
+            emit(ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(4),
+                message,
+                is_error,
+                ..Default::default()
+            });
+        }
+    }
+
+    if args.preserve_metadata {
+        for output in list_input_files(&final_dir)?
+            .into_iter()
+            .filter(|p| has_image_extension(p) && !corrupt.contains(p))
+        {
+            let Some(source) = stem_of(&output).and_then(|stem| sources_by_stem.get(&stem)) else {
+                continue;
+            };
+            if let Err(e) = imaging::copy_exif(source, &output) {
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(4),
+                    message: format!("Warning: metadata not copied: {e}"),
+                    is_error: true,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    let mut latest = latest;
+    if let Some(format) = convert_to {
+        let outputs: Vec<_> = list_input_files(&final_dir)?
+            .into_iter()
+            .filter(|p| has_image_extension(p) && !corrupt.contains(p))
+            .collect();
+        for path in outputs {
+            let converted = imaging::convert_format(&path, format, jpeg_quality)?;
+            let (old, new) = (path.to_string_lossy(), converted.to_string_lossy());
+            for entry in resized.iter_mut().filter(|r| **r == old) {
+                *entry = new.to_string();
+            }
+            if path == latest {
+                latest = converted;
+            }
+        }
+    }
+
+    let source_paths = map_outputs_to_sources(&final_dir, &staged_sources)?;
+    let structured_paths = if args.preserve_structure && args.recursive && input_is_dir {
+        mirror_input_structure(&source_paths, &input_path, &output_folder)?
+    } else {
+        HashMap::new()
+    };
+
+    if !args.keep_intermediates {
+        remove_intermediates(&output_folder, &layout)?;
+    }
+
+    let manifest_path = if args.write_manifest {
+        Some(manifest::write(
+            &output_folder,
+            &layout.result_dirs,
+            &args.run_id,
+            &sources_by_stem,
+        )?)
+    } else {
+        None
+    };
+
+    let mut original_copies = HashMap::new();
+    if args.keep_original_copy {
+        let originals = output_folder.join(ORIGINALS_DIR);
+        fs::create_dir_all(&originals)
+            .map_err(|e| format!("Failed to create {}: {e}", originals.display()))?;
+        for output in list_input_files(&final_dir)?
+            .into_iter()
+            .filter(|p| has_image_extension(p) && !corrupt.contains(p))
+        {
+            let Some(stem) = stem_of(&output) else {
+                continue;
+            };
+            let Some(source) = sources_by_stem.get(&stem) else {
+                continue;
+            };
+            let mut copy = originals.join(format!("{stem}{ORIGINAL_SUFFIX}"));
+            if let Some(ext) = source.extension() {
+                copy.set_extension(ext);
+            }
+            fs::copy(source, &copy)
+                .map_err(|e| format!("Failed to copy {}: {e}", source.display()))?;
+            original_copies.insert(
+                output.to_string_lossy().to_string(),
+                copy.to_string_lossy().to_string(),
+            );
+        }
+    }
+
+    let produced = if input_is_dir {
+        list_input_files(&final_dir)?
+            .into_iter()
+            .filter(|p| has_image_extension(p) && !corrupt.contains(p))
+            .collect()
+    } else {
+        vec![latest.clone()]
+    };
+    let outputs = produced
+        .into_iter()
+        .map(|output| OutputItem {
+            input: stem_of(&output)
+                .and_then(|stem| sources_by_stem.get(&stem))
+                .map(|p| p.to_string_lossy().to_string()),
+            output: output.to_string_lossy().to_string(),
+        })
+        .collect();
+
+    let thumbs = if input_is_dir {
+        None
+    } else {
+        comparison_thumbs(&input_path, &latest, &output_folder)
+            .inspect_err(|e| {
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(4),
+                    message: format!("Warning: no before/after thumbnails: {e}"),
+                    is_error: true,
+                    ..Default::default()
+                })
+            })
+            .ok()
+    };
+
+    emit(ProgressEvent {
+        run_id: args.run_id.clone(),
+        stage: Some(4),
+        message: "Done".to_string(),
+        is_error: false,
+        ..Default::default()
+    });
+
+    let mut hook_error = None;
+    match args.post_hook.as_deref().map(str::trim) {
+        Some(hook) if !hook.is_empty() && args.allow_post_hook => {
+            if let Err(e) = run_post_hook(hook, &output_folder, &args.run_id, emit) {
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(4),
+                    message: e.clone(),
+                    is_error: true,
+                    hook: true,
+                    ..Default::default()
+                });
+                hook_error = Some(e);
+            }
+        }
+        Some(hook) if !hook.is_empty() => emit(ProgressEvent {
+            run_id: args.run_id.clone(),
+            stage: Some(4),
+            message: "post_hook not run: set allow_post_hook to enable it".to_string(),
+            is_error: true,
+            hook: true,
+            ..Default::default()
+        }),
+        _ => {}
+    }
+
+    Ok(ModifyPhotoResult {
+        run_id: args.run_id.clone(),
+        output_path: latest.to_string_lossy().to_string(),
+        outputs,
+        failed: failed
+            .into_iter()
+            .map(|(name, e)| (name, e.to_string()))
+            .collect(),
+        source_paths,
+        structured_paths,
+        original_copies,
+        archive_path: None,
+        stage_counts,
+        hook_error,
+        manifest_path: manifest_path.map(|p| p.to_string_lossy().to_string()),
+        no_faces: report.no_faces,
+        skipped: report.skipped,
+        resized,
+        reprocess_depth: args.reprocess_depth,
+        stage_durations: Vec::new(),
+        input_thumb: thumbs
+            .as_ref()
+            .map(|(t, _)| t.to_string_lossy().to_string()),
+        output_thumb: thumbs
+            .as_ref()
+            .map(|(_, t)| t.to_string_lossy().to_string()),
+        log_path: log.map(|l| l.path().to_string_lossy().to_string()),
+        command: None,
+    })
+}
+
+/// Runs the user's post-run `hook` through the shell with `output_folder` as its last
+/// argument, streaming its output as hook events.
+fn run_post_hook(
+    hook: &str,
+    output_folder: &Path,
+    run_id: &str,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<(), String> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(hook).arg(output_folder);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!("{hook} \"$1\""))
+            .arg("sh")
+            .arg(output_folder);
+        cmd
+    };
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to start post_hook: {e}"))?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let (tx, rx) = mpsc::channel::<(bool, String)>();
+    let tx_err = tx.clone();
+    let out_handle = thread::spawn(move || {
+        if let Some(stdout) = stdout {
+            for_each_capped_line(BufReader::new(stdout), |line, _| {
+                let _ = tx.send((false, line));
+            });
+        }
+    });
+    let err_handle = thread::spawn(move || {
+        if let Some(stderr) = stderr {
+            for_each_capped_line(BufReader::new(stderr), |line, _| {
+                let _ = tx_err.send((true, line));
+            });
+        }
+    });
+    for (is_error, line) in rx {
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: Some(4),
+            message: line,
+            is_error,
+            hook: true,
+            ..Default::default()
+        });
+    }
+    let _ = out_handle.join();
+    let _ = err_handle.join();
+
+    let status = child
+        .wait()
+        .map_err(|e| format!("post_hook process error: {e}"))?;
+    if !status.success() {
+        return Err(format!("post_hook exited with status: {status}"));
+    }
+    Ok(())
+}
+
+/// Whether `path` lies inside one of `roots`, after resolving symlinks and `..`.
+fn is_within_roots(path: &Path, roots: &HashSet<PathBuf>) -> bool {
+    let Ok(path) = fs::canonicalize(path) else {
+        return false;
+    };
+    roots
+        .iter()
+        .filter_map(|root| fs::canonicalize(root).ok())
+        .any(|root| path.starts_with(root))
+}
+
+/// Every output folder this app has used, this session or in the run history.
+fn known_output_roots(registry: &RunRegistry, root: &Path) -> HashSet<PathBuf> {
+    let mut roots = registry
+        .output_roots
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    roots.insert(root.join("output_gui"));
+    roots.extend(
+        history::load(root)
+            .into_iter()
+            .map(|record| resolve_output_folder(root, &record.args)),
+    );
+    roots
+}
+
+/// Returns an output image as PNG bytes, scaled down to `max_edge` if given, for
+/// webviews that can't load files through the asset protocol. Only files under an
+/// output folder this app has used (this session or in the run history) can be read.
+#[tauri::command]
+async fn read_output_image(
+    registry: tauri::State<'_, RunRegistry>,
+    path: String,
+    max_edge: Option<u32>,
+) -> Result<Vec<u8>, String> {
+    let root = project_root()?;
+    let roots = known_output_roots(&registry, &root);
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let path = PathBuf::from(path);
+        if !is_within_roots(&path, &roots) {
+            return Err(format!(
+                "Not inside a known output folder: {}",
+                path.display()
+            ));
+        }
+        let img = imaging::open_image(&path)?;
+        let img = match max_edge {
+            Some(edge) if edge > 0 => imaging::shrink_to_fit(img, edge),
+            _ => img,
+        };
+        imaging::encode_png(&img)
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// Makes thumbnails of every image in `folder` in one call, for gallery views; see
+/// [`imaging::thumbnail_folder`]. Like [`read_output_image`], only known output
+/// folders are accepted.
+#[tauri::command]
+async fn thumbnail_folder(
+    registry: tauri::State<'_, RunRegistry>,
+    folder: String,
+    max_edge: u32,
+) -> Result<Vec<String>, String> {
+    if max_edge == 0 {
+        return Err("max_edge must be at least 1".to_string());
+    }
+    let root = project_root()?;
+    let roots = known_output_roots(&registry, &root);
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let folder = PathBuf::from(folder);
+        if !is_within_roots(&folder, &roots) {
+            return Err(format!(
+                "Not inside a known output folder: {}",
+                folder.display()
+            ));
+        }
+        let thumbs = imaging::thumbnail_folder(&folder, max_edge)?;
+        Ok(thumbs
+            .into_iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect())
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// Removes every [`imaging::THUMBS_DIR`] under `dir`, returning the bytes freed.
+/// Symlinks are not followed, so a link can't lead the sweep outside `dir`.
+fn remove_thumbnail_caches(dir: &Path) -> Result<u64, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(format!("Failed to read {}: {e}", dir.display())),
+    };
+    let mut reclaimed = 0;
+    for entry in entries.map_while(Result::ok) {
+        if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_name() == imaging::THUMBS_DIR {
+            let size = dir_size(&path);
+            fs::remove_dir_all(&path)
+                .map_err(|e| format!("Failed to remove {}: {e}", path.display()))?;
+            reclaimed += size;
+        } else {
+            reclaimed += remove_thumbnail_caches(&path)?;
+        }
+    }
+    Ok(reclaimed)
+}
+
+/// Deletes the thumbnail caches written by [`thumbnail_folder`] under `folder`, or
+/// under every known output folder when `None`. Returns the bytes reclaimed.
+#[tauri::command]
+async fn clear_thumbnail_cache(
+    registry: tauri::State<'_, RunRegistry>,
+    folder: Option<String>,
+) -> Result<u64, String> {
+    let root = project_root()?;
+    let roots = known_output_roots(&registry, &root);
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let folders = match folder {
+            Some(folder) => {
+                let folder = PathBuf::from(folder);
+                if !is_within_roots(&folder, &roots) {
+                    return Err(format!(
+                        "Not inside a known output folder: {}",
+                        folder.display()
+                    ));
+                }
+                vec![folder]
+            }
+            None => roots.into_iter().collect(),
+        };
+        folders
+            .iter()
+            .map(|folder| remove_thumbnail_caches(folder))
+            .sum()
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct VersionInfo {
+    app_version: String,
+    pipeline_version: Option<String>,
+    git_commit: Option<String>,
+}
+
+/// `(VERSION file contents, git commit)` of the project root, probed once per launch.
+static PIPELINE_VERSION: OnceLock<(Option<String>, Option<String>)> = OnceLock::new();
+
+fn probe_pipeline_version(root: &Path) -> (Option<String>, Option<String>) {
+    let version = fs::read_to_string(root.join("VERSION"))
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    let git_commit = Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .filter(|commit| !commit.is_empty());
+    (version, git_commit)
+}
+
+/// Checks the whole setup for `python` in one go, for a "check everything" view: see
+/// [`diagnose::run`].
+#[tauri::command]
+async fn diagnose(python: String) -> Result<diagnose::Diagnosis, String> {
+    let root = project_root()?;
+    tauri::async_runtime::spawn_blocking(move || diagnose::run(&root, &python))
+        .await
+        .map_err(|e| format!("Task failed: {e}"))
+}
+
+/// The interpreter's version and which required packages it has; every run checks
+/// the same before it starts.
+#[tauri::command]
+async fn check_environment(python: String) -> Result<diagnose::Environment, String> {
+    tauri::async_runtime::spawn_blocking(move || diagnose::check_environment(&python))
+        .await
+        .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// Versions to quote in bug reports.
+#[tauri::command]
+fn version_info() -> VersionInfo {
+    let (pipeline_version, git_commit) = PIPELINE_VERSION
+        .get_or_init(|| match project_root() {
+            Ok(root) => probe_pipeline_version(&root),
+            Err(_) => (None, None),
+        })
+        .clone();
+    VersionInfo {
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        pipeline_version,
+        git_commit,
+    }
+}
+
+/// `args` with paths made absolute and the GPU value normalized, so a stored copy
+/// reproduces the run regardless of the working directory at the time. A URL input is
+/// kept as is, so a rerun downloads it again.
+fn resolve_args(root: &Path, args: &ModifyPhotoArgs) -> ModifyPhotoArgs {
+    let absolute = |p: &str| {
+        std::path::absolute(p)
+            .unwrap_or_else(|_| PathBuf::from(p))
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut resolved = args.clone();
+    resolved.input_path = if fetch::is_url(&args.input_path) {
+        args.input_path.trim().to_string()
+    } else {
+        absolute(&user_path(&args.input_path).to_string_lossy())
+    };
+    // The base folder is kept so a rerun with `isolate_runs` nests under its own run_id.
+    resolved.output_folder = Some(base_output_folder(root, args).to_string_lossy().to_string());
+    resolved.gpu = args.gpu.trim().to_string();
+    // A bare interpreter name is looked up on PATH; only pin explicit paths.
+    if Path::new(&args.python).components().count() > 1 {
+        resolved.python = absolute(&args.python);
+    }
+    resolved
+}
+
+/// Runs one job on the current thread: wires up event delivery, marks the run as
+/// active and records the outcome in the run history, even if the pipeline panics. A
+/// `label` is prefixed to every progress message.
+fn execute_run(
+    app: &tauri::AppHandle,
+    args: ModifyPhotoArgs,
+    label: Option<&str>,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    let root = project_root()?;
+    let args = resolve_args(&root, &args);
+    if args.dry_run {
+        return Ok(ModifyPhotoResult {
+            run_id: args.run_id.clone(),
+            command: Some(dry_run_command(&root, &args)?),
+            ..Default::default()
+        });
+    }
+
+    let record = history::RecordGuard::start(&root, args.clone());
+    let event_pipe = match args.event_pipe.as_deref().map(str::trim) {
+        Some(p) if !p.is_empty() => match EventPipe::open(p) {
+            Ok(pipe) => Some(pipe),
+            Err(e) => {
+                let result = Err(ModifyError::from(e));
+                record.finish(&result);
+                return result;
+            }
+        },
+        _ => None,
+    };
+    // The event pipe gets every line; only the webview is throttled.
+    let throttle = throttle::EventThrottle::new();
+    let emit = |mut event: ProgressEvent| {
+        if let Some(preview) = event.preview.take() {
+            let _ = app.emit("stage_preview", preview);
+            return;
+        }
+        if let Some(stage) = event.stage {
+            record.reached_stage(stage);
+            event.eta_secs = record.eta_secs();
+        }
+        if let Some(label) = label {
+            event.message = format!("[{label}] {}", event.message);
+        }
+        if let Some(pipe) = &event_pipe {
+            pipe.write(&event);
+        }
+        throttle.submit(event, |event| emit_progress(app, event));
+    };
+
+    let output_folder = resolve_output_folder(&root, &args);
+    let cancel = cancel::register(&args.run_id);
+    let result = with_downloaded_input(&root, &args, &emit, |args| {
+        let _turn = wait_for_folder(app, &args.run_id, &output_folder, &cancel, &emit)?;
+        let _slot = match GpuSelection::parse(&args.gpu) {
+            Ok(GpuSelection::Cpu) => None,
+            _ => Some(GpuRunSlot::acquire()?),
+        };
+        let _active = ActiveRunGuard::register(app, &args.run_id, &output_folder);
+        // Before run_pipeline can clear the previous outputs.
+        match diagnose::preflight(&args.python) {
+            Ok(()) => run_pipeline(&root, args, emit),
+            Err(e) => {
+                emit(ProgressEvent {
+                    run_id: args.run_id.clone(),
+                    stage: Some(0),
+                    message: e.clone(),
+                    is_error: true,
+                    ..Default::default()
+                });
+                Err(e.into())
+            }
+        }
+    });
+    throttle.finish(&args.run_id, |event| emit_progress(app, event));
+    record.finish(&result);
+    result
+}
+
+/// Waits until the runs ahead of `run_id` in `output_folder` are done, see
+/// [`queue::FolderQueue`]. A run cancelled while it waits never starts.
+fn wait_for_folder<'a>(
+    app: &'a tauri::AppHandle,
+    run_id: &str,
+    output_folder: &Path,
+    cancel: &cancel::CancelGuard,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<queue::Turn<'a>, String> {
+    let token = cancel.token();
+    token.set_queued(true);
+    let turn = app.state::<RunRegistry>().inner().queue.wait_turn(
+        output_folder,
+        || token.message(),
+        || {
+            emit(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: Some(0),
+                message: "Queued, waiting for current job".to_string(),
+                ..Default::default()
+            })
+        },
+    );
+    token.set_queued(false);
+    turn.inspect_err(|message| {
+        emit(ProgressEvent {
+            run_id: run_id.to_string(),
+            stage: Some(0),
+            message: format!("{message} while queued; nothing was started"),
+            is_error: true,
+            ..Default::default()
+        });
+    })
+}
+
+/// Stops a run in flight, or takes it out of the queue if it is still waiting for the
+/// GPU or its output folder. Its error is "Cancelled: <reason>", or "Cancelled by user"
+/// without a reason, and ends up in the run history. Returns whether the run had
+/// started or was still queued, or `None` if no run with that id is active.
+#[tauri::command]
+fn cancel_run(run_id: String, reason: Option<String>) -> Option<cancel::CancelledState> {
+    cancel::cancel(&run_id, reason)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RunPlan {
+    to_process: Vec<String>,
+    skipped: Vec<String>,
+    /// Skipped file -> why it was left out.
+    reasons: HashMap<String, String>,
+}
+
+/// The input files a run with `args` would hand to run.py, worked out with the same
+/// selection code the run uses.
+fn plan_inputs(args: &ModifyPhotoArgs) -> Result<RunPlan, String> {
+    let input_path = user_path(&args.input_path);
+    if !input_path.exists() {
+        return Err(format!("Input not found: {}", input_path.display()));
+    }
+    let filter = NameFilter::from_args(args)?;
+    let mut plan = RunPlan {
+        to_process: Vec::new(),
+        skipped: Vec::new(),
+        reasons: HashMap::new(),
+    };
+    if !input_path.is_dir() {
+        // Single files are never filtered.
+        plan.to_process
+            .push(input_path.to_string_lossy().to_string());
+        return Ok(plan);
+    }
+    for file in folder_inputs(&input_path, args)? {
+        let path = file.to_string_lossy().to_string();
+        match filter.rejects(&file) {
+            Some(reason) => {
+                plan.reasons.insert(path.clone(), reason.to_string());
+                plan.skipped.push(path);
+            }
+            None => plan.to_process.push(path),
+        }
+    }
+    Ok(plan)
+}
+
+/// Lists which files a run would process, and which it would skip and why, without
+/// starting run.py.
+#[tauri::command]
+async fn plan_run(args: ModifyPhotoArgs) -> Result<RunPlan, String> {
+    tauri::async_runtime::spawn_blocking(move || plan_inputs(&args))
+        .await
+        .map_err(|e| format!("Task failed: {e}"))?
+}
+
+#[tauri::command]
+async fn modify_photo(
+    app: tauri::AppHandle,
+    args: ModifyPhotoArgs,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    tauri::async_runtime::spawn_blocking(move || execute_run(&app, args, None))
+        .await
+        .map_err(|e| ModifyError::Other(format!("Task failed: {e}")))?
+}
+
+/// Settings that describe a single run and make no sense as saved defaults.
+const PER_RUN_SETTINGS: [&str; 2] = ["runId", "inputPath"];
+
+/// Saves default run settings, for every GPU when `gpu` is `None` or for one GPU
+/// index. Passing no settings for a GPU clears its entry.
+#[tauri::command]
+fn save_gpu_settings(
+    gpu: Option<u32>,
+    settings: serde_json::Map<String, serde_json::Value>,
+) -> Result<settings::Settings, String> {
+    if let Some(key) = PER_RUN_SETTINGS.iter().find(|k| settings.contains_key(**k)) {
+        return Err(format!("{key} can't be saved as a default setting"));
+    }
+    settings::store(&project_root()?, gpu, settings)
+}
+
+/// The saved defaults for runs on GPU `index`: the global defaults with that GPU's
+/// own settings, if any, on top.
+#[tauri::command]
+fn settings_for_gpu(index: u32) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    Ok(settings::load(&project_root()?).for_gpu(index))
+}
+
+/// The devices `python`'s torch can run the pipeline on, for the GPU picker. The CPU
+/// is always listed, and is all there is when torch can't be imported.
+#[tauri::command]
+async fn list_gpus(python: String) -> Result<Vec<gpu::GpuInfo>, String> {
+    tauri::async_runtime::spawn_blocking(move || gpu::gpu_options(gpu::probe_devices(&python).ok()))
+        .await
+        .map_err(|e| format!("Task failed: {e}"))
+}
+
+/// The summary of run `run_id` written to `run_<run_id>.json` in its output folder,
+/// see [`history::RunSummary`]. Every run that got a history record has one.
+#[tauri::command]
+fn read_run_manifest(run_id: String) -> Result<history::RunSummary, String> {
+    history::read_summary(&project_root()?, &run_id)
+}
+
+/// The log file of run `run_id`, see [`runlog`]. Works while the run is still going.
+#[tauri::command]
+fn get_log_path(run_id: String) -> Result<String, String> {
+    runlog::find(&project_root()?, &run_id).map(|p| p.to_string_lossy().to_string())
+}
+
+/// The `limit` most recent runs from the history, oldest first. A missing or corrupt
+/// history file gives an empty list.
+#[tauri::command]
+fn get_run_history(limit: usize) -> Result<Vec<RunRecord>, String> {
+    Ok(history::recent(&project_root()?, limit))
+}
+
+/// Repeats a run from the history with exactly the settings it used. The new run gets
+/// its own run_id, returned in the result.
+#[tauri::command]
+async fn rerun_with_same_settings(
+    app: tauri::AppHandle,
+    run_id: String,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let root = project_root()?;
+        let record = history::find(&root, &run_id)
+            .ok_or_else(|| format!("No run with id {run_id} in history"))?;
+        let mut args = record.args;
+        args.run_id = format!("{run_id}_rerun_{}", history::unix_now());
+        execute_run(&app, args, None)
+    })
+    .await
+    .map_err(|e| ModifyError::Other(format!("Task failed: {e}")))?
+}
+
+/// Fields a config file must set; everything else has a default.
+const CONFIG_REQUIRED: [&str; 5] = ["inputPath", "gpu", "withScratch", "hr", "python"];
+
+/// Reads a TOML file of [`ModifyPhotoArgs`] settings, camelCase keys as in the JSON
+/// arguments. Relative `inputPath` and `outputFolder` values are taken from the config
+/// file's folder, and a `runId` is generated unless the file sets one.
+fn args_from_config(path: &Path) -> Result<ModifyPhotoArgs, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config {}: {e}", path.display()))?;
+    let mut table: toml::Table =
+        toml::from_str(&text).map_err(|e| format!("Invalid config {}: {e}", path.display()))?;
+    let missing: Vec<_> = CONFIG_REQUIRED
+        .iter()
+        .filter(|key| !table.contains_key(**key))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "Config {} is missing required field(s): {}",
+            path.display(),
+            missing.join(", ")
+        ));
+    }
+
+    let base = path.parent().unwrap_or(Path::new("."));
+    for key in ["inputPath", "outputFolder"] {
+        if let Some(toml::Value::String(value)) = table.get_mut(key) {
+            if !value.trim().is_empty() && Path::new(value.as_str()).is_relative() {
+                *value = base.join(&*value).to_string_lossy().to_string();
+            }
+        }
+    }
+    if !table.contains_key("runId") {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let run_id = format!("{stem}_{}", history::unix_now());
+        table.insert("runId".to_string(), toml::Value::String(run_id));
+    }
+    table
+        .try_into()
+        .map_err(|e| format!("Invalid config {}: {e}", path.display()))
+}
+
+/// Runs the pipeline with the settings in a TOML config file, see [`args_from_config`],
+/// for scripted runs that repeat the same settings.
+#[tauri::command]
+async fn modify_photo_from_config(
+    app: tauri::AppHandle,
+    config_path: String,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let args = args_from_config(Path::new(&config_path))?;
+        execute_run(&app, args, None)
+    })
+    .await
+    .map_err(|e| ModifyError::Other(format!("Task failed: {e}")))?
+}
+
+/// Calls `run` with `args`, or, when `args.input_path` is a URL, with the image it
+/// points at downloaded into `<output folder>/_gui_download`. Download progress is
+/// reported through `emit`. The download is removed once `run` returns; the history
+/// keeps the URL, so a rerun fetches it again.
+fn with_downloaded_input<T>(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    emit: &dyn Fn(ProgressEvent),
+    run: impl FnOnce(&ModifyPhotoArgs) -> Result<T, ModifyError>,
+) -> Result<T, ModifyError> {
+    if !fetch::is_url(&args.input_path) {
+        return run(args);
+    }
+    let downloads = resolve_output_folder(root, args).join(DOWNLOAD_DIR);
+    let folder = downloads.join(run_dir_name(&args.run_id));
+    let event = |message: String, is_error: bool| ProgressEvent {
+        run_id: args.run_id.clone(),
+        stage: Some(0),
+        message,
+        is_error,
+        ..Default::default()
+    };
+    let result = fetch::download_image(&args.python, args.input_path.trim(), &folder, &|m| {
+        emit(event(m, false))
+    })
+    .inspect_err(|e| emit(event(e.clone(), true)))
+    .map_err(ModifyError::from)
+    .and_then(|path| {
+        let mut local = args.clone();
+        local.input_path = path.to_string_lossy().to_string();
+        run(&local)
+    });
+    let _ = fs::remove_dir_all(&folder);
+    // Only goes if no other run is downloading into it.
+    let _ = fs::remove_dir(&downloads);
+    result
+}
+
+/// Restores the image at `url` with `args`, whose `input_path` is replaced. The same
+/// as passing the URL as `input_path` to [`modify_photo`].
+#[tauri::command]
+async fn restore_from_url(
+    app: tauri::AppHandle,
+    url: String,
+    mut args: ModifyPhotoArgs,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    args.input_path = url;
+    tauri::async_runtime::spawn_blocking(move || execute_run(&app, args, None))
+        .await
+        .map_err(|e| ModifyError::Other(format!("Task failed: {e}")))?
+}
+
+/// Images [`benchmark`] runs on, relative to the project root.
+const SAMPLE_IMAGES_DIR: &str = "test_images/old";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BenchmarkResult {
+    images: usize,
+    total_secs: f64,
+    images_per_minute: f64,
+    /// Stage (1-4) -> seconds it took per image, on average.
+    stage_secs_per_image: HashMap<u8, f64>,
+    /// The device run.py reported using, or the requested one if it didn't say.
+    device: Option<String>,
+    /// The CUDA device's name, when running on one.
+    device_name: Option<String>,
+}
+
+/// Times `args` on `sample_count` of the bundled sample images, repeating them if there
+/// are fewer, in a scratch folder that is removed afterwards. One image is restored
+/// first as an untimed warm-up.
+fn run_benchmark(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    sample_count: usize,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<BenchmarkResult, String> {
+    if sample_count == 0 {
+        return Err("sample_count must be at least 1".to_string());
+    }
+    let samples: Vec<_> = list_input_files(&root.join(SAMPLE_IMAGES_DIR))?
+        .into_iter()
+        .filter(|p| has_image_extension(p))
+        .collect();
+    if samples.is_empty() {
+        return Err(format!("No sample images in {SAMPLE_IMAGES_DIR}"));
+    }
+
+    let scratch = std::env::temp_dir()
+        .join("old_photos_benchmark")
+        .join(run_dir_name(&args.run_id));
+    let result = time_samples(root, args, &samples, sample_count, &scratch, emit);
+    let _ = fs::remove_dir_all(&scratch);
+    result
+}
+
+/// The timed part of [`run_benchmark`], working in `scratch`.
+fn time_samples(
+    root: &Path,
+    args: &ModifyPhotoArgs,
+    samples: &[PathBuf],
+    sample_count: usize,
+    scratch: &Path,
+    emit: &dyn Fn(ProgressEvent),
+) -> Result<BenchmarkResult, String> {
+    let input = scratch.join("input");
+    fs::create_dir_all(&input).map_err(|e| format!("Failed to create {}: {e}", input.display()))?;
+    for (i, sample) in samples.iter().cycle().take(sample_count).enumerate() {
+        let stem = sample.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = sample.extension().unwrap_or_default().to_string_lossy();
+        let name = match i / samples.len() {
+            0 => format!("{stem}.{ext}"),
+            n => format!("{stem}_{n}.{ext}"),
+        };
+        fs::copy(sample, input.join(name))
+            .map_err(|e| format!("Failed to copy sample image: {e}"))?;
+    }
+
+    let mut bench_args = args.clone();
+    bench_args.output_folder = Some(scratch.join("output").to_string_lossy().to_string());
+    bench_args.clear_previous = true;
+    bench_args.isolate_runs = false;
+    bench_args.isolate_files = false;
+    bench_args.chunk_size = None;
+    bench_args.recursive = false;
+
+    bench_args.input_path = samples[0].to_string_lossy().to_string();
+    emit(ProgressEvent {
+        run_id: args.run_id.clone(),
+        stage: Some(0),
+        message: "Warming up on one image...".to_string(),
+        ..Default::default()
+    });
+    run_pipeline(root, &bench_args, emit)?;
+
+    bench_args.input_path = input.to_string_lossy().to_string();
+    let stage_starts = Mutex::new(Vec::<(u8, Instant)>::new());
+    let device = Mutex::new(None);
+    let started = Instant::now();
+    run_pipeline(root, &bench_args, |event: ProgressEvent| {
+        if let Some(stage) = event.stage.filter(|&s| s >= 1) {
+            let mut starts = stage_starts.lock().unwrap_or_else(|e| e.into_inner());
+            if starts.last().is_none_or(|&(last, _)| stage > last) {
+                starts.push((stage, Instant::now()));
+            }
+        }
+        if let Some(selected) = &event.device_selected {
+            *device.lock().unwrap_or_else(|e| e.into_inner()) = Some(selected.clone());
+        }
+        emit(event);
+    })?;
+    let finished = Instant::now();
+
+    let starts = stage_starts.into_inner().unwrap_or_else(|e| e.into_inner());
+    let ends = starts.iter().skip(1).map(|&(_, at)| at).chain([finished]);
+    let stage_secs_per_image = starts
+        .iter()
+        .zip(ends)
+        .map(|(&(stage, start), end)| (stage, (end - start).as_secs_f64() / sample_count as f64))
+        .collect();
+    let total_secs = (finished - started).as_secs_f64();
+    let device = device
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .or_else(|| GpuSelection::parse(&args.gpu).ok()?.expected_device());
+    let device_name = device
+        .as_deref()
+        .and_then(|d| d.strip_prefix("cuda:")?.parse::<u32>().ok())
+        .and_then(|index| {
+            gpu::probe_cuda_devices(&args.python)
+                .ok()?
+                .into_iter()
+                .find(|d| d.index == index)
+                .map(|d| d.name)
+        });
+    Ok(BenchmarkResult {
+        images: sample_count,
+        total_secs,
+        images_per_minute: sample_count as f64 * 60.0 / total_secs.max(f64::EPSILON),
+        stage_secs_per_image,
+        device,
+        device_name,
+    })
+}
+
+/// Measures how fast this machine restores images, for comparing hardware: see
+/// [`run_benchmark`]. `args` supplies the run settings; its input and output folder
+/// are replaced. Nothing is added to the run history.
+#[tauri::command]
+async fn benchmark(
+    app: tauri::AppHandle,
+    sample_count: usize,
+    args: ModifyPhotoArgs,
+) -> Result<BenchmarkResult, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let root = project_root()?;
+        let _cancel = cancel::register(&args.run_id);
+        run_benchmark(&root, &args, sample_count, &|event| {
+            emit_progress(&app, event)
+        })
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// How often an output can be fed back through the pipeline in a chain.
+const MAX_REPROCESS_DEPTH: u32 = 3;
+
+/// `args` set up to run an earlier output through the pipeline again. The depth is one
+/// more than that of the run that produced it, according to the history. The run is
+/// always isolated so clearing its stage folders can't remove the output it started
+/// from.
+fn reprocess_args(
+    root: &Path,
+    output_path: &str,
+    mut args: ModifyPhotoArgs,
+) -> Result<ModifyPhotoArgs, String> {
+    if !Path::new(output_path).is_file() {
+        return Err(format!("Output not found: {output_path}"));
+    }
+    let previous = history::load(root)
+        .into_iter()
+        .rev()
+        .find(|r| r.output_path.as_deref() == Some(output_path))
+        .map_or(0, |r| r.args.reprocess_depth);
+    if previous >= MAX_REPROCESS_DEPTH {
+        return Err(format!(
+            "This output has already been reprocessed {previous} time(s); the limit is \
+             {MAX_REPROCESS_DEPTH}"
+        ));
+    }
+    args.input_path = output_path.to_string();
+    args.reprocess_depth = previous + 1;
+    args.isolate_runs = true;
+    Ok(args)
+}
+
+/// Runs an earlier output through the pipeline again with `args`, for damage one pass
+/// didn't fix. `args.input_path` is replaced by `output_path`. Chains stop after
+/// [`MAX_REPROCESS_DEPTH`] passes.
+#[tauri::command]
+async fn reprocess(
+    app: tauri::AppHandle,
+    output_path: String,
+    args: ModifyPhotoArgs,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let args = reprocess_args(&project_root()?, &output_path, args)?;
+        execute_run(&app, args, None)
+    })
+    .await
+    .map_err(|e| ModifyError::Other(format!("Task failed: {e}")))?
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RetryReport {
+    /// The new run ids, in the order they run.
+    retried: Vec<String>,
+    /// Failed runs left alone because their input no longer exists.
+    skipped: Vec<String>,
+}
+
+/// The runs [`retry_failed`] would start, as of the records in `history`, and the
+/// failed runs it has to skip because their input is gone.
+fn plan_retries(history: Vec<RunRecord>, now: u64) -> (Vec<ModifyPhotoArgs>, Vec<RunRecord>) {
+    let mut latest: Vec<RunRecord> = Vec::new();
+    for record in history {
+        // A retry supersedes the run it retried, whatever its outcome.
+        let original = record.run_id.rsplit_once("_retry_").map(|(id, _)| id);
+        latest.retain(|r| r.run_id != record.run_id && Some(r.run_id.as_str()) != original);
+        latest.push(record);
+    }
+
+    let mut retries = Vec::new();
+    let mut skipped = Vec::new();
+    for record in latest.into_iter().filter(|r| !r.success) {
+        let input = &record.args.input_path;
+        if !fetch::is_url(input) && !Path::new(input).exists() {
+            skipped.push(record);
+            continue;
+        }
+        let mut args = record.args;
+        args.run_id = format!("{}_retry_{now}", record.run_id);
+        retries.push(args);
+    }
+    (retries, skipped)
+}
+
+/// Reruns, one after another in the background, every run whose latest history
+/// record failed. Returns the new run ids right away, along with the runs skipped
+/// because their input has since disappeared; each of those also gets an error event
+/// under its old run id.
+#[tauri::command]
+fn retry_failed(app: tauri::AppHandle) -> Result<RetryReport, String> {
+    let root = project_root()?;
+    let (retries, skipped) = plan_retries(history::load(&root), history::unix_now());
+    for record in &skipped {
+        emit_progress(
+            &app,
+            ProgressEvent {
+                run_id: record.run_id.clone(),
+                message: format!(
+                    "Not retrying {}: input no longer exists: {}",
+                    record.run_id, record.args.input_path
+                ),
+                is_error: true,
+                ..Default::default()
+            },
+        );
+    }
+
+    let report = RetryReport {
+        retried: retries.iter().map(|args| args.run_id.clone()).collect(),
+        skipped: skipped.into_iter().map(|record| record.run_id).collect(),
+    };
+    tauri::async_runtime::spawn_blocking(move || {
+        for args in retries {
+            // Failures are reported through progress events and the history.
+            let _ = execute_run(&app, args, None);
+        }
+    });
+    Ok(report)
+}
+
+/// Restores the images inside a ZIP archive as one batch. Entries are extracted into
+/// `<output>/_gui_zip`, which is removed afterwards; `source_paths` maps each output to
+/// `<zip_path>/<entry>`. With `rezip`, the restored images are also written to
+/// `<output>/<zip stem>_restored.zip`.
+#[cfg(feature = "zip-archives")]
+fn run_zip(
+    app: &tauri::AppHandle,
+    zip_path: &Path,
+    args: ModifyPhotoArgs,
+    rezip: bool,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    let root = project_root()?;
+    let output_folder = resolve_output_folder(&root, &args);
+    let staging = output_folder.join(ZIP_STAGING_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)
+            .map_err(|e| format!("Failed to clear {ZIP_STAGING_DIR}: {e}"))?;
+    }
+    fs::create_dir_all(&staging).map_err(|e| format!("Failed to create {ZIP_STAGING_DIR}: {e}"))?;
+
+    let emit = |message: String, is_error: bool| {
+        emit_progress(
+            app,
+            ProgressEvent {
+                run_id: args.run_id.clone(),
+                stage: Some(0),
+                message,
+                is_error,
+                ..Default::default()
+            },
+        )
+    };
+    let staged = archive::extract_images(
+        zip_path,
+        &staging,
+        |i, total, name| emit(format!("Extracted {i}/{total}: {name}"), false),
+        |name| {
+            emit(
+                format!("Skipped zip entry {name}: it points outside the archive"),
+                true,
+            )
+        },
+    )?;
+    if staged.is_empty() {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(format!("No images found in {}", zip_path.display()).into());
+    }
+
+    let final_dir = output_folder.join(StageLayout::from_args(&args)?.final_dir());
+    let mut batch = args;
+    batch.input_path = staging.to_string_lossy().to_string();
+    batch.recursive = false;
+    let result = execute_run(app, batch, None);
+    let _ = fs::remove_dir_all(&staging);
+    let mut result = result?;
+
+    let sources: HashMap<String, PathBuf> = staged
+        .into_iter()
+        .map(|(name, entry)| (name, zip_path.join(entry)))
+        .collect();
+    result.source_paths = map_outputs_to_sources(&final_dir, &sources)?;
+    if rezip {
+        let stem = zip_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "restored".to_string());
+        let out = output_folder.join(format!("{stem}_restored.zip"));
+        archive::zip_files(&list_input_files(&final_dir)?, &out, |_, _, _| {})?;
+        result.archive_path = Some(out.to_string_lossy().to_string());
+    }
+    Ok(result)
+}
+
+#[tauri::command]
+async fn restore_from_zip(
+    app: tauri::AppHandle,
+    zip_path: String,
+    args: ModifyPhotoArgs,
+    rezip: Option<bool>,
+) -> Result<ModifyPhotoResult, ModifyError> {
+    #[cfg(feature = "zip-archives")]
+    return tauri::async_runtime::spawn_blocking(move || {
+        run_zip(&app, Path::new(&zip_path), args, rezip.unwrap_or(false))
+    })
+    .await
+    .map_err(|e| ModifyError::Other(format!("Task failed: {e}")))?;
+
+    #[cfg(not(feature = "zip-archives"))]
+    {
+        let _ = (app, zip_path, args, rezip);
+        Err(ModifyError::Other(
+            "This build has no ZIP support (the zip-archives feature is off)".to_string(),
+        ))
+    }
+}
+
+/// Writes the final outputs of the recorded run `run_id` into a ZIP archive at `dest`.
+/// Only images that really live in the run's `final_output` folder are packed, so a
+/// symlinked output can't pull in files from elsewhere.
+#[cfg(feature = "zip-archives")]
+fn zip_run_outputs(
+    root: &Path,
+    run_id: &str,
+    dest: &Path,
+    on_file: impl FnMut(usize, usize, &str),
+) -> Result<PathBuf, String> {
+    let record =
+        history::find(root, run_id).ok_or_else(|| format!("No run with id {run_id} in history"))?;
+    let final_dir = resolve_output_folder(root, &record.args)
+        .join(StageLayout::from_args(&record.args)?.final_dir());
+    let real_dir =
+        fs::canonicalize(&final_dir).map_err(|_| format!("Run {run_id} has no final outputs"))?;
+    let files: Vec<_> = list_input_files(&final_dir)?
+        .into_iter()
+        .filter(|p| has_image_extension(p))
+        .collect();
+    if files.is_empty() {
+        return Err(format!("Run {run_id} has no final outputs"));
+    }
+    for file in &files {
+        if !fs::canonicalize(file).is_ok_and(|real| real.starts_with(&real_dir)) {
+            return Err(format!(
+                "Output is outside the run's folder: {}",
+                file.display()
+            ));
+        }
+    }
+    if let Some(parent) = dest.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+    }
+    archive::zip_files(&files, dest, on_file)?;
+    Ok(dest.to_path_buf())
+}
+
+/// Bundles a finished run's final outputs into a ZIP at `dest` and returns its path.
+/// Each compressed file is reported as a progress event under the run's id.
+#[tauri::command]
+async fn zip_outputs(
+    app: tauri::AppHandle,
+    run_id: String,
+    dest: String,
+) -> Result<String, String> {
+    #[cfg(feature = "zip-archives")]
+    return tauri::async_runtime::spawn_blocking(move || {
+        let root = project_root()?;
+        let archive = zip_run_outputs(&root, &run_id, Path::new(&dest), |i, total, name| {
+            emit_progress(
+                &app,
+                ProgressEvent {
+                    run_id: run_id.clone(),
+                    stage: Some(4),
+                    message: format!("Compressed {i}/{total}: {name}"),
+                    ..Default::default()
+                },
+            );
+        })?;
+        Ok(archive.to_string_lossy().to_string())
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?;
+
+    #[cfg(not(feature = "zip-archives"))]
+    {
+        let _ = (app, run_id, dest);
+        Err("This build has no ZIP support (the zip-archives feature is off)".to_string())
+    }
+}
+
+/// Moves `src` to `dst`, copying and deleting when they are on different filesystems.
+/// An existing `dst` is never overwritten.
+fn move_file(src: &Path, dst: &Path) -> Result<(), String> {
+    if dst.exists() {
+        return Err(format!("Destination already exists: {}", dst.display()));
+    }
+    if fs::rename(src, dst).is_ok() {
+        return Ok(());
+    }
+    fs::copy(src, dst).map_err(|e| format!("Failed to copy {}: {e}", src.display()))?;
+    fs::remove_file(src).map_err(|e| format!("Failed to remove {}: {e}", src.display()))
+}
+
+/// Moves a finished output, which must sit under a known output folder, to `dst`, and
+/// points the run history and the nearest `manifest.json` above it at the new path.
+/// `dst`'s folder must already exist. Returns the new path.
+#[tauri::command]
+async fn move_output(
+    registry: tauri::State<'_, RunRegistry>,
+    src: String,
+    dst: String,
+) -> Result<String, String> {
+    let root = project_root()?;
+    let roots = known_output_roots(&registry, &root);
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let src_path = PathBuf::from(&src);
+        if !src_path.is_file() || !is_within_roots(&src_path, &roots) {
+            return Err(format!("Not an output inside a known output folder: {src}"));
+        }
+        let dst_path =
+            std::path::absolute(&dst).map_err(|e| format!("Invalid destination: {e}"))?;
+        if !dst_path.parent().is_some_and(Path::is_dir) {
+            return Err(format!(
+                "Destination folder does not exist: {}",
+                dst_path.parent().unwrap_or(&dst_path).display()
+            ));
+        }
+        let manifest = src_path
+            .ancestors()
+            .skip(1)
+            .map(|dir| dir.join(manifest::MANIFEST_NAME))
+            .find(|p| p.is_file() && is_within_roots(p, &roots));
+
+        move_file(&src_path, &dst_path)?;
+        let new = dst_path.to_string_lossy().to_string();
+        if let Err(e) = history::replace_output_path(&root, &src, &new) {
+            eprintln!("Failed to update run history: {e}");
+        }
+        if let Some(manifest) = manifest {
+            if let Err(e) = manifest::relocate(&manifest, &src, &new) {
+                eprintln!("Failed to update {}: {e}", manifest.display());
+            }
+        }
+        Ok(new)
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// Composites captioned thumbnails of `paths` into grid images with `columns` cells per
+/// row, written to the temp folder. Long lists are split over several sheets of
+/// [`imaging::SHEET_ROWS`] rows; every sheet's path is returned. Like
+/// [`read_output_image`], only files under known output folders are accepted.
+#[tauri::command]
+async fn make_contact_sheet(
+    registry: tauri::State<'_, RunRegistry>,
+    paths: Vec<String>,
+    columns: u32,
+) -> Result<Vec<String>, String> {
+    if paths.is_empty() {
+        return Err("No images given for the contact sheet".to_string());
+    }
+    if columns == 0 {
+        return Err("columns must be at least 1".to_string());
+    }
+    let root = project_root()?;
+    let roots = known_output_roots(&registry, &root);
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+        if let Some(outside) = paths.iter().find(|p| !is_within_roots(p, &roots)) {
+            return Err(format!(
+                "Not inside a known output folder: {}",
+                outside.display()
+            ));
+        }
+        let out_dir = std::env::temp_dir().join("old_photos_contact_sheets");
+        fs::create_dir_all(&out_dir)
+            .map_err(|e| format!("Failed to create {}: {e}", out_dir.display()))?;
+        let prefix = format!("sheet_{}", history::unix_now());
+        let sheets = imaging::contact_sheets(&paths, columns, &out_dir, &prefix)?;
+        Ok(sheets
+            .into_iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect())
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// Downloads whatever checkpoints and helper code the pipeline is missing, the way the
+/// `download-weights` script would, reporting progress as `download_progress` events.
+/// Succeeds only once every required asset is present.
+#[tauri::command]
+async fn ensure_models(app: tauri::AppHandle, python: String) -> Result<(), String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let root = project_root()?;
+        let missing = models::missing_assets(&root);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        check_interpreter(&python)?;
+        for asset in missing {
+            models::download(&root, &python, asset, &|event| {
+                let _ = app.emit("download_progress", event);
+            })?;
+        }
+
+        let still_missing: Vec<_> = models::missing_assets(&root)
+            .iter()
+            .map(|asset| asset.name)
+            .collect();
+        if still_missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Still missing after download: {}",
+                still_missing.join(", ")
+            ))
+        }
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+/// How long [`emit_test_events`] spreads its events over.
+const TEST_EVENTS_SPAN: Duration = Duration::from_secs(2);
+
+/// Stage numbers 1-4 spread evenly over `count` events, for [`emit_test_events`].
+fn test_event_stage(index: u32, count: u32) -> u8 {
+    (1 + u64::from(index) * 4 / u64::from(count.max(1))) as u8
+}
+
+/// Emits `count` synthetic progress events for `run_id` across stages 1-4 over about
+/// two seconds, without starting Python. If these show up but a real run's don't, the
+/// problem is in the pipeline rather than event delivery.
+#[tauri::command]
+async fn emit_test_events(app: tauri::AppHandle, run_id: String, count: u32) {
+    let _ = tauri::async_runtime::spawn_blocking(move || {
+        let delay = TEST_EVENTS_SPAN / count.max(1);
+        for i in 0..count {
+            if i > 0 {
+                thread::sleep(delay);
+            }
+            emit_progress(
+                &app,
+                ProgressEvent {
+                    run_id: run_id.clone(),
+                    stage: Some(test_event_stage(i, count)),
+                    message: format!("Test event {}/{count}", i + 1),
+                    ..Default::default()
+                },
+            );
+        }
+    })
+    .await;
+}
+
+/// Opens the folder holding the settings and run history, `root/output_gui`, in the
+/// system file manager, creating it first if needed, so users can find files to attach
+/// to bug reports.
+#[tauri::command]
+fn open_app_data_dir(app: tauri::AppHandle) -> Result<(), String> {
+    use tauri_plugin_opener::OpenerExt;
+    let settings = settings::settings_path(&project_root()?);
+    let dir = settings.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    app.opener()
+        .open_path(dir.to_string_lossy(), None::<&str>)
+        .map_err(|e| format!("Failed to open {}: {e}", dir.display()))
+}
+
+/// Shows `path`, typically a result's `output_path`, selected in its folder in the
+/// system file manager. Opening the file itself would start an image viewer instead.
+#[tauri::command]
+fn open_output_location(app: tauri::AppHandle, path: String) -> Result<(), String> {
+    use tauri_plugin_opener::OpenerExt;
+    let path = PathBuf::from(path);
+    if !path.exists() {
+        return Err(format!("Output not found: {}", path.display()));
+    }
+    app.opener()
+        .reveal_item_in_dir(&path)
+        .map_err(|e| format!("Failed to reveal {}: {e}", path.display()))
+}
+
+/// The optional cargo features compiled into this build, so the UI can hide controls
+/// for the ones that are missing. Every optional feature is listed here and only here.
+#[tauri::command]
+fn features() -> Vec<String> {
+    [("zip-archives", cfg!(feature = "zip-archives"))]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+/// One output matched between two runs by file stem.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RunComparison {
+    name: String,
+    /// `None` when the run has no output for this name.
+    path_a: Option<String>,
+    path_b: Option<String>,
+    /// Luma PSNR in dB (100 for identical images); only set when both outputs exist.
+    psnr: Option<f64>,
+    ssim: Option<f64>,
+}
+
+/// Final outputs of a recorded run by file stem.
+fn run_outputs(root: &Path, record: &RunRecord) -> Result<HashMap<String, PathBuf>, String> {
+    let final_dir = resolve_output_folder(root, &record.args)
+        .join(StageLayout::from_args(&record.args)?.final_dir());
+    if !final_dir.is_dir() {
+        return Ok(HashMap::new());
+    }
+    Ok(list_input_files(&final_dir)?
+        .into_iter()
+        .filter(|p| has_image_extension(p))
+        .filter_map(|p| {
+            let stem = p.file_stem()?.to_string_lossy().to_string();
+            Some((stem, p))
+        })
+        .collect())
+}
+
+fn compare_outputs(
+    outputs_a: &HashMap<String, PathBuf>,
+    outputs_b: &HashMap<String, PathBuf>,
+) -> Result<Vec<RunComparison>, String> {
+    let mut names: Vec<&String> = outputs_a.keys().chain(outputs_b.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let mut comparisons = Vec::new();
+    for name in names {
+        let (a, b) = (outputs_a.get(name), outputs_b.get(name));
+        let (psnr, ssim) = match (a, b) {
+            (Some(a), Some(b)) => {
+                let (a, b) = (imaging::open_image(a)?, imaging::open_image(b)?);
+                (Some(imaging::psnr(&a, &b)), Some(imaging::ssim(&a, &b)))
+            }
+            _ => (None, None),
+        };
+        comparisons.push(RunComparison {
+            name: name.clone(),
+            path_a: a.map(|p| p.to_string_lossy().to_string()),
+            path_b: b.map(|p| p.to_string_lossy().to_string()),
+            psnr,
+            ssim,
+        });
+    }
+    Ok(comparisons)
+}
+
+/// Pairs up the outputs of two runs from the history by file name and measures how
+/// much they differ. Runs that shared an output folder without `isolate_runs` will have
+/// overwritten each other, so compare isolated runs.
+#[tauri::command]
+async fn compare_runs(run_id_a: String, run_id_b: String) -> Result<Vec<RunComparison>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let root = project_root()?;
+        let find = |run_id: &str| {
+            history::find(&root, run_id)
+                .ok_or_else(|| format!("No run with id {run_id} in history"))
+        };
+        let (a, b) = (find(&run_id_a)?, find(&run_id_b)?);
+        compare_outputs(&run_outputs(&root, &a)?, &run_outputs(&root, &b)?)
+    })
+    .await
+    .map_err(|e| format!("Task failed: {e}"))?
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScratchComparison {
+    with_scratch: ModifyPhotoResult,
+    without_scratch: ModifyPhotoResult,
+}
+
+/// Restores one image twice, with and without scratch removal, so the results can be
+/// compared side by side. The variants run one after the other to avoid competing for
+/// the GPU, each in its own folder under `<output>/_gui_compare/<run_id>`, with run ids
+/// `<run_id>_with_scratch` and `<run_id>_without_scratch`.
+#[tauri::command]
+async fn compare_scratch(
+    app: tauri::AppHandle,
+    args: ModifyPhotoArgs,
+) -> Result<ScratchComparison, ModifyError> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let root = project_root()?;
+        let args = resolve_args(&root, &args);
+        if !Path::new(&args.input_path).is_file() {
+            return Err(ModifyError::Other(
+                "Scratch comparison needs a single image as input".to_string(),
+            ));
+        }
+        let compare_dir = base_output_folder(&root, &args)
+            .join("_gui_compare")
+            .join(&args.run_id);
+
+        let variant = |with_scratch: bool, name: &str, label: &str| {
+            let mut variant = args.clone();
+            variant.run_id = format!("{}_{name}", args.run_id);
+            variant.with_scratch = with_scratch;
+            variant.output_folder = Some(compare_dir.join(name).to_string_lossy().to_string());
+            variant.isolate_files = false;
+            variant.isolate_runs = false;
+            variant.recursive = false;
+            execute_run(&app, variant, Some(label))
+        };
+        Ok(ScratchComparison {
+            with_scratch: variant(true, "with_scratch", "with scratch")?,
+            without_scratch: variant(false, "without_scratch", "without scratch")?,
+        })
+    })
+    .await
+    .map_err(|e| ModifyError::Other(format!("Task failed: {e}")))?
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    tauri::Builder::default()
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_opener::init())
+        .manage(RunRegistry::default())
+        .invoke_handler(tauri::generate_handler![
+            greet,
+            modify_photo,
+            cleanup_temp,
+            export_sizes,
+            version_info,
+            diagnose,
+            check_environment,
+            get_run_history,
+            read_run_manifest,
+            get_log_path,
+            list_gpus,
+            rerun_with_same_settings,
+            save_gpu_settings,
+            settings_for_gpu,
+            modify_photo_from_config,
+            plan_run,
+            reprocess,
+            restore_from_url,
+            benchmark,
+            compare_scratch,
+            validate_output_folder,
+            read_output_image,
+            thumbnail_folder,
+            clear_thumbnail_cache,
+            retry_failed,
+            pipeline_options,
+            restore_from_zip,
+            zip_outputs,
+            features,
+            ensure_models,
+            emit_test_events,
+            set_concurrency,
+            make_contact_sheet,
+            move_output,
+            open_app_data_dir,
+            open_output_location,
+            compare_runs,
+            cancel_run
+        ])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::process::Child;
+
+    fn write_png(path: &Path) {
+        image::RgbImage::from_pixel(32, 32, image::Rgb([120, 80, 40]))
+            .save(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn truncated_png_is_reported_as_corrupt() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good.png");
+        let truncated = dir.path().join("truncated.png");
+        let empty = dir.path().join("empty.png");
+        write_png(&good);
+        let bytes = fs::read(&good).unwrap();
+        fs::write(&truncated, &bytes[..bytes.len() / 2]).unwrap();
+        fs::write(&empty, b"").unwrap();
+
+        assert!(is_decodable_image(&good));
+        assert!(!is_decodable_image(&truncated));
+
+        let corrupt = find_corrupt_images(dir.path()).unwrap();
+        assert_eq!(corrupt, vec![empty, truncated]);
+    }
+
+    #[test]
+    fn latest_file_skips_rejected_candidates() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("good.png");
+        let bad = dir.path().join("bad.png");
+        write_png(&good);
+        fs::write(&bad, b"not an image").unwrap();
+
+        let picked = pick_latest_file(dir.path(), is_decodable_image).unwrap();
+        assert_eq!(picked, Some(good));
+    }
+
+    #[test]
+    fn output_scan_ignores_non_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("a.png");
+        write_png(&image);
+        thread::sleep(Duration::from_millis(20));
+        fs::write(dir.path().join("_run.log"), vec![b'x'; 4096]).unwrap();
+
+        for strategy in [
+            SelectionStrategy::Newest,
+            SelectionStrategy::Largest,
+            SelectionStrategy::ByName,
+        ] {
+            let picked = select_output_file(dir.path(), strategy, |_| true).unwrap();
+            assert_eq!(picked.as_ref(), Some(&image), "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn missing_key_module_is_diagnosed() {
+        let d = missing_module_from_line("ModuleNotFoundError: No module named 'cv2'").unwrap();
+        assert_eq!(d.kind, "missing_module");
+        assert_eq!(d.hint, "pip install opencv-python");
+
+        assert!(missing_module_from_line("ImportError: No module named torch.cuda").is_some());
+        assert!(missing_module_from_line("ModuleNotFoundError: No module named 'yaml'").is_none());
+        assert!(missing_module_from_line("Running Stage 1: Overall restoration").is_none());
+    }
+
+    /// Stands in for the real run.py: prints the stage banners and copies each input
+    /// into final_output. Inputs named `bad*` make it fail.
+    const STUB_RUN_PY: &str = r#"
+import argparse, os, shutil, sys
+parser = argparse.ArgumentParser()
+parser.add_argument("--input_folder")
+parser.add_argument("--output_folder")
+parser.add_argument("--GPU")
+parser.add_argument("--with_scratch", action="store_true")
+parser.add_argument("--HR", action="store_true")
+opts = parser.parse_args()
+names = sorted(os.listdir(opts.input_folder))
+if any(name.startswith("bad") for name in names):
+    print("Traceback (most recent call last):", file=sys.stderr)
+    sys.exit(3)
+final_dir = os.path.join(opts.output_folder, "final_output")
+os.makedirs(final_dir, exist_ok=True)
+for stage in range(1, 5):
+    print("Running Stage %d: stub" % stage)
+for name in names:
+    shutil.copy(os.path.join(opts.input_folder, name), final_dir)
+print("All the processing is done. Please check the results.")
+"#;
+
+    fn stub_project() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("run.py"), STUB_RUN_PY).unwrap();
+        root
+    }
+
+    fn stub_args(root: &Path, input: &Path, extra: serde_json::Value) -> ModifyPhotoArgs {
+        let mut args = serde_json::json!({
+            "runId": "test-run",
+            "inputPath": input,
+            "outputFolder": root.join("out"),
+            "gpu": "-1",
+            "withScratch": false,
+            "hr": false,
+            "python": "python3",
+        });
+        if let serde_json::Value::Object(extra) = extra {
+            args.as_object_mut().unwrap().extend(extra);
+        }
+        serde_json::from_value(args).unwrap()
+    }
+
+    fn collect_events(
+        root: &Path,
+        args: &ModifyPhotoArgs,
+    ) -> (Result<ModifyPhotoResult, ModifyError>, Vec<ProgressEvent>) {
+        let events = Mutex::new(Vec::new());
+        let result = run_pipeline(root, args, |event| events.lock().unwrap().push(event));
+        (result, events.into_inner().unwrap())
+    }
+
+    #[test]
+    fn stub_pipeline_reports_stages_and_output() {
+        let root = stub_project();
+        let input = root.path().join("grandpa.png");
+        write_png(&input);
+
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let (result, events) = collect_events(root.path(), &args);
+        let result = result.unwrap();
+
+        let mut stages: Vec<u8> = events.iter().filter_map(|e| e.stage).collect();
+        stages.dedup();
+        assert_eq!(stages, vec![0, 1, 2, 3, 4]);
+        assert!(events.iter().all(|e| e.run_id == "test-run"));
+        let clearing = events
+            .iter()
+            .position(|e| e.message == "Clearing previous outputs...")
+            .unwrap();
+        assert_eq!(events[clearing + 1].message, "Ready");
+        assert_eq!(
+            PathBuf::from(result.output_path),
+            root.path().join("out/final_output/grandpa_restored.png")
+        );
+        assert_eq!(result.stage_counts[&4], 1);
+        assert_eq!(result.stage_counts[&1], 0);
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(result.manifest_path.unwrap()).unwrap())
+                .unwrap();
+        let entry = &manifest["outputs"][0];
+        assert_eq!(entry["stage"], 4);
+        assert_eq!(entry["source"], input.to_string_lossy().as_ref());
+        assert_eq!(
+            (entry["width"].as_u64(), entry["format"].as_str()),
+            (Some(32), Some("png"))
+        );
+    }
+
+    #[test]
+    fn stub_pipeline_failure_is_returned() {
+        let root = stub_project();
+        let input = root.path().join("bad.png");
+        write_png(&input);
+
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let (result, events) = collect_events(root.path(), &args);
+
+        let err = result.unwrap_err();
+        assert!(
+            matches!(err, ModifyError::PythonExited { code: Some(3), .. }),
+            "{err:?}"
+        );
+        let err = err.to_string();
+        assert!(err.starts_with("Python exited with status"), "{err}");
+        assert!(events.last().unwrap().is_error);
+    }
+
+    #[test]
+    fn isolated_batch_keeps_going_after_a_failure() {
+        let root = stub_project();
+        let input = root.path().join("batch");
+        fs::create_dir_all(&input).unwrap();
+        write_png(&input.join("a.png"));
+        write_png(&input.join("bad.png"));
+        write_png(&input.join("c.png"));
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "isolateFiles": true }),
+        );
+        let (result, _) = collect_events(root.path(), &args);
+        let result = result.unwrap();
+
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].0, "bad.png");
+        let final_dir = root.path().join("out/final_output");
+        assert!(final_dir.join("a.png").is_file());
+        assert!(final_dir.join("c.png").is_file());
+        assert!(!root.path().join("out").join(ISOLATED_RUN_DIR).exists());
+    }
+
+    fn python_snippet(code: &str) -> Command {
+        let mut cmd = Command::new("python3");
+        cmd.arg("-c").arg(code);
+        cmd
+    }
+
+    #[test]
+    fn tracker_follows_text_and_structured_stages() {
+        let events = Mutex::new(Vec::new());
+        let emit = |event: ProgressEvent| events.lock().unwrap().push(event);
+        let mut tracker = OutputTracker::new();
+
+        tracker.handle_line("r", false, "loading".to_string(), false, &emit);
+        assert_eq!(tracker.stage, Some(0));
+        tracker.handle_line(
+            "r",
+            false,
+            "Running Stage 2: Face Detection".to_string(),
+            false,
+            &emit,
+        );
+        assert_eq!(tracker.stage, Some(2));
+        tracker.handle_line("r", false, "Finish Stage 2 ...".to_string(), false, &emit);
+        assert_eq!(tracker.stage, Some(2));
+        assert!(!tracker.structured);
+
+        let json = r#"@progress {"stage": 3, "message": "faces"}"#;
+        tracker.handle_line("r", false, json.to_string(), false, &emit);
+        assert!(tracker.structured);
+        assert_eq!(tracker.stage, Some(3));
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[3].message, "faces");
+    }
+
+    #[test]
+    fn output_printed_right_before_exit_is_drained() {
+        let events = Mutex::new(Vec::new());
+        let cmd = python_snippet("for i in range(500): print(i)");
+        run_pipeline_process(cmd, "r", ProcessOptions::default(), &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+
+        let events: Vec<_> = events
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .filter(|e| !e.message.starts_with(LOADING_MODELS_MESSAGE))
+            .collect();
+        assert_eq!(events.len(), 500);
+        assert_eq!(events[499].message, "499");
+    }
+
+    #[test]
+    fn missing_module_is_carried_into_the_error() {
+        let events = Mutex::new(Vec::new());
+        let cmd = python_snippet(
+            "import sys; print(\"ModuleNotFoundError: No module named 'torch'\", file=sys.stderr); sys.exit(1)",
+        );
+        let err = run_pipeline_process(cmd, "r", ProcessOptions::default(), &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.contains("pip install torch"), "{err}");
+        let last = events.into_inner().unwrap().pop().unwrap();
+        assert!(last.is_error);
+        assert_eq!(last.diagnostic.unwrap().kind, "missing_module");
+    }
+
+    #[test]
+    fn recursive_input_is_flattened_and_mapped_back() {
+        let root = stub_project();
+        let input = root.path().join("album");
+        fs::create_dir_all(input.join("1950/summer")).unwrap();
+        write_png(&input.join("cover.png"));
+        write_png(&input.join("1950/summer/beach.png"));
+        fs::write(input.join("1950/notes.txt"), "not an image").unwrap();
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "recursive": true }),
+        );
+        let (result, _) = collect_events(root.path(), &args);
+        let result = result.unwrap();
+
+        let final_dir = root.path().join("out/final_output");
+        let beach = final_dir.join("1950__summer__beach.png");
+        assert_eq!(result.source_paths.len(), 2);
+        assert_eq!(
+            result.source_paths[&beach.to_string_lossy().to_string()],
+            input.join("1950/summer/beach.png").to_string_lossy()
+        );
+        assert!(result.structured_paths.is_empty());
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "recursive": true, "preserveStructure": true }),
+        );
+        let result = collect_events(root.path(), &args).0.unwrap();
+        let manifest = fs::read_to_string(result.manifest_path.as_ref().unwrap()).unwrap();
+        assert!(manifest.contains(&*input.join("1950/summer/beach.png").to_string_lossy()));
+        let mirrored = root.path().join("out/structured/1950/summer/beach.png");
+        assert!(mirrored.is_file());
+        assert_eq!(
+            result.structured_paths[&beach.to_string_lossy().to_string()],
+            mirrored.to_string_lossy()
+        );
+        assert!(root.path().join("out/structured/cover.png").is_file());
+    }
+
+    #[test]
+    fn loading_heartbeat_runs_until_the_first_stage() {
+        let events = Mutex::new(Vec::new());
+        let cmd = python_snippet("import time; time.sleep(3.5); print('Running Stage 1: x')");
+        run_pipeline_process(cmd, "r", ProcessOptions::default(), &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events[0].message, LOADING_MODELS_MESSAGE);
+        assert!(events[1].message.starts_with("Loading models... ("));
+        assert_eq!(events.last().unwrap().stage, Some(1));
+    }
+
+    #[test]
+    fn loading_heartbeat_stops_at_the_first_stdout_line() {
+        let events = Mutex::new(Vec::new());
+        let cmd = python_snippet(
+            "import time\nprint('Loading weights', flush=True)\ntime.sleep(2.5)\n\
+             print('Running Stage 1: x')",
+        );
+        run_pipeline_process(cmd, "r", ProcessOptions::default(), &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+
+        let events = events.into_inner().unwrap();
+        assert!(events.iter().any(|e| e.message == "Loading weights"));
+        assert!(!events
+            .iter()
+            .any(|e| e.message.starts_with("Loading models... (")));
+    }
+
+    #[test]
+    fn output_selection_strategies() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        for (name, size, age_secs) in [("b.png", 30, 0), ("a.png", 10, 20), ("c.png", 50, 10)] {
+            let path = dir.path().join(name);
+            fs::write(&path, vec![0u8; size]).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age_secs))
+                .unwrap();
+        }
+
+        let pick = |strategy| {
+            select_output_file(dir.path(), strategy, |_| true)
+                .unwrap()
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        };
+        assert_eq!(pick(SelectionStrategy::Newest), "b.png");
+        assert_eq!(pick(SelectionStrategy::Largest), "c.png");
+        assert_eq!(pick(SelectionStrategy::ByName), "a.png");
+
+        let none = select_output_file(dir.path(), SelectionStrategy::Largest, |_| false).unwrap();
+        assert_eq!(none, None);
+    }
+
+    #[test]
+    fn stop_on_error_kills_the_process_at_the_first_failure() {
+        let cmd = python_snippet(
+            "import time\nprint('Now you are processing a.png', flush=True)\n\
+             print('Skip a.png due to an error: boom', flush=True)\ntime.sleep(30)",
+        );
+        let started = Instant::now();
+        let options = ProcessOptions {
+            stop_on_error: true,
+            ..Default::default()
+        };
+        let err = run_pipeline_process(cmd, "r", options, &|_| {})
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("a.png"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn monitor_proc_reports_samples_without_messages() {
+        let events = Mutex::new(Vec::new());
+        let options = ProcessOptions {
+            monitor_proc: true,
+            ..Default::default()
+        };
+        let cmd = python_snippet("import time\ntime.sleep(2.5)");
+        run_pipeline_process(cmd, "r", options, &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+
+        let events = events.into_inner().unwrap();
+        let samples: Vec<_> = events.iter().filter(|e| e.proc_stats.is_some()).collect();
+        assert!(!samples.is_empty());
+        assert!(samples.iter().all(|e| e.message.is_empty()));
+        assert!(samples[0].proc_stats.as_ref().unwrap().rss_bytes > 0);
+    }
+
+    #[test]
+    fn multi_page_tiff_is_split_and_recombined() {
+        let root = stub_project();
+        let input = root.path().join("scan.tif");
+        let pages: Vec<_> = [10u8, 120, 240]
+            .iter()
+            .map(|&v| {
+                image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+                    8,
+                    6,
+                    image::Rgb([v; 3]),
+                ))
+            })
+            .collect();
+        imaging::write_multipage_tiff(&input, &pages).unwrap();
+        assert_eq!(imaging::read_tiff_pages(&input).unwrap().len(), 3);
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "splitTiffPages": true, "recombineTiff": true }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        let result = result.unwrap();
+        assert!(result.output_path.ends_with("scan_restored.tif"));
+        let restored = imaging::read_tiff_pages(Path::new(&result.output_path)).unwrap();
+        assert_eq!(restored.len(), 3);
+        assert_eq!(restored[2].to_rgb8().get_pixel(0, 0), &image::Rgb([240; 3]));
+        assert!(events.iter().any(|e| e.message == "Extracted page 3/3"));
+    }
+
+    #[test]
+    fn output_folder_that_is_a_file_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("photo.png");
+        write_png(&file);
+        assert!(check_output_folder(dir.path()).is_ok());
+        assert!(check_output_folder(&dir.path().join("not_yet")).is_ok());
+
+        let err = check_output_folder(&file).unwrap_err();
+        assert!(
+            err.starts_with("Output path is a file, not a folder"),
+            "{err}"
+        );
+
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("link");
+            std::os::unix::fs::symlink(&file, &link).unwrap();
+            assert!(check_output_folder(&link).is_err());
+        }
+    }
+
+    #[test]
+    fn reads_are_limited_to_output_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        fs::create_dir_all(&output).unwrap();
+        let inside = output.join("a.png");
+        let outside = dir.path().join("b.png");
+        write_png(&inside);
+        write_png(&outside);
+        let roots = HashSet::from([output.clone()]);
+
+        assert!(is_within_roots(&inside, &roots));
+        assert!(!is_within_roots(&outside, &roots));
+        assert!(!is_within_roots(&output.join("..").join("b.png"), &roots));
+    }
+
+    #[test]
+    fn isolated_runs_get_their_own_folder() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+
+        let first = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "runId": "one", "isolateRuns": true }),
+        );
+        let second = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "runId": "../two", "isolateRuns": true }),
+        );
+        let first_out = collect_events(root.path(), &first).0.unwrap().output_path;
+        let second_out = collect_events(root.path(), &second).0.unwrap().output_path;
+
+        let out = root.path().join("out");
+        assert!(Path::new(&first_out).starts_with(out.join("one")));
+        assert!(Path::new(&second_out).starts_with(out.join("___two")));
+        assert!(Path::new(&first_out).exists());
+        assert_eq!(
+            resolve_args(root.path(), &first).output_folder,
+            Some(out.to_string_lossy().to_string())
+        );
+    }
+
+    #[test]
+    fn unsupported_face_flags_are_dropped() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let supported = options::probe(root.path(), "python3").unwrap();
+        assert!(supported.contains(&"--GPU".to_string()));
+        assert!(!supported.contains(&"--help".to_string()));
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "maxFaces": 2, "faceUpsample": 1 }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        assert!(result.is_ok());
+        assert!(events
+            .iter()
+            .any(|e| e.message == "run.py does not support --max_faces; ignoring it"));
+
+        let bad = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "faceUpsample": 9 }),
+        );
+        assert!(collect_events(root.path(), &bad).0.is_err());
+    }
+
+    #[test]
+    fn help_text_lists_long_options_only() {
+        let help = "usage: run.py [-h] [--input_folder INPUT_FOLDER] [--GPU GPU]\n\
+                    \n\
+                    options:\n\
+                    \x20 -h, --help            show this help message and exit\n\
+                    \x20 --input_folder INPUT_FOLDER\n\
+                    \x20                       Test images\n\
+                    \x20 --GPU GPU             0,1,2\n\
+                    \x20 -m {a,b}, --mode {a,b}\n\
+                    \x20 --with_scratch\n";
+        assert_eq!(
+            options::parse_help(help),
+            ["--input_folder", "--GPU", "--mode", "--with_scratch"]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_truncated() {
+        let mut lines = Vec::new();
+        let input = format!("{}\r\nshort\nlast", "x".repeat(MAX_LINE_BYTES * 3));
+        for_each_capped_line(input.as_bytes(), |line, _| lines.push(line));
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].len(), MAX_LINE_BYTES + TRUNCATED_MARKER.len());
+        assert!(lines[0].ends_with(TRUNCATED_MARKER));
+        assert_eq!(lines[1], "short");
+        assert_eq!(lines[2], "last");
+    }
+
+    #[test]
+    #[cfg(feature = "zip-archives")]
+    fn zip_entries_outside_the_staging_folder_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("photo.png");
+        write_png(&png);
+        let bytes = fs::read(&png).unwrap();
+
+        let zip_path = dir.path().join("photos.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        for name in [
+            "album/a.png",
+            "../evil.png",
+            "/abs.png",
+            "notes.txt",
+            "b.png",
+        ] {
+            writer
+                .start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(&bytes).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let dest = dir.path().join("staging");
+        fs::create_dir_all(&dest).unwrap();
+        let mut unsafe_entries = Vec::new();
+        let staged = archive::extract_images(
+            &zip_path,
+            &dest,
+            |_, _, _| {},
+            |name| unsafe_entries.push(name.to_string()),
+        )
+        .unwrap();
+        let mut names: Vec<_> = staged.keys().cloned().collect();
+        names.sort();
+        // A leading `/` is stripped, which keeps the entry inside the staging folder.
+        assert_eq!(names, ["abs.png", "album__a.png", "b.png"]);
+        assert_eq!(unsafe_entries, ["../evil.png"]);
+        assert!(dest.join("abs.png").exists());
+        assert!(!dir.path().join("evil.png").exists());
+    }
+
+    #[test]
+    fn failing_test_image_stops_the_batch() {
+        let root = stub_project();
+        let input = root.path().join("photos");
+        fs::create_dir_all(&input).unwrap();
+        write_png(&input.join("bad.png"));
+        write_png(&input.join("good.png"));
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "testFirst": true }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        let err = result.unwrap_err().to_string();
+        assert!(err.starts_with("Test run on bad.png failed"), "{err}");
+        assert!(events.iter().any(|e| e.message.starts_with("[test run] ")));
+        assert!(!events
+            .iter()
+            .any(|e| e.message.contains("starting the full batch")));
+
+        fs::remove_file(input.join("bad.png")).unwrap();
+        let (result, events) = collect_events(root.path(), &args);
+        assert!(result.is_ok());
+        assert!(events
+            .iter()
+            .any(|e| e.message == "Test run on good.png passed; starting the full batch"));
+    }
+
+    #[test]
+    fn run_outputs_are_paired_and_scored() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a_dir, b_dir) = (dir.path().join("a"), dir.path().join("b"));
+        fs::create_dir_all(&a_dir).unwrap();
+        fs::create_dir_all(&b_dir).unwrap();
+        write_png(&a_dir.join("same.png"));
+        write_png(&b_dir.join("same.jpg"));
+        write_png(&a_dir.join("only_a.png"));
+        let mut noisy = image::RgbImage::from_pixel(32, 32, image::Rgb([120, 80, 40]));
+        noisy.put_pixel(3, 3, image::Rgb([255, 255, 255]));
+        write_png(&a_dir.join("diff.png"));
+        noisy.save(b_dir.join("diff.png")).unwrap();
+
+        let outputs = |dir: &Path| {
+            list_input_files(dir)
+                .unwrap()
+                .into_iter()
+                .map(|p| (p.file_stem().unwrap().to_string_lossy().to_string(), p))
+                .collect::<HashMap<_, _>>()
+        };
+        let pairs = compare_outputs(&outputs(&a_dir), &outputs(&b_dir)).unwrap();
+        let names: Vec<_> = pairs.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["diff", "only_a", "same"]);
+
+        assert!(pairs[1].path_b.is_none() && pairs[1].psnr.is_none());
+        assert!(pairs[2].psnr.unwrap() > 40.0);
+        assert!(pairs[0].psnr.unwrap() < pairs[2].psnr.unwrap());
+        assert!(pairs[0].ssim.unwrap() < 1.0);
+    }
+
+    #[test]
+    fn name_filters_limit_staged_files() {
+        let root = stub_project();
+        let input = root.path().join("photos");
+        fs::create_dir_all(&input).unwrap();
+        for name in ["a.JPG", "b.jpg", "c.png", "b_draft.jpg"] {
+            write_png(&input.join(name));
+        }
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "includeGlob": "*.jpg", "excludeGlob": "*_draft*" }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        let mut sources: Vec<_> = result.unwrap().source_paths.into_values().collect();
+        sources.sort();
+        assert_eq!(
+            sources,
+            [input.join("a.JPG"), input.join("b.jpg")].map(|p| p.to_string_lossy().to_string())
+        );
+        assert!(events.iter().any(|e| e.message.starts_with(
+            "Filters removed 1 file(s) not matching include_glob and 1 matching exclude_glob"
+        )));
+
+        let bad = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "includeGlob": "[" }),
+        );
+        assert!(collect_events(root.path(), &bad)
+            .0
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid include_glob"));
+    }
+
+    #[test]
+    fn heartbeat_reports_elapsed_time_while_running() {
+        let events = Mutex::new(Vec::new());
+        let cmd = python_snippet("import time; time.sleep(5.5)");
+        run_pipeline_process(cmd, "r", ProcessOptions::default(), &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+
+        let events = events.into_inner().unwrap();
+        let beats: Vec<_> = events.iter().filter_map(|e| e.elapsed_secs).collect();
+        assert_eq!(beats, [5]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_hook_runs_only_when_allowed_and_never_fails_the_run() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+
+        let hook = "echo hooked; exit 2";
+        let blocked = stub_args(root.path(), &input, serde_json::json!({ "postHook": hook }));
+        let (result, events) = collect_events(root.path(), &blocked);
+        assert!(result.unwrap().hook_error.is_none());
+        assert!(!events.iter().any(|e| e.message == "hooked"));
+
+        let allowed = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "postHook": "echo hooked", "allowPostHook": true }),
+        );
+        let (result, events) = collect_events(root.path(), &allowed);
+        let out = root.path().join("out").to_string_lossy().to_string();
+        assert!(events
+            .iter()
+            .any(|e| e.hook && !e.is_error && e.message == format!("hooked {out}")));
+        assert!(result.unwrap().hook_error.is_none());
+
+        let failing = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "postHook": "false", "allowPostHook": true }),
+        );
+        let result = collect_events(root.path(), &failing).0.unwrap();
+        assert!(result.hook_error.unwrap().contains("post_hook exited"));
+    }
+
+    #[test]
+    fn cancelled_run_stops_with_the_reason() {
+        let root = stub_project();
+        fs::write(
+            root.path().join("run.py"),
+            "import time\nprint('Running Stage 1: x', flush=True)\ntime.sleep(30)\n",
+        )
+        .unwrap();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "runId": "cancel-me" }),
+        );
+
+        let _guard = cancel::register("cancel-me");
+        let canceller = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(500));
+            assert_eq!(
+                cancel::cancel("cancel-me", Some("switching GPU".to_string())),
+                Some(cancel::CancelledState::Running)
+            );
+        });
+        let started = Instant::now();
+        let (result, events) = collect_events(root.path(), &args);
+        canceller.join().unwrap();
+
+        assert_eq!(
+            result.unwrap_err(),
+            ModifyError::Cancelled("Cancelled: switching GPU".to_string())
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(events.last().unwrap().message, "Cancelled: switching GPU");
+        assert_eq!(cancel::cancel("no-such-run", None), None);
+    }
+
+    #[test]
+    fn mislabeled_inputs_get_the_right_extension() {
+        let root = stub_project();
+        let input = root.path().join("photos");
+        fs::create_dir_all(&input).unwrap();
+        write_png(&input.join("real.png"));
+        fs::copy(input.join("real.png"), input.join("fake.jpg")).unwrap();
+        fs::copy(input.join("real.png"), input.join("noext")).unwrap();
+        assert_eq!(sniffed_name(&input.join("real.png")), None);
+
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let (result, events) = collect_events(root.path(), &args);
+        result.unwrap();
+        let final_dir = root.path().join("out/final_output");
+        assert!(final_dir.join("fake.png").exists());
+        assert!(final_dir.join("noext.png").exists());
+        assert!(events
+            .iter()
+            .any(|e| e.message == "fake.jpg is really fake.png; renamed for processing"));
+        // The original folder is left untouched.
+        assert!(input.join("fake.jpg").exists());
+    }
+
+    #[test]
+    fn gpu_slot_is_released_on_failure_and_cancel() {
+        static SLOT: gpu::GpuSemaphore = gpu::GpuSemaphore::new();
+        let options = ProcessOptions {
+            gpu_slot: Some(&SLOT),
+            ..Default::default()
+        };
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "exit 3"]);
+        assert!(run_pipeline_process(cmd, "r", options.clone(), &|_| {}).is_err());
+        assert!(!SLOT.is_busy());
+
+        let held = SLOT.acquire(|| None).unwrap();
+        let _guard = cancel::register("gpu-wait");
+        let waiting = ProcessOptions {
+            cancel: cancel::token("gpu-wait"),
+            ..options
+        };
+        let waiter = thread::spawn(move || {
+            let events = Mutex::new(Vec::new());
+            let result = run_pipeline_process(Command::new("true"), "r", waiting, &|e| {
+                events.lock().unwrap().push(e.message)
+            });
+            (result, events.into_inner().unwrap())
+        });
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            cancel::cancel("gpu-wait", None),
+            Some(cancel::CancelledState::Queued)
+        );
+        let (result, events) = waiter.join().unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "Cancelled by user");
+        assert!(events[0].starts_with("Waiting for another job"));
+        assert_eq!(
+            events.last().unwrap(),
+            "Cancelled by user while waiting for the GPU; nothing was started"
+        );
+
+        drop(held);
+        assert!(SLOT.acquire(|| None).is_ok());
+    }
+
+    #[test]
+    fn no_faces_warnings_are_reported_once_per_file() {
+        let events = Mutex::new(Vec::new());
+        let cmd = python_snippet(
+            "for name in ['a.png', 'a.png', 'b.png']: print('Warning: There is no face in ' + name)",
+        );
+        let no_faces = run_pipeline_process(cmd, "r", ProcessOptions::default(), &|event| {
+            events.lock().unwrap().push(event.message)
+        })
+        .unwrap()
+        .no_faces;
+
+        assert_eq!(no_faces, ["a.png", "b.png"]);
+        let notes: Vec<_> = events
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .filter(|m| m.starts_with("No faces detected"))
+            .collect();
+        assert_eq!(
+            notes,
+            [
+                "No faces detected in a.png \u{2014} face enhancement skipped for this image",
+                "No faces detected in b.png \u{2014} face enhancement skipped for this image",
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_chunk_keeps_earlier_chunks() {
+        let root = stub_project();
+        let input = root.path().join("photos");
+        fs::create_dir(&input).unwrap();
+        for name in ["a.png", "b.png", "bad.png"] {
+            write_png(&input.join(name));
+        }
+        let args = stub_args(root.path(), &input, serde_json::json!({ "chunkSize": 2 }));
+        let (result, events) = collect_events(root.path(), &args);
+
+        let err = result.unwrap_err().to_string();
+        assert!(err.starts_with("Chunk 2/2 (files 3-3) failed"), "{err}");
+        assert!(events
+            .iter()
+            .any(|e| e.message == "Chunk 1/2 finished (2/3 files)"));
+        let final_dir = root.path().join("out").join("final_output");
+        assert_eq!(
+            list_input_files(&final_dir).unwrap(),
+            [final_dir.join("a.png"), final_dir.join("b.png")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interpreter_paths_with_spaces_and_unicode_are_checked() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("Program Files").join("Pyth\u{f6}n 3");
+        fs::create_dir_all(&folder).unwrap();
+        let python = folder.join("python");
+        fs::write(&python, "#!/bin/sh\n").unwrap();
+        let python = python.to_string_lossy().to_string();
+
+        fs::set_permissions(&python, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            check_interpreter(&python).unwrap_err(),
+            format!("Python interpreter is not executable: \"{python}\"")
+        );
+        fs::set_permissions(&python, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(check_interpreter(&python).is_ok());
+
+        let typo = folder.join("pyhton").to_string_lossy().to_string();
+        assert_eq!(
+            check_interpreter(&typo).unwrap_err(),
+            format!("Python interpreter not found: \"{typo}\"")
+        );
+        assert!(check_interpreter("python3").is_ok());
+        assert!(check_interpreter("no-such-python-\u{e9}")
+            .unwrap_err()
+            .contains("\"no-such-python-\u{e9}\" was not found on PATH"));
+    }
+
+    #[test]
+    fn missing_models_are_downloaded() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(
+            models::missing_assets(root.path()).len(),
+            models::ASSETS.len()
+        );
+
+        let source = root.path().join("weights.bin.bz2");
+        let status = Command::new("python3")
+            .arg("-c")
+            .arg("import bz2, sys; open(sys.argv[1], 'wb').write(bz2.compress(b'w' * 4096))")
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let url = format!("file://{}", source.display());
+        let asset = models::Asset {
+            name: "weights",
+            required: &["weights.bin"],
+            kind: "bz2",
+            url: Box::leak(url.into_boxed_str()),
+            target: "weights.bin",
+        };
+
+        let events = Mutex::new(Vec::new());
+        models::download(root.path(), "python3", &asset, &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read(root.path().join("weights.bin")).unwrap(),
+            [b'w'; 4096]
+        );
+        let events = events.into_inner().unwrap();
+        assert!(events.iter().any(|e| e.percent == Some(100)));
+        assert!(events.iter().all(|e| !e.is_error));
+
+        let broken = models::Asset {
+            url: "file:///no/such/file.bz2",
+            ..asset
+        };
+        let err = models::download(root.path(), "python3", &broken, &|_| {}).unwrap_err();
+        assert!(err.starts_with("Failed to download weights"), "{err}");
+    }
+
+    #[test]
+    fn force_grayscale_rewrites_outputs() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        image::RgbImage::from_pixel(8, 8, image::Rgb([200, 40, 40]))
+            .save(&input)
+            .unwrap();
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "forceGrayscale": true, "convertSrgb": true }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+
+        let output = image::open(result.unwrap().output_path).unwrap();
+        assert_eq!(output.color(), image::ColorType::L8);
+        assert!(events
+            .iter()
+            .any(|e| e.message == "Post-processed 1/1: photo_restored.png"));
+    }
+
+    #[test]
+    fn test_events_cover_all_four_stages() {
+        let stages: Vec<_> = (0..8).map(|i| test_event_stage(i, 8)).collect();
+        assert_eq!(stages, [1, 1, 2, 2, 3, 3, 4, 4]);
+        assert_eq!(test_event_stage(0, 1), 1);
+        assert_eq!(test_event_stage(2, 3), 3);
+    }
+
+    #[test]
+    fn gpu_run_slots_are_capped_and_released() {
+        set_concurrency(2).unwrap();
+        let first = GpuRunSlot::acquire().unwrap();
+        let second = GpuRunSlot::acquire().unwrap();
+        assert_eq!(
+            GpuRunSlot::acquire().err().unwrap(),
+            "Too many concurrent runs; cap is 2"
+        );
+        drop(first);
+        let third = GpuRunSlot::acquire().unwrap();
+        drop((second, third));
+        assert_eq!(GPU_RUNS.load(Ordering::SeqCst), 0);
+
+        assert!(set_concurrency(0).is_err());
+        set_concurrency(DEFAULT_MAX_CONCURRENT).unwrap();
+    }
+
+    #[test]
+    fn working_dir_overrides_the_run_py_cwd() {
+        let root = stub_project();
+        let script = format!("{STUB_RUN_PY}print('cwd=' + os.getcwd())\n");
+        fs::write(root.path().join("run.py"), script).unwrap();
+        fs::create_dir(root.path().join("fork")).unwrap();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "workingDir": "fork" }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        assert!(result.is_ok());
+        let expected = format!("cwd={}", root.path().join("fork").display());
+        assert!(events.iter().any(|e| e.message == expected));
+
+        let missing = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "workingDir": "nope" }),
+        );
+        let err = collect_events(root.path(), &missing)
+            .0
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Working directory not found"), "{err}");
+    }
+
+    #[test]
+    fn download_stall_is_reported_once_until_bytes_arrive() {
+        let start = Instant::now();
+        let limit = Duration::from_secs(30);
+        let mut watch = models::StallWatch::new(start);
+        assert!(!watch.check(start + Duration::from_secs(29), limit));
+
+        watch.progress(1024, start + Duration::from_secs(10));
+        assert!(!watch.check(start + Duration::from_secs(39), limit));
+        assert!(watch.check(start + Duration::from_secs(40), limit));
+        assert!(!watch.check(start + Duration::from_secs(50), limit));
+
+        // The same byte count again is not progress.
+        watch.progress(1024, start + Duration::from_secs(60));
+        assert!(!watch.check(start + Duration::from_secs(70), limit));
+        watch.progress(2048, start + Duration::from_secs(80));
+        assert!(watch.check(start + Duration::from_secs(110), limit));
+    }
+
+    #[test]
+    fn contact_sheets_paginate_with_captions() {
+        let dir = tempfile::tempdir().unwrap();
+        let columns = 3;
+        let count = columns as usize * imaging::SHEET_ROWS + 1;
+        let paths: Vec<PathBuf> = (0..count)
+            .map(|i| {
+                let path = dir.path().join(format!("photo_{i}.png"));
+                write_png(&path);
+                path
+            })
+            .collect();
+
+        let sheets = imaging::contact_sheets(&paths, columns, dir.path(), "sheet").unwrap();
+        assert_eq!(
+            sheets,
+            [
+                dir.path().join("sheet_1.png"),
+                dir.path().join("sheet_2.png")
+            ]
+        );
+        let first = image::open(&sheets[0]).unwrap();
+        let last = image::open(&sheets[1]).unwrap().to_rgb8();
+        assert_eq!(first.width(), last.width());
+        assert!(last.height() < first.height());
+
+        // The caption below the single cell on the last page has light pixels.
+        let caption_row = 8 + 192 + 4;
+        let lit = (0..last.width())
+            .flat_map(|x| (caption_row..caption_row + 14).map(move |y| (x, y)))
+            .filter(|&(x, y)| last.get_pixel(x, y)[0] > 200)
+            .count();
+        assert!(lit > 0);
+    }
+
+    #[test]
+    fn moved_output_is_relocated_in_history_and_manifest() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let result = collect_events(root.path(), &args).0.unwrap();
+        let record = RunRecord {
+            run_id: "test-run".to_string(),
+            started_at: 0,
+            finished_at: 0,
+            success: true,
+            cancelled: false,
+            stage_reached: Some(4),
+            stage_durations: Vec::new(),
+            output_path: Some(result.output_path.clone()),
+            error: None,
+            args,
+        };
+        history::append(root.path(), record).unwrap();
+
+        let dst = root.path().join("keep.png");
+        let new = dst.to_string_lossy().to_string();
+        move_file(Path::new(&result.output_path), &dst).unwrap();
+        assert!(move_file(&dst, &dst).is_err());
+        assert_eq!(
+            history::replace_output_path(root.path(), &result.output_path, &new).unwrap(),
+            1
+        );
+        let manifest = PathBuf::from(result.manifest_path.unwrap());
+        assert!(manifest::relocate(&manifest, &result.output_path, &new).unwrap());
+
+        assert!(!Path::new(&result.output_path).exists());
+        let record = history::find(root.path(), "test-run").unwrap();
+        assert_eq!(record.output_path.as_deref(), Some(new.as_str()));
+        let text = fs::read_to_string(manifest).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert!(manifest["outputs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["path"] == new.as_str()));
+    }
+
+    #[test]
+    fn keep_original_copy_saves_the_input_outside_final_output() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        image::RgbImage::from_pixel(8, 8, image::Rgb([1, 2, 3]))
+            .save(&input)
+            .unwrap();
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "keepOriginalCopy": true }),
+        );
+        let result = collect_events(root.path(), &args).0.unwrap();
+
+        let copy = root
+            .path()
+            .join("out")
+            .join(ORIGINALS_DIR)
+            .join("photo_restored_original.png");
+        assert_eq!(
+            result.original_copies[&result.output_path],
+            copy.to_string_lossy()
+        );
+        assert_eq!(fs::read(copy).unwrap(), fs::read(&input).unwrap());
+
+        // A follow-up batch into the same folder only sees restored images.
+        let folder = root.path().join("batch");
+        fs::create_dir(&folder).unwrap();
+        write_png(&folder.join("a.png"));
+        write_png(&folder.join("b.png"));
+        let args = stub_args(
+            root.path(),
+            &folder,
+            serde_json::json!({ "keepOriginalCopy": true, "clearPrevious": false }),
+        );
+        let result = collect_events(root.path(), &args).0.unwrap();
+        let final_dir = root.path().join("out/final_output");
+        let mut listed: Vec<_> = list_input_files(&final_dir)
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        listed.sort();
+        assert_eq!(listed, ["a.png", "b.png", "photo_restored.png"]);
+        assert_eq!(result.outputs.len(), 3);
+        assert_eq!(result.original_copies.len(), 2);
+        assert_eq!(result.stage_counts[&4], 3);
+    }
+
+    #[test]
+    fn already_processed_lines_are_reported_as_skipped() {
+        assert_eq!(
+            skipped_file_from_line("Skipping a.png: already processed"),
+            Some("a.png")
+        );
+        assert_eq!(
+            skipped_file_from_line("'b.JPG' already exists, skipping"),
+            Some("b.JPG")
+        );
+        assert_eq!(skipped_file_from_line("Skip c.png due to an error"), None);
+
+        let events = Mutex::new(Vec::new());
+        let cmd = python_snippet("print('already processed, skipping old.png')");
+        let report = run_pipeline_process(cmd, "r", ProcessOptions::default(), &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+        assert_eq!(report.skipped, ["old.png"]);
+        let events = events.into_inner().unwrap();
+        let skipped: Vec<_> = events.iter().filter(|e| e.skipped).collect();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].message, "Skipped old.png: already processed");
+        assert!(!skipped[0].is_error);
+    }
+
+    #[test]
+    fn throttle_drops_only_log_lines_and_counts_them() {
+        let throttle = throttle::EventThrottle::new();
+        let sent = Mutex::new(Vec::new());
+        let send = |event: ProgressEvent| sent.lock().unwrap().push(event);
+        let line = |stage: u8, message: &str, is_error: bool| ProgressEvent {
+            run_id: "r".to_string(),
+            stage: Some(stage),
+            message: message.to_string(),
+            is_error,
+            ..Default::default()
+        };
+
+        throttle.submit(line(1, "Running Stage 1", false), send);
+        for i in 0..1000 {
+            throttle.submit(line(1, &format!("line {i}"), false), send);
+        }
+        throttle.submit(line(1, "Traceback", true), send);
+        throttle.submit(line(2, "Running Stage 2", false), send);
+        throttle.finish("r", send);
+
+        let sent = sent.into_inner().unwrap();
+        let lines = sent
+            .iter()
+            .filter(|e| e.message.starts_with("line "))
+            .count();
+        let omitted: usize = sent.iter().filter_map(|e| e.omitted).sum();
+        assert!(lines < 1000);
+        assert_eq!(lines + omitted, 1000);
+        assert!(sent
+            .iter()
+            .any(|e| e.message == format!("({omitted} log lines omitted)")));
+
+        let order: Vec<_> = sent
+            .iter()
+            .filter(|e| !e.message.starts_with("line ") && e.omitted.is_none())
+            .map(|e| e.message.as_str())
+            .collect();
+        assert_eq!(order, ["Running Stage 1", "Traceback", "Running Stage 2"]);
+        // The last lines before the error are kept, the oldest dropped.
+        let traceback = sent.iter().position(|e| e.is_error).unwrap();
+        assert_eq!(sent[traceback - 2].message, "line 999");
+    }
+
+    #[test]
+    fn max_output_edge_downscales_only_large_outputs() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        image::RgbImage::new(40, 20).save(&input).unwrap();
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "maxOutputEdge": 100 }),
+        );
+        let result = collect_events(root.path(), &args).0.unwrap();
+        assert!(result.resized.is_empty());
+        assert_eq!(
+            image::image_dimensions(&result.output_path).unwrap(),
+            (40, 20)
+        );
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "maxOutputEdge": 10 }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        let result = result.unwrap();
+        assert_eq!(result.resized, std::slice::from_ref(&result.output_path));
+        assert_eq!(
+            image::image_dimensions(&result.output_path).unwrap(),
+            (10, 5)
+        );
+        assert!(events
+            .iter()
+            .any(|e| e.message == "Post-processed 1/1: photo_restored.png (resized to fit 10px)"));
+    }
+
+    #[test]
+    fn thumbnail_folder_caches_in_name_order() {
+        let dir = tempfile::tempdir().unwrap();
+        image::RgbImage::new(40, 20)
+            .save(dir.path().join("b.png"))
+            .unwrap();
+        image::RgbImage::new(8, 8)
+            .save(dir.path().join("a.jpg"))
+            .unwrap();
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let thumbs = imaging::thumbnail_folder(dir.path(), 10).unwrap();
+        let thumbs_dir = dir.path().join(imaging::THUMBS_DIR);
+        assert_eq!(thumbs, [thumbs_dir.join("a.jpg"), thumbs_dir.join("b.png")]);
+        assert_eq!(image::image_dimensions(&thumbs[0]).unwrap(), (8, 8));
+        assert_eq!(image::image_dimensions(&thumbs[1]).unwrap(), (10, 5));
+
+        // An up-to-date thumbnail is not regenerated.
+        fs::write(&thumbs[1], "cached").unwrap();
+        imaging::thumbnail_folder(dir.path(), 10).unwrap();
+        assert_eq!(fs::read_to_string(&thumbs[1]).unwrap(), "cached");
+    }
+
+    #[test]
+    fn history_is_written_when_a_run_panics() {
+        let root = tempfile::tempdir().unwrap();
+        let input = root.path().join("photo.png");
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "runId": "panics" }),
+        );
+
+        let outcome = std::panic::catch_unwind(|| {
+            let record = history::RecordGuard::start(root.path(), args);
+            record.reached_stage(2);
+            panic!("stage 2 blew up");
+        });
+        assert!(outcome.is_err());
+
+        let record = history::find(root.path(), "panics").unwrap();
+        assert!(!record.success && !record.cancelled);
+        assert_eq!(record.stage_reached, Some(2));
+        assert_eq!(
+            record.error.as_deref(),
+            Some("The run crashed unexpectedly")
+        );
+    }
+
+    #[test]
+    fn history_marks_cancelled_runs() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "runId": "cancel-early" }),
+        );
+
+        let _cancel = cancel::register("cancel-early");
+        cancel::cancel("cancel-early", None);
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        let (result, _) = collect_events(root.path(), &args);
+        record.finish(&result);
+
+        let record = history::find(root.path(), "cancel-early").unwrap();
+        assert!(!record.success && record.cancelled);
+        assert_eq!(record.error.as_deref(), Some("Cancelled by user"));
+    }
+
+    #[test]
+    fn config_file_presets_args() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("nightly.toml");
+        fs::write(
+            &config,
+            "inputPath = \"scans\"\ngpu = \"-1\"\nwithScratch = true\nhr = false\npython = \"python3\"\n",
+        )
+        .unwrap();
+        let args = args_from_config(&config).unwrap();
+        assert!(args.run_id.starts_with("nightly_"));
+        assert_eq!(Path::new(&args.input_path), dir.path().join("scans"));
+        assert!(args.with_scratch && args.clear_previous);
+
+        fs::write(&config, "inputPath = \"scans\"\nhr = \n").unwrap();
+        let err = args_from_config(&config).unwrap_err();
+        assert!(err.contains("line 2, column"), "{err}");
+
+        fs::write(&config, "inputPath = \"scans\"\nhr = false\n").unwrap();
+        let err = args_from_config(&config).unwrap_err();
+        assert!(
+            err.ends_with("missing required field(s): gpu, withScratch, python"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn plan_lists_files_the_run_would_take() {
+        let root = tempfile::tempdir().unwrap();
+        let input = root.path().join("photos");
+        fs::create_dir_all(&input).unwrap();
+        for name in ["a.jpg", "b.png", "c.jpg", ".hidden.jpg"] {
+            fs::write(input.join(name), "").unwrap();
+        }
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "excludeGlob": "*.PNG" }),
+        );
+
+        let plan = plan_inputs(&args).unwrap();
+        let path = |name: &str| input.join(name).to_string_lossy().to_string();
+        assert_eq!(plan.to_process, [path("a.jpg"), path("c.jpg")]);
+        assert_eq!(plan.skipped, [path("b.png")]);
+        assert_eq!(plan.reasons[&path("b.png")], "matches exclude_glob");
+    }
+
+    #[test]
+    fn output_watch_reports_coarse_percent() {
+        let dir = tempfile::tempdir().unwrap();
+        write_png(&dir.path().join("earlier.png"));
+        let code = format!(
+            "import shutil, time\n\
+             time.sleep(1.5)\n\
+             for name in ['a.png', 'b.png']:\n    \
+                 shutil.copy({src:?}, {dir:?} + '/' + name)\n    \
+                 time.sleep(1.5)\n",
+            src = dir.path().join("earlier.png").to_string_lossy(),
+            dir = dir.path().to_string_lossy(),
+        );
+        let options = ProcessOptions {
+            watch_outputs: Some(OutputWatch::new(dir.path().to_path_buf(), 4)),
+            ..Default::default()
+        };
+        let events = Mutex::new(Vec::new());
+        run_pipeline_process(python_snippet(&code), "r", options, &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap();
+
+        let percents: Vec<_> = events
+            .into_inner()
+            .unwrap()
+            .iter()
+            .filter_map(|e| e.percent)
+            .collect();
+        assert_eq!(percents, [25, 50]);
+    }
+
+    #[test]
+    fn locked_input_copies_are_retried_then_reported() {
+        let src = Path::new("/photos/scan.tif");
+        let locked = || std::io::Error::other("locked");
+        let attempts = std::cell::Cell::new(0);
+        let copy = || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(locked())
+            } else {
+                Ok(1)
+            }
+        };
+        copy_retrying_locked(src, copy, |_| true).unwrap();
+        assert_eq!(attempts.get(), 3);
+
+        let err = copy_retrying_locked(src, || Err(locked()), |_| true).unwrap_err();
+        assert_eq!(
+            err,
+            "Input file is in use by another program: /photos/scan.tif"
+        );
+        let err = copy_retrying_locked(src, || Err(locked()), |_| false).unwrap_err();
+        assert_eq!(err, "Failed to copy /photos/scan.tif: locked");
+
+        let sharing_violation = std::io::Error::from_raw_os_error(32);
+        assert_eq!(is_file_locked(&sharing_violation), cfg!(windows));
+    }
+
+    #[test]
+    fn reprocessing_counts_and_limits_depth() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let mut args = stub_args(root.path(), &input, serde_json::json!({}));
+
+        for depth in 1..=MAX_REPROCESS_DEPTH {
+            let result = collect_events(root.path(), &args).0;
+            history::RecordGuard::start(root.path(), args.clone()).finish(&result);
+            let output = result.unwrap().output_path;
+
+            args = reprocess_args(root.path(), &output, args).unwrap();
+            assert_eq!(args.reprocess_depth, depth);
+            assert_eq!(args.input_path, output);
+            assert!(args.isolate_runs);
+            args.run_id = format!("pass-{depth}");
+        }
+        let result = collect_events(root.path(), &args).0;
+        history::RecordGuard::start(root.path(), args.clone()).finish(&result);
+        let result = result.unwrap();
+        assert_eq!(result.reprocess_depth, MAX_REPROCESS_DEPTH);
+
+        let err = reprocess_args(root.path(), &result.output_path, args).unwrap_err();
+        assert!(err.ends_with("the limit is 3"), "{err}");
+    }
+
+    #[test]
+    fn tracker_reports_the_device_run_py_bound_to() {
+        let events = Mutex::new(Vec::new());
+        let emit = |event: ProgressEvent| events.lock().unwrap().push(event);
+        let mut tracker = OutputTracker::new();
+        tracker.expected_device = Some("cuda:1".to_string());
+        tracker.cuda_visible_devices = Some("2,3".to_string());
+
+        for line in [
+            "running on GPU [1, 2]",
+            "running on GPU 1",
+            "The main GPU is ",
+            "0",
+        ] {
+            tracker.handle_line("r", false, line.to_string(), false, &emit);
+        }
+
+        let events = events.into_inner().unwrap();
+        let devices: Vec<_> = events
+            .iter()
+            .filter_map(|e| e.device_selected.as_deref())
+            .collect();
+        assert_eq!(devices, ["cuda:1", "cuda:0"]);
+        let warnings: Vec<_> = events
+            .iter()
+            .filter(|e| e.message.starts_with("Warning: run.py is using"))
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .message
+            .starts_with("Warning: run.py is using cuda:0, but cuda:1 was requested"));
+        assert!(warnings[0]
+            .message
+            .ends_with("(CUDA_VISIBLE_DEVICES=2,3 renumbers the GPUs it can see)"));
+
+        let mut args = stub_args(Path::new("/"), Path::new("a.png"), serde_json::json!({}));
+        args.env = Some(HashMap::from([(
+            "CUDA_VISIBLE_DEVICES".to_string(),
+            "1".to_string(),
+        )]));
+        let options = ProcessOptions::from_args(&args, &GpuSelection::Devices(vec![0]));
+        assert_eq!(options.cuda_visible_devices.as_deref(), Some("1"));
+        assert_eq!(device_from_line("running on CPU").as_deref(), Some("cpu"));
+    }
+
+    #[test]
+    fn denoise_smooths_grain_but_keeps_edges() {
+        // Left half dark, right half light, with +-10 grain on every other pixel.
+        let img = image::RgbImage::from_fn(16, 16, |x, y| {
+            let base: u8 = if x < 8 { 40 } else { 200 };
+            let grain = (x + y) % 2 == 0;
+            image::Rgb([if grain { base + 10 } else { base - 10 }; 3])
+        });
+        let out = imaging::denoise(&img, 5);
+
+        let spread = |img: &image::RgbImage, x: u32| {
+            let values: Vec<u8> = (0..16).map(|y| img.get_pixel(x, y)[0]).collect();
+            values.iter().max().unwrap() - values.iter().min().unwrap()
+        };
+        assert_eq!(spread(&img, 3), 20);
+        assert!(spread(&out, 3) <= 10);
+        assert!(out.get_pixel(7, 8)[0] < 60);
+        assert!(out.get_pixel(8, 8)[0] > 180);
+        // Out-of-range strengths are clamped, not rejected.
+        assert_eq!(imaging::denoise(&img, 0), imaging::denoise(&img, 1));
+    }
+
+    #[test]
+    fn diagnosis_lists_failed_checks_with_fixes() {
+        let root = stub_project();
+        let diagnosis = diagnose::run(root.path(), "no-such-python-3");
+        assert!(!diagnosis.ready);
+        let names: Vec<_> = diagnosis.checks.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "Python interpreter",
+                "Python packages",
+                "Model weights",
+                "CUDA GPUs"
+            ]
+        );
+        assert!(diagnosis
+            .checks
+            .iter()
+            .all(|c| !c.passed && c.fix.is_some()));
+        assert!(diagnosis.checks[1].detail.starts_with("Skipped"));
+        assert!(diagnosis.gpus.is_empty());
+    }
+
+    /// Serves `dir` over HTTP on a free local port; returns the server and its base URL.
+    fn serve_folder(dir: &Path) -> (Child, String) {
+        let mut server = Command::new("python3")
+            .args(["-u", "-m", "http.server", "0", "--bind", "127.0.0.1"])
+            .current_dir(dir)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut banner = String::new();
+        BufReader::new(server.stdout.take().unwrap())
+            .read_line(&mut banner)
+            .unwrap();
+        // "Serving HTTP on 127.0.0.1 port 43567 (http://127.0.0.1:43567/) ..."
+        let port = banner.split_whitespace().nth(5).unwrap().to_string();
+        (server, format!("http://127.0.0.1:{port}"))
+    }
+
+    #[test]
+    fn images_download_from_urls_and_other_content_is_refused() {
+        let served = tempfile::tempdir().unwrap();
+        write_png(&served.path().join("scan.png"));
+        fs::write(served.path().join("page.html"), "<html></html>").unwrap();
+        let (mut server, base) = serve_folder(served.path());
+
+        let downloads = tempfile::tempdir().unwrap();
+        let messages = Mutex::new(Vec::new());
+        let emit = |m: String| messages.lock().unwrap().push(m);
+        let saved = fetch::download_image(
+            "python3",
+            &format!("{base}/scan.png"),
+            downloads.path(),
+            &emit,
+        );
+        let html = fetch::download_image(
+            "python3",
+            &format!("{base}/page.html"),
+            downloads.path(),
+            &emit,
+        );
+        let missing = fetch::download_image(
+            "python3",
+            &format!("{base}/gone.png"),
+            downloads.path(),
+            &emit,
+        );
+        let _ = server.kill();
+        let _ = server.wait();
+
+        let saved = saved.unwrap();
+        assert_eq!(saved, downloads.path().join("scan.png"));
+        assert!(image::open(&saved).is_ok());
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m.starts_with("Downloaded ")));
+        assert!(html
+            .unwrap_err()
+            .ends_with("Not an image: the server sent text/html"));
+        assert!(missing
+            .unwrap_err()
+            .ends_with("The server answered 404 File not found"));
+        assert!(
+            fetch::download_image("python3", "file:///etc/passwd", downloads.path(), &emit)
+                .unwrap_err()
+                .starts_with("Not an http(s) URL")
+        );
+    }
+
+    #[test]
+    fn project_root_falls_back_to_the_folder_with_run_py() {
+        let root = stub_project();
+        let shallow = root.path().join("src-tauri");
+        assert_eq!(project_root_from(&shallow).unwrap(), root.path());
+        assert_eq!(
+            project_root_from(&root.path().join("ui/src-tauri")).unwrap(),
+            root.path()
+        );
+
+        let err = project_root_from(Path::new("/src-tauri")).unwrap_err();
+        assert!(err.contains("CARGO_MANIFEST_DIR /src-tauri"), "{err}");
+        assert!(project_root_from(Path::new("/a/ui/src-tauri")).is_ok());
+    }
+
+    #[test]
+    fn benchmark_times_repeated_samples() {
+        let root = stub_project();
+        let samples = root.path().join(SAMPLE_IMAGES_DIR);
+        fs::create_dir_all(&samples).unwrap();
+        write_png(&samples.join("a.png"));
+        write_png(&samples.join("b.png"));
+        let args = stub_args(
+            root.path(),
+            &samples,
+            serde_json::json!({ "runId": "bench" }),
+        );
+
+        let events = Mutex::new(Vec::new());
+        let result =
+            run_benchmark(root.path(), &args, 3, &|e| events.lock().unwrap().push(e)).unwrap();
+        assert_eq!(result.images, 3);
+        assert!(result.images_per_minute > 0.0);
+        let mut stages: Vec<_> = result.stage_secs_per_image.keys().copied().collect();
+        stages.sort();
+        assert_eq!(stages, [1, 2, 3, 4]);
+        assert_eq!(result.device.as_deref(), Some("cpu"));
+        assert_eq!(result.device_name, None);
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events[0].message, "Warming up on one image...");
+        let done: Vec<_> = events.iter().filter(|e| e.message == "Done").collect();
+        assert_eq!(done.len(), 2);
+        assert!(!std::env::temp_dir()
+            .join("old_photos_benchmark/bench")
+            .exists());
+        assert_eq!(
+            run_benchmark(root.path(), &args, 0, &|_| {}).unwrap_err(),
+            "sample_count must be at least 1"
+        );
+    }
+
+    #[test]
+    fn mps_is_probed_and_runs_run_py_without_cuda() {
+        let probe = gpu::DeviceProbe::parse(
+            "{\"index\": 0, \"name\": \"RTX\", \"freeBytes\": null}\n{\"mps\": true}\n",
+        )
+        .unwrap();
+        assert_eq!(probe.cuda.len(), 1);
+        assert!(probe.mps);
+        assert!(!gpu::DeviceProbe::parse("{\"mps\": false}").unwrap().mps);
+
+        let mps = GpuSelection::parse("MPS").unwrap();
+        assert_eq!(mps, GpuSelection::Mps);
+        assert_eq!(mps.to_arg().as_deref(), Some("-1"));
+        let devices = GpuSelection::parse("0").unwrap();
+        assert!(devices
+            .check_device_count(0, false)
+            .unwrap_err()
+            .contains("only CPU mode (-1) will work"));
+        assert!(devices
+            .check_device_count(0, true)
+            .unwrap_err()
+            .contains("select mps"));
+    }
+
+    #[test]
+    fn thumbnail_caches_are_removed_recursively() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = dir.path().join("run_1");
+        fs::create_dir_all(run.join(imaging::THUMBS_DIR)).unwrap();
+        fs::create_dir_all(dir.path().join(imaging::THUMBS_DIR)).unwrap();
+        fs::write(run.join(imaging::THUMBS_DIR).join("a.png"), [0u8; 300]).unwrap();
+        fs::write(
+            dir.path().join(imaging::THUMBS_DIR).join("b.png"),
+            [0u8; 20],
+        )
+        .unwrap();
+        fs::write(run.join("a.png"), [0u8; 1000]).unwrap();
+
+        assert_eq!(remove_thumbnail_caches(dir.path()).unwrap(), 320);
+        assert!(!run.join(imaging::THUMBS_DIR).exists());
+        assert!(!dir.path().join(imaging::THUMBS_DIR).exists());
+        assert!(run.join("a.png").exists());
+        assert_eq!(
+            remove_thumbnail_caches(&dir.path().join("missing")).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn cpu_threads_caps_the_math_library_threads() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({ "cpuThreads": 1 }));
+
+        let cmd = pipeline_command(
+            root.path(),
+            &args,
+            &GpuSelection::Cpu,
+            &[],
+            root.path(),
+            root.path(),
+        );
+        let envs: HashMap<_, _> = cmd.get_envs().collect();
+        assert_eq!(envs[OsStr::new("OMP_NUM_THREADS")], Some(OsStr::new("1")));
+        assert_eq!(envs[OsStr::new("MKL_NUM_THREADS")], Some(OsStr::new("1")));
+
+        let (result, events) = collect_events(root.path(), &args);
+        result.unwrap();
+        assert!(events
+            .iter()
+            .any(|e| e.message.starts_with("Limiting CPU threads to 1 of ")));
+
+        let args = stub_args(root.path(), &input, serde_json::json!({ "cpuThreads": 0 }));
+        let err = run_pipeline(root.path(), &args, |_| {})
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("cpu_threads must be between 1 and "),
+            "{err}"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "zip-archives")]
+    fn zip_outputs_packs_the_runs_final_images() {
+        let root = stub_project();
+        let input = root.path().join("photos");
+        fs::create_dir_all(&input).unwrap();
+        write_png(&input.join("a.png"));
+        write_png(&input.join("b.png"));
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "runId": "to-zip" }),
+        );
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        let (result, _) = collect_events(root.path(), &args);
+        record.finish(&result);
+        result.unwrap();
+
+        let dest = root.path().join("exports/run.zip");
+        let mut progress = Vec::new();
+        let archive_path = zip_run_outputs(root.path(), "to-zip", &dest, |i, total, name| {
+            progress.push(format!("{i}/{total} {name}"))
+        })
+        .unwrap();
+        assert_eq!(archive_path, dest);
+        assert_eq!(progress, ["1/2 a.png", "2/2 b.png"]);
+        let archive = zip::ZipArchive::new(fs::File::open(&dest).unwrap()).unwrap();
+        let mut names: Vec<_> = archive
+            .file_names()
+            .map(|n| n.unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.png", "b.png"]);
+
+        #[cfg(unix)]
+        {
+            let outside = root.path().join("secret.png");
+            write_png(&outside);
+            std::os::unix::fs::symlink(&outside, root.path().join("out/final_output/c.png"))
+                .unwrap();
+            let err = zip_run_outputs(root.path(), "to-zip", &dest, |_, _, _| {}).unwrap_err();
+            assert!(
+                err.starts_with("Output is outside the run's folder"),
+                "{err}"
+            );
+        }
+        let err = zip_run_outputs(root.path(), "unknown", &dest, |_, _, _| {}).unwrap_err();
+        assert_eq!(err, "No run with id unknown in history");
+    }
+
+    #[test]
+    fn carriage_returns_split_progress_bars_into_updates() {
+        let input = "\r 10%|#\r 50%|#####\r100%|##########\nStage done\r\nlast\r";
+        for capacity in [64, 1] {
+            let mut lines = Vec::new();
+            let reader = BufReader::with_capacity(capacity, input.as_bytes());
+            for_each_capped_line(reader, |line, in_place| lines.push((line, in_place)));
+            assert_eq!(
+                lines,
+                [
+                    (" 10%|#".to_string(), true),
+                    (" 50%|#####".to_string(), true),
+                    ("100%|##########".to_string(), false),
+                    ("Stage done".to_string(), false),
+                    ("last".to_string(), true),
+                ],
+                "read {capacity} bytes at a time"
+            );
+        }
+
+        // Bar updates beyond the rate limit collapse into the newest one.
+        let throttle = throttle::EventThrottle::new();
+        let sent = Mutex::new(Vec::new());
+        let send = |event: ProgressEvent| sent.lock().unwrap().push(event);
+        for i in 0..=100 {
+            throttle.submit(
+                ProgressEvent {
+                    run_id: "r".to_string(),
+                    stage: Some(1),
+                    message: format!("{i}%"),
+                    in_place: true,
+                    ..Default::default()
+                },
+                send,
+            );
+        }
+        throttle.finish("r", send);
+        let sent = sent.into_inner().unwrap();
+        assert!(sent.len() < 101);
+        assert_eq!(sent.last().unwrap().message, "100%");
+        assert!(sent.iter().all(|e| e.omitted.is_none()));
+    }
+
+    #[test]
+    fn gpu_settings_override_the_defaults() {
+        let root = tempfile::tempdir().unwrap();
+        let values = |value: serde_json::Value| value.as_object().unwrap().clone();
+        settings::store(
+            root.path(),
+            None,
+            values(serde_json::json!({ "hr": false, "withScratch": true })),
+        )
+        .unwrap();
+        settings::store(
+            root.path(),
+            Some(0),
+            values(serde_json::json!({ "hr": true })),
+        )
+        .unwrap();
+
+        let loaded = settings::load(root.path());
+        assert_eq!(
+            loaded.for_gpu(0),
+            values(serde_json::json!({ "hr": true, "withScratch": true }))
+        );
+        assert_eq!(loaded.for_gpu(1), loaded.defaults);
+
+        settings::store(root.path(), Some(0), serde_json::Map::new()).unwrap();
+        assert!(settings::load(root.path()).gpus.is_empty());
+    }
+
+    #[test]
+    fn stage_percent_is_read_from_counters() {
+        assert_eq!(stage_percent_from_line("0120/0500"), Some(24.0));
+        assert_eq!(
+            stage_percent_from_line("Processing image 3 of 12"),
+            Some(25.0)
+        );
+        assert_eq!(stage_percent_from_line("done 12 of 12."), Some(100.0));
+        assert_eq!(stage_percent_from_line("7/5"), Some(100.0));
+        assert_eq!(stage_percent_from_line("3/0"), None);
+        assert_eq!(stage_percent_from_line("one of many"), None);
+        assert_eq!(stage_percent_from_line("saved to out/final_output"), None);
+        assert_eq!(stage_percent_from_line("Running Stage 3"), None);
+    }
+
+    #[test]
+    fn folder_runs_return_every_output_with_its_input() {
+        let root = stub_project();
+        let input = root.path().join("photos");
+        fs::create_dir_all(&input).unwrap();
+        write_png(&input.join("grandpa.png"));
+        write_png(&input.join("grandma.png"));
+        // An output no input accounts for.
+        let extra = "shutil.copy(os.path.join(final_dir, names[0]), \
+                     os.path.join(final_dir, \"leftover.png\"))\n";
+        fs::write(root.path().join("run.py"), format!("{STUB_RUN_PY}{extra}")).unwrap();
+
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let result = collect_events(root.path(), &args).0.unwrap();
+        let pairs: Vec<_> = result
+            .outputs
+            .iter()
+            .map(|item| {
+                let name = |p: &str| {
+                    Path::new(p)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string()
+                };
+                (item.input.as_deref().map(name), name(&item.output))
+            })
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                (Some("grandma.png".to_string()), "grandma.png".to_string()),
+                (Some("grandpa.png".to_string()), "grandpa.png".to_string()),
+                (None, "leftover.png".to_string()),
+            ]
+        );
+
+        fs::write(root.path().join("run.py"), STUB_RUN_PY).unwrap();
+        let single = root.path().join("solo.png");
+        write_png(&single);
+        let args = stub_args(root.path(), &single, serde_json::json!({}));
+        let result = collect_events(root.path(), &args).0.unwrap();
+        assert_eq!(result.outputs.len(), 1);
+        assert_eq!(result.outputs[0].output, result.output_path);
+        assert_eq!(result.outputs[0].input.as_deref(), single.to_str());
+    }
+
+    #[test]
+    fn environment_check_names_the_missing_module() {
+        let environment = diagnose::check_environment("python3").unwrap();
+        assert!(environment.python_version.starts_with("3."));
+        assert_eq!(environment.modules.len(), KEY_PYTHON_MODULES.len());
 
-        let out_handle = thread::spawn(move || {
-            let reader = BufReader::new(stdout);
-            for line in reader.lines().flatten() {
-                let _ = tx_out.send((false, line));
+        match environment.missing().first() {
+            Some(module) => {
+                let err = diagnose::preflight("python3").unwrap_err();
+                assert!(err.contains(&format!("'{module}'")), "{err}");
             }
+            None => diagnose::preflight("python3").unwrap(),
+        }
+        assert!(diagnose::check_environment("no-such-python-3")
+            .unwrap_err()
+            .contains("no-such-python-3"));
+    }
+
+    #[test]
+    fn runs_in_the_same_folder_wait_their_turn() {
+        let queue = queue::FolderQueue::default();
+        let folder = Path::new("/out");
+        let first = queue.wait_turn(folder, || None, || panic!("nothing to wait for"));
+        // Another folder doesn't wait.
+        drop(queue.wait_turn(Path::new("/other"), || None, || panic!("other folder")));
+
+        let waited = std::sync::atomic::AtomicBool::new(false);
+        let err = queue
+            .wait_turn(
+                folder,
+                || Some("Cancelled by user".to_string()),
+                || waited.store(true, Ordering::SeqCst),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(err, "Cancelled by user");
+        assert!(waited.load(Ordering::SeqCst));
+
+        let order = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            let second = scope.spawn(|| {
+                let _turn = queue.wait_turn(folder, || None, || {}).unwrap();
+                order.lock().unwrap().push("second");
+            });
+            thread::sleep(Duration::from_millis(300));
+            order.lock().unwrap().push("first");
+            drop(first);
+            second.join().unwrap();
         });
-        let err_handle = thread::spawn(move || {
-            let reader = BufReader::new(stderr);
-            for line in reader.lines().flatten() {
-                let _ = tx_err.send((true, line));
+        assert_eq!(*order.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[test]
+    fn stage_durations_cover_every_stage() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let result = collect_events(root.path(), &args).0.unwrap();
+        let stages: Vec<_> = result.stage_durations.iter().map(|t| t.stage).collect();
+        assert_eq!(stages, [0, 1, 2, 3, 4]);
+        // Starting python takes measurable time.
+        assert!(result.stage_durations[0].millis > 0);
+
+        fs::write(
+            root.path().join("run.py"),
+            "import sys, time\nprint('Running Stage 1: x', flush=True)\n\
+             time.sleep(0.2)\nsys.exit(1)\n",
+        )
+        .unwrap();
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        let result = run_pipeline(root.path(), &args, |event| {
+            if let Some(stage) = event.stage {
+                record.reached_stage(stage);
             }
         });
-        drop(tx);
+        record.finish(&result);
+        assert!(result.is_err());
+        let timings = history::find(root.path(), "test-run")
+            .unwrap()
+            .stage_durations;
+        assert!(timings[1].millis >= 200);
+        assert_eq!(timings[3].millis, 0);
+    }
 
-        let mut stage: Option<u8> = Some(0);
-        loop {
-            match rx.recv_timeout(Duration::from_millis(200)) {
-                Ok((is_error, line)) => {
-                    if let Some(s) = stage_from_line(&line) {
-                        stage = Some(s);
-                    }
-                    emit_progress(
-                        &app,
-                        ProgressEvent {
-                            run_id: run_id.clone(),
-                            stage,
-                            message: line,
-                            is_error,
-                        },
-                    );
-                }
-                Err(mpsc::RecvTimeoutError::Timeout) => {
-                    if let Some(status) =
-                        child.try_wait().map_err(|e| format!("Process error: {e}"))?
-                    {
-                        let _ = out_handle.join();
-                        let _ = err_handle.join();
-                        if !status.success() {
-                            emit_progress(
-                                &app,
-                                ProgressEvent {
-                                    run_id: run_id.clone(),
-                                    stage,
-                                    message: format!("Python exited with status: {status}"),
-                                    is_error: true,
-                                },
-                            );
-                            return Err(format!("Python exited with status: {status}"));
-                        }
-                        break;
-                    }
-                }
-                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+    #[test]
+    fn single_outputs_are_renamed_after_the_input_without_clobbering() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("0001.png");
+        fs::write(&output, "new").unwrap();
+        fs::write(dir.path().join("grandpa_restored.png"), "old").unwrap();
+
+        let renamed = rename_after_input(&output, Path::new("/photos/grandpa.jpg")).unwrap();
+        assert_eq!(renamed, dir.path().join("grandpa_restored_2.png"));
+        assert_eq!(fs::read_to_string(&renamed).unwrap(), "new");
+        assert_eq!(
+            fs::read_to_string(dir.path().join("grandpa_restored.png")).unwrap(),
+            "old"
+        );
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn intermediates_are_removed_only_after_success() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let out = root.path().join("out");
+        let stage_1 = out.join("stage_1_restore_output");
+        fs::write(
+            root.path().join("run.py"),
+            format!("import os\nos.makedirs({stage_1:?}, exist_ok=True)\n{STUB_RUN_PY}"),
+        )
+        .unwrap();
+
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        collect_events(root.path(), &args).0.unwrap();
+        assert!(!stage_1.exists() && !out.join("_gui_input").exists());
+        assert!(out.join("final_output/photo_restored.png").exists());
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "keepIntermediates": true }),
+        );
+        collect_events(root.path(), &args).0.unwrap();
+        assert!(stage_1.is_dir() && out.join("_gui_input").is_dir());
+
+        let bad = root.path().join("bad.png");
+        write_png(&bad);
+        let args = stub_args(root.path(), &bad, serde_json::json!({}));
+        assert!(collect_events(root.path(), &args).0.is_err());
+        assert!(stage_1.is_dir() && out.join("_gui_input").is_dir());
+    }
+
+    #[test]
+    fn failed_run_error_carries_the_traceback() {
+        let mut tracker = OutputTracker::new();
+        let mut feed = |line: &str, is_error| {
+            tracker.handle_line("r", is_error, line.to_string(), false, &|_| {})
+        };
+        feed("Running Stage 1: Overall restoration", false);
+        feed("UserWarning: deprecated", true);
+        feed("Traceback (most recent call last):", true);
+        for i in 0..40 {
+            feed(&format!("  File \"test.py\", line {i}, in forward"), true);
+        }
+        feed("RuntimeError: CUDA out of memory.", true);
+        let summary = tracker.stderr_summary().unwrap();
+        let lines: Vec<_> = summary.lines().collect();
+        assert_eq!(lines.len(), STDERR_TAIL_LINES);
+        assert_eq!(lines[0], "Traceback (most recent call last):");
+        assert_eq!(lines[1], "  File \"test.py\", line 12, in forward");
+        assert_eq!(lines[29], "RuntimeError: CUDA out of memory.");
+
+        let root = stub_project();
+        let input = root.path().join("bad.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let err = collect_events(root.path(), &args)
+            .0
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.ends_with("\nTraceback (most recent call last):"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn finished_stages_are_previewed_once() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let restored = root
+            .path()
+            .join("out/stage_1_restore_output/restored_image");
+        fs::write(
+            root.path().join("run.py"),
+            format!(
+                "import os, shutil, sys\n\
+                 os.makedirs({restored:?}, exist_ok=True)\n\
+                 shutil.copy({input:?}, {restored:?})\n\
+                 print('Running Stage 2: Face Detection')\n\
+                 print('Running Stage 2: Face Detection')\n\
+                 print('Running Stage 3: Face Enhancement')\n\
+                 sys.exit(1)\n"
+            ),
+        )
+        .unwrap();
+
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let (_, events) = collect_events(root.path(), &args);
+        let previews: Vec<_> = events.iter().filter_map(|e| e.preview.as_ref()).collect();
+        assert_eq!(previews.len(), 1);
+        assert_eq!(previews[0].stage, 1);
+        assert_eq!(
+            PathBuf::from(&previews[0].image_path),
+            restored.join("photo.png")
+        );
+    }
+
+    #[test]
+    fn dry_run_returns_the_command_without_touching_anything() {
+        let root = stub_project();
+        let folder = root.path().join("old photos");
+        fs::create_dir(&folder).unwrap();
+        let input = folder.join("it's grandpa.png");
+        write_png(&input);
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "dryRun": true, "withScratch": true, "cpuThreads": 1 }),
+        );
+        let command = dry_run_command(root.path(), &args).unwrap();
+        let out = root.path().join("out");
+        let expected: Vec<String> = [
+            "python3".into(),
+            "-u".into(),
+            root.path().join("run.py"),
+            "--input_folder".into(),
+            out.join("_gui_input"),
+            "--output_folder".into(),
+            out.clone(),
+            "--GPU".into(),
+            "-1".into(),
+            "--with_scratch".into(),
+        ]
+        .iter()
+        .map(|a: &PathBuf| a.to_string_lossy().to_string())
+        .collect();
+        assert_eq!(command.argv, expected);
+        assert_eq!(PathBuf::from(&command.working_dir), root.path());
+        assert_eq!(command.env["OMP_NUM_THREADS"], "1");
+        assert!(!out.exists());
+
+        assert_eq!(shell_quote("--GPU"), "--GPU");
+        if cfg!(unix) {
+            assert_eq!(shell_quote("it's a b"), "'it'\\''s a b'");
+            assert!(command.shell.starts_with("python3 -u "));
+        }
+
+        let args = stub_args(root.path(), &folder, serde_json::json!({ "dryRun": true }));
+        let command = dry_run_command(root.path(), &args).unwrap();
+        assert_eq!(command.argv[4], folder.to_string_lossy());
+
+        // Nothing listens on the discard port, so a download attempt would fail.
+        let mut args = stub_args(root.path(), &folder, serde_json::json!({ "dryRun": true }));
+        args.input_path = "http://127.0.0.1:9/scan.png".to_string();
+        let command = dry_run_command(root.path(), &args).unwrap();
+        assert_eq!(command.argv[4], out.join("_gui_input").to_string_lossy());
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn silent_process_is_killed_after_the_stall_timeout() {
+        let options = ProcessOptions {
+            stall_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let busy = python_snippet(
+            "import time\nfor i in range(5):\n    print(i, flush=True)\n    time.sleep(0.4)",
+        );
+        run_pipeline_process(busy, "r", options.clone(), &|_| {}).unwrap();
+
+        let hung = python_snippet("import time\nprint('start', flush=True)\ntime.sleep(30)");
+        let started = Instant::now();
+        let events = Mutex::new(Vec::new());
+        let err = run_pipeline_process(hung, "r", options, &|event| {
+            events.lock().unwrap().push(event)
+        })
+        .unwrap_err()
+        .to_string();
+        assert_eq!(err, "No output for 1 seconds; process killed");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let events = events.into_inner().unwrap();
+        let last = events.last().unwrap();
+        assert!(last.is_error && last.message == err);
+    }
+
+    #[test]
+    fn only_http_inputs_are_urls() {
+        assert!(fetch::is_url("https://example.com/a.jpg"));
+        assert!(fetch::is_url(" HTTP://example.com/a"));
+        assert!(!fetch::is_url("ftp://example.com/a.jpg"));
+        assert!(!fetch::is_url("/photos/https.jpg"));
+        assert!(!fetch::is_url("C:\\photos\\a.jpg"));
+    }
+
+    #[test]
+    fn stage_banners_match_loosely() {
+        let stage_from_line = |line| stage_from_line(line, &StageLayout::default().numbers());
+        assert_eq!(
+            stage_from_line("Running Stage 1: Overall restoration"),
+            Some(1)
+        );
+        assert_eq!(stage_from_line("running stage 2:"), Some(2));
+        assert_eq!(stage_from_line(">> RUNNING  Stage\t4 - finalize"), Some(4));
+        assert_eq!(stage_from_line("Finished running the stage 3 warmup"), None);
+        assert_eq!(stage_from_line("Stage 2 of the pipeline is running"), None);
+        assert_eq!(stage_from_line("Running Stage 5"), None);
+        assert_eq!(stage_from_line("Running Stage 12"), None);
+    }
+
+    #[test]
+    fn recent_history_is_the_tail_and_tolerates_corruption() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        assert!(history::recent(root.path(), 5).is_empty());
+        for id in ["one", "two", "three"] {
+            let args = stub_args(root.path(), &input, serde_json::json!({ "runId": id }));
+            drop(history::RecordGuard::start(root.path(), args));
+        }
+        let ids: Vec<_> = history::recent(root.path(), 2)
+            .into_iter()
+            .map(|r| r.run_id)
+            .collect();
+        assert_eq!(ids, ["two", "three"]);
+        assert_eq!(history::recent(root.path(), 10).len(), 3);
+
+        fs::write(history::history_path(root.path()), "{ not json").unwrap();
+        assert!(history::recent(root.path(), 5).is_empty());
+    }
+
+    #[test]
+    fn outputs_are_converted_to_the_requested_format() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "outputFormat": "JPEG", "jpegQuality": 0 }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        let final_dir = root.path().join("out/final_output");
+        assert_eq!(
+            PathBuf::from(result.unwrap().output_path),
+            final_dir.join("photo_restored.jpg")
+        );
+        assert!(!final_dir.join("photo_restored.png").exists());
+        assert_eq!(
+            image::ImageFormat::from_path(final_dir.join("photo_restored.jpg")).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+        assert!(imaging::open_image(&final_dir.join("photo_restored.jpg")).is_ok());
+        assert!(events
+            .iter()
+            .any(|e| e.is_error && e.message == "jpeg_quality 0 is out of range; using 1"));
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "outputFormat": "gif" }),
+        );
+        let err = collect_events(root.path(), &args)
+            .0
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("use png, jpeg or webp"), "{err}");
+    }
+
+    #[test]
+    fn gpu_options_always_end_with_the_cpu() {
+        let probe = gpu::DeviceProbe::parse(
+            "{\"index\": 0, \"name\": \"RTX 3090\", \"freeBytes\": null}\n{\"mps\": false}\n",
+        )
+        .unwrap();
+        let options = gpu::gpu_options(Some(probe));
+        let picks: Vec<_> = options.iter().map(|o| (o.gpu.as_str(), o.index)).collect();
+        assert_eq!(picks, [("0", Some(0)), ("-1", None)]);
+        assert_eq!(options[0].name, "RTX 3090");
+
+        let options = gpu::gpu_options(gpu::probe_devices("no-such-python-3").ok());
+        assert_eq!(options.len(), 1);
+        assert_eq!(
+            (options[0].gpu.as_str(), options[0].name.as_str()),
+            ("-1", "CPU")
+        );
+    }
+
+    #[test]
+    fn single_image_runs_get_before_after_thumbnails() {
+        let root = stub_project();
+        let input = root.path().join("tall.png");
+        image::RgbImage::from_pixel(300, 1200, image::Rgb([90, 90, 90]))
+            .save(&input)
+            .unwrap();
+
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let result = collect_events(root.path(), &args).0.unwrap();
+        let thumbs = root.path().join("out/_thumbs");
+        assert_eq!(
+            result.input_thumb.map(PathBuf::from),
+            Some(thumbs.join("tall_restored_before.png"))
+        );
+        let after = imaging::open_image(&thumbs.join("tall_restored_after.png")).unwrap();
+        assert_eq!((after.width(), after.height()), (128, 512));
+
+        let folder = root.path().join("batch");
+        fs::create_dir(&folder).unwrap();
+        write_png(&folder.join("a.png"));
+        let args = stub_args(root.path(), &folder, serde_json::json!({}));
+        let result = collect_events(root.path(), &args).0.unwrap();
+        assert!(result.input_thumb.is_none() && result.output_thumb.is_none());
+    }
+
+    /// A TIFF-style EXIF block holding just Orientation = 6 (rotate 90° clockwise).
+    const ROTATED_EXIF: [u8; 26] = [
+        0x49, 0x49, 0x2a, 0, 8, 0, 0, 0, 1, 0, 0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn input_exif_is_copied_into_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let scan = dir.path().join("scan.jpg");
+        let mut encoder = image::codecs::jpeg::JpegEncoder::new(fs::File::create(&scan).unwrap());
+        image::ImageEncoder::set_exif_metadata(&mut encoder, ROTATED_EXIF.to_vec()).unwrap();
+        image::DynamicImage::ImageRgb8(image::RgbImage::new(8, 4))
+            .write_with_encoder(encoder)
+            .unwrap();
+        let output = dir.path().join("scan.png");
+        write_png(&output);
+
+        assert!(imaging::copy_exif(&scan, &output).unwrap());
+        assert_eq!(
+            imaging::read_exif(&output).unwrap(),
+            Some(ROTATED_EXIF.to_vec())
+        );
+        let plain = dir.path().join("plain.png");
+        write_png(&plain);
+        let before = fs::read(&output).unwrap();
+        assert!(!imaging::copy_exif(&plain, &output).unwrap());
+        assert_eq!(fs::read(&output).unwrap(), before);
+
+        let converted = imaging::convert_format(&output, image::ImageFormat::Jpeg, 90).unwrap();
+        assert_eq!(
+            imaging::read_exif(&converted).unwrap(),
+            Some(ROTATED_EXIF.to_vec())
+        );
+    }
+
+    #[test]
+    fn typed_paths_are_trimmed_and_expand_the_home_dir() {
+        let home = std::env::home_dir().unwrap();
+        assert_eq!(
+            user_path("~/Pictures/restored "),
+            home.join("Pictures/restored")
+        );
+        assert_eq!(user_path(" ~ "), home);
+        assert_eq!(user_path("~bob/x"), PathBuf::from("~bob/x"));
+
+        let root = Path::new("/project");
+        let input = Path::new("/in.png");
+        let folder = |of: &str| {
+            let args = stub_args(root, input, serde_json::json!({ "outputFolder": of }));
+            base_output_folder(root, &args)
+        };
+        assert_eq!(
+            folder("~/Pictures/restored "),
+            home.join("Pictures/restored")
+        );
+        assert_eq!(folder(" results\t"), root.join("results"));
+        if cfg!(windows) {
+            assert_eq!(folder("C:\\restored "), PathBuf::from("C:\\restored"));
+        } else {
+            assert_eq!(folder("C:\\restored "), root.join("C:\\restored"));
+            assert_eq!(folder(" /srv/restored "), PathBuf::from("/srv/restored"));
+        }
+
+        let args = stub_args(root, Path::new(" ~/scan.png "), serde_json::json!({}));
+        assert_eq!(
+            PathBuf::from(resolve_args(root, &args).input_path),
+            home.join("scan.png")
+        );
+    }
+
+    #[test]
+    fn runs_refuse_to_start_without_enough_disk_space() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        assert!(check_free_space(root.path(), 0).is_ok());
+        if free_space(root.path()).is_none() {
+            // No disk list in this environment, so the check always passes.
+            return;
+        }
+
+        let previous = root.path().join("out/final_output/previous.png");
+        fs::create_dir_all(previous.parent().unwrap()).unwrap();
+        write_png(&previous);
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "minFreeBytes": u64::MAX }),
+        );
+        let err = collect_events(root.path(), &args)
+            .0
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Insufficient disk space: "), "{err}");
+        assert!(err.ends_with(" required"), "{err}");
+        assert!(previous.exists());
+    }
+
+    #[test]
+    fn cuda_oom_falls_back_to_the_cpu_once() {
+        let root = stub_project();
+        let fake_oom = format!(
+            "import sys\n\
+             if '--GPU' in sys.argv and sys.argv[sys.argv.index('--GPU') + 1] != '-1':\n    \
+             sys.exit('torch.OutOfMemoryError: CUDA out of memory. Tried to allocate 2 GiB')\n\
+             {STUB_RUN_PY}"
+        );
+        fs::write(root.path().join("run.py"), fake_oom).unwrap();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+
+        // Device 0 only reaches run.py where torch either sees a GPU or can't be
+        // imported, which skips the device check.
+        if gpu::probe_devices("python3").is_ok_and(|probe| probe.cuda.is_empty()) {
+            return;
+        }
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "gpu": "0", "cpuFallback": true }),
+        );
+        let (result, events) = collect_events(root.path(), &args);
+        result.unwrap();
+        assert!(events
+            .iter()
+            .any(|e| e.message == "GPU failed, retrying on CPU"));
+
+        let args = stub_args(root.path(), &input, serde_json::json!({ "gpu": "0" }));
+        let err = collect_events(root.path(), &args)
+            .0
+            .unwrap_err()
+            .to_string();
+        assert!(is_cuda_oom(&err), "{err}");
+        assert!(!is_cuda_oom("Python exited with status: exit status: 1"));
+    }
+
+    #[test]
+    fn run_summary_is_written_for_successes_and_failures() {
+        let root = stub_project();
+        let folder = root.path().join("batch");
+        fs::create_dir(&folder).unwrap();
+        write_png(&folder.join("a.png"));
+        write_png(&folder.join("b.png"));
+        let run = |input: &Path, id: &str| {
+            let args = stub_args(root.path(), input, serde_json::json!({ "runId": id }));
+            let record = history::RecordGuard::start(root.path(), args.clone());
+            let (result, _) = collect_events(root.path(), &args);
+            record.finish(&result);
+            history::read_summary(root.path(), id).unwrap()
+        };
+
+        let summary = run(&folder, "summary-batch");
+        assert!(summary.success && summary.error.is_none());
+        assert!(history::summary_path(&root.path().join("out"), "summary-batch").is_file());
+        assert_eq!(summary.outputs.len(), 2);
+        assert_eq!(summary.inputs.len(), 2);
+        assert_eq!(summary.commands.len(), 1);
+        assert_eq!(summary.commands[0].exit_code, Some(0));
+        assert!(summary.commands[0]
+            .argv
+            .iter()
+            .any(|a| a == "--input_folder"));
+        assert!(summary.finished_at >= summary.started_at);
+
+        let bad = folder.join("bad.png");
+        write_png(&bad);
+        let summary = run(&bad, "summary-bad");
+        assert!(!summary.success);
+        assert_eq!(summary.commands[0].exit_code, Some(3));
+        assert_eq!(summary.inputs, [bad.to_string_lossy().to_string()]);
+        assert!(history::read_summary(root.path(), "no-such-run").is_err());
+    }
+
+    #[test]
+    fn folder_progress_counts_images_per_stage() {
+        let mut tracker = OutputTracker::new();
+        tracker.file_total = Some(2);
+        let events = Mutex::new(Vec::new());
+        let emit = |e: ProgressEvent| events.lock().unwrap().push(e);
+        for line in [
+            "Running Stage 1: Overall restoration",
+            "Now you are processing a.png",
+            "Now you are processing b.png",
+            "Running Stage 3: Face Enhancement",
+            "warming up",
+            "processing a.png",
+        ] {
+            tracker.handle_line("r", false, line.to_string(), false, &emit);
+        }
+        let progress: Vec<_> = events
+            .into_inner()
+            .unwrap()
+            .iter()
+            .map(|e| (e.stage, e.file_index, e.file_total))
+            .collect();
+        assert_eq!(
+            progress,
+            [
+                (Some(1), None, None),
+                (Some(1), Some(1), Some(2)),
+                (Some(1), Some(2), Some(2)),
+                (Some(3), None, None),
+                (Some(3), None, None),
+                (Some(3), Some(1), Some(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn caller_env_and_conda_prefix_reach_run_py() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let conda = root.path().join("env");
+        fs::create_dir(&conda).unwrap();
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({
+                "env": { "OMP_NUM_THREADS": "3", "PYTHONUNBUFFERED": "0", "PATH": "/opt/x" },
+                "condaPrefix": conda.to_string_lossy(),
+                "cpuThreads": 1,
+            }),
+        );
+
+        let cmd = pipeline_command(
+            root.path(),
+            &args,
+            &GpuSelection::Cpu,
+            &[],
+            root.path(),
+            root.path(),
+        );
+        let envs: HashMap<_, _> = cmd.get_envs().collect();
+        assert_eq!(envs[OsStr::new("OMP_NUM_THREADS")], Some(OsStr::new("3")));
+        assert_eq!(envs[OsStr::new("PYTHONUNBUFFERED")], Some(OsStr::new("1")));
+        let path: Vec<_> = std::env::split_paths(envs[OsStr::new("PATH")].unwrap()).collect();
+        let bin = conda.join(if cfg!(windows) { "Scripts" } else { "bin" });
+        assert_eq!(path, [bin, PathBuf::from("/opt/x")]);
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "condaPrefix": root.path().join("nope").to_string_lossy() }),
+        );
+        let err = run_pipeline(root.path(), &args, |_| {})
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("Conda prefix not found: "), "{err}");
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "env": { "A=B": "1" } }),
+        );
+        let err = run_pipeline(root.path(), &args, |_| {})
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Invalid environment variable: \"A=B\"");
+    }
+
+    #[test]
+    fn run_output_is_logged_to_disk() {
+        let root = stub_project();
+        let input = root.path().join("bad.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let result = run_pipeline(root.path(), &args, |_| {});
+        assert!(result.is_err());
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let result = run_pipeline(root.path(), &args, |_| {}).unwrap();
+
+        let path = PathBuf::from(result.log_path.unwrap());
+        let output_folder = resolve_output_folder(root.path(), &args);
+        assert_eq!(path, runlog::log_path(&output_folder, &args.run_id));
+        let log = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert!(lines
+            .iter()
+            .any(|l| l.ends_with(" [err] Traceback (most recent call last):")));
+        assert!(lines
+            .iter()
+            .any(|l| l.ends_with(" [out] Running Stage 1: stub")));
+        // 2024-05-01T09:30:00.250Z
+        assert!(lines
+            .iter()
+            .all(|l| l.len() > 25 && &l[10..11] == "T" && &l[23..25] == "Z "));
+
+        reset_stage_dirs(&output_folder, &StageLayout::default()).unwrap();
+        assert!(path.is_file());
+    }
+
+    #[test]
+    fn non_images_are_rejected_before_anything_runs() {
+        let root = stub_project();
+        let photo = image::RgbImage::new(8, 8);
+        for ext in ["png", "jpg", "bmp", "tif", "webp"] {
+            let path = root.path().join(format!("photo.{ext}"));
+            photo.save(&path).unwrap();
+            assert!(has_image_header(&path), "{ext}");
+        }
+
+        let notes = root.path().join("notes.png");
+        fs::write(&notes, "not an image").unwrap();
+        let args = stub_args(root.path(), &notes, serde_json::json!({}));
+        let err = run_pipeline(root.path(), &args, |_| {})
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "Selected file is not a supported image: {}",
+                notes.display()
+            )
+        );
+        let output_folder = resolve_output_folder(root.path(), &args);
+        assert!(!output_folder.join("_gui_input").exists());
+
+        let folder = root.path().join("texts");
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("a.txt"), "hello").unwrap();
+        let args = stub_args(root.path(), &folder, serde_json::json!({}));
+        let err = run_pipeline(root.path(), &args, |_| {})
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, format!("No supported images in {}", folder.display()));
+    }
+
+    #[test]
+    fn latest_file_ties_go_to_the_last_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let when = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for name in ["b.png", "d.png", "a.png", "c.png"] {
+            let path = dir.path().join(name);
+            write_png(&path);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(when)
+                .unwrap();
+        }
+        for _ in 0..5 {
+            let picked = pick_latest_file(dir.path(), |_| true).unwrap().unwrap();
+            assert_eq!(picked, dir.path().join("d.png"));
+        }
+        let picked = pick_latest_file(dir.path(), |p| !p.ends_with("d.png")).unwrap();
+        assert_eq!(picked, Some(dir.path().join("c.png")));
+    }
+
+    #[test]
+    fn eta_comes_from_matching_past_runs() {
+        let root = stub_project();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        record.reached_stage(1);
+        assert_eq!(record.eta_secs(), None);
+        drop(record);
+
+        let past = |hr: bool, success: bool, secs: [u64; 5]| RunRecord {
+            run_id: "past".to_string(),
+            started_at: 0,
+            finished_at: 0,
+            success,
+            cancelled: false,
+            stage_reached: Some(4),
+            stage_durations: (0u8..)
+                .zip(secs)
+                .map(|(stage, s)| StageTiming {
+                    stage,
+                    millis: s * 1000,
+                })
+                .collect(),
+            output_path: None,
+            error: None,
+            args: ModifyPhotoArgs { hr, ..args.clone() },
+        };
+        for record in [
+            past(false, true, [2, 10, 20, 30, 4]),
+            past(false, true, [4, 30, 40, 50, 6]),
+            past(true, true, [900, 900, 900, 900, 900]),
+            past(false, false, [900, 900, 900, 900, 900]),
+        ] {
+            history::append(root.path(), record).unwrap();
+        }
+        assert_eq!(
+            history::stage_averages(root.path(), &args),
+            Some([3, 20, 30, 40, 5].map(Duration::from_secs))
+        );
+
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        record.reached_stage(2);
+        assert_eq!(record.eta_secs(), Some(75));
+        let averages = [3, 20, 30, 40, 5].map(Duration::from_secs);
+        assert_eq!(
+            history::remaining(&averages, 2, Duration::from_secs(10)),
+            Duration::from_secs(65)
+        );
+        // Slower than usual: the current stage counts as nearly done, never negative.
+        assert_eq!(
+            history::remaining(&averages, 2, Duration::from_secs(100)),
+            Duration::from_secs(45)
+        );
+        drop(record);
+    }
+
+    #[test]
+    fn custom_pipeline_sets_the_stage_folders() {
+        let root = tempfile::tempdir().unwrap();
+        let fork = STUB_RUN_PY
+            .replace("\"final_output\"", "\"results\"")
+            .replace("range(1, 5)", "range(1, 4)");
+        fs::write(root.path().join("run.py"), fork).unwrap();
+        let input = root.path().join("photo.png");
+        write_png(&input);
+        let pipeline = serde_json::json!([
+            { "stage": 1, "outputDir": "clean" },
+            { "stage": 2, "outputDir": "results", "isFinal": true },
+        ]);
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "pipeline": pipeline }),
+        );
+
+        let (result, events) = collect_events(root.path(), &args);
+        let result = result.unwrap();
+        let out = root.path().join("out");
+        assert_eq!(
+            Path::new(&result.output_path).parent(),
+            Some(out.join("results").as_path())
+        );
+        assert_eq!(result.stage_counts, HashMap::from([(1, 0), (2, 1)]));
+        for phantom in ["clean", "stage_1_restore_output", "final_output"] {
+            assert!(!out.join(phantom).exists(), "{phantom}");
+        }
+        // The fork's third banner isn't one of its stages.
+        assert!(events
+            .iter()
+            .filter(|e| e.message.starts_with("Running Stage"))
+            .all(|e| e.stage.is_some_and(|s| s <= 2)));
+
+        for (pipeline, why) in [
+            (serde_json::json!([]), "it has no stages"),
+            (
+                serde_json::json!([{ "stage": 1, "outputDir": "a" }]),
+                "exactly one stage must be final",
+            ),
+            (
+                serde_json::json!([{ "stage": 1, "outputDir": "../a", "isFinal": true }]),
+                "stage 1 output_dir must be a folder inside the output folder",
+            ),
+        ] {
+            let args = stub_args(
+                root.path(),
+                &input,
+                serde_json::json!({ "pipeline": pipeline }),
+            );
+            let err = run_pipeline(root.path(), &args, |_| {})
+                .unwrap_err()
+                .to_string();
+            assert_eq!(err, format!("Invalid pipeline: {why}"));
+        }
+    }
+
+    #[test]
+    fn run_errors_are_typed_for_the_webview() {
+        let root = stub_project();
+        let missing = root.path().join("missing.png");
+        let args = stub_args(root.path(), &missing, serde_json::json!({}));
+        let err = run_pipeline(root.path(), &args, |_| {}).unwrap_err();
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({
+                "kind": "inputNotFound",
+                "message": format!("Input not found: {}", missing.display()),
+            })
+        );
+
+        let input = root.path().join("bad.png");
+        write_png(&input);
+        let args = stub_args(root.path(), &input, serde_json::json!({}));
+        let err = serde_json::to_value(run_pipeline(root.path(), &args, |_| {}).unwrap_err());
+        let err = err.unwrap();
+        assert_eq!(err["kind"], "pythonExited");
+        assert_eq!(err["code"], 3);
+        assert!(err["message"]
+            .as_str()
+            .unwrap()
+            .ends_with("Traceback (most recent call last):"));
+
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "python": "no-such-python-for-errors" }),
+        );
+        let err = run_pipeline(root.path(), &args, |_| {}).unwrap_err();
+        assert!(
+            matches!(err, ModifyError::InterpreterNotFound(_)),
+            "{err:?}"
+        );
+        assert_eq!(
+            ModifyError::from("Cancelled by user".to_string()),
+            ModifyError::Cancelled("Cancelled by user".to_string())
+        );
+        let exited = ModifyError::PythonExited {
+            code: Some(1),
+            message: "Python exited".to_string(),
+        };
+        assert_eq!(
+            exited.context("Test run on a.png failed"),
+            ModifyError::PythonExited {
+                code: Some(1),
+                message: "Test run on a.png failed: Python exited".to_string(),
             }
+        );
+        let cancelled = ModifyError::Cancelled("Cancelled by user".to_string());
+        assert_eq!(
+            cancelled.clone().context("Test run on a.png failed"),
+            cancelled
+        );
+    }
+
+    #[test]
+    fn event_pipe_writes_ndjson_and_never_waits_for_a_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.ndjson");
+        let pipe = EventPipe::open(&path.to_string_lossy()).unwrap();
+        for message in ["one", "two"] {
+            pipe.write(&ProgressEvent {
+                run_id: "r".to_string(),
+                message: message.to_string(),
+                ..Default::default()
+            });
         }
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["message"], "two");
 
-        let latest = pick_latest_file(&final_dir)?
-            .ok_or_else(|| format!("No output image found under {}", final_dir.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            let fifo = dir.path().join("events.fifo");
+            assert!(Command::new("mkfifo")
+                .arg(&fifo)
+                .status()
+                .unwrap()
+                .success());
+            let started = Instant::now();
+            let err = EventPipe::open(&fifo.to_string_lossy()).err().unwrap();
+            assert_eq!(err, format!("No reader on event pipe {}", fifo.display()));
+            assert!(started.elapsed() < Duration::from_secs(5));
 
-        emit_progress(
-            &app,
-            ProgressEvent {
-                run_id: run_id.clone(),
-                stage: Some(4),
-                message: "Done".to_string(),
-                is_error: false,
+            // A reader that goes away drops the pipe; writing on doesn't fail or block.
+            let reader = fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&fifo)
+                .unwrap();
+            let pipe = EventPipe::open(&fifo.to_string_lossy()).unwrap();
+            drop(reader);
+            for _ in 0..2 {
+                pipe.write(&ProgressEvent::default());
+            }
+            assert!(pipe.file.lock().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_run_failures_keep_their_kind() {
+        let root = stub_project();
+        let input = root.path().join("photos");
+        fs::create_dir(&input).unwrap();
+        write_png(&input.join("bad.png"));
+        write_png(&input.join("good.png"));
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "testFirst": true }),
+        );
+        let err = run_pipeline(root.path(), &args, |_| {}).unwrap_err();
+        assert!(
+            matches!(&err, ModifyError::PythonExited { code: Some(3), message }
+                if message.starts_with("Test run on bad.png failed: ")),
+            "{err:?}"
+        );
+
+        fs::write(
+            root.path().join("run.py"),
+            "import time\nprint('Running Stage 1: x', flush=True)\ntime.sleep(30)\n",
+        )
+        .unwrap();
+        let args = stub_args(
+            root.path(),
+            &input,
+            serde_json::json!({ "runId": "cancel-test-run", "testFirst": true }),
+        );
+        let _guard = cancel::register("cancel-test-run");
+        let canceller = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(500));
+            cancel::cancel("cancel-test-run", None);
+        });
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        let (result, _) = collect_events(root.path(), &args);
+        canceller.join().unwrap();
+        assert_eq!(
+            result.as_ref().unwrap_err(),
+            &ModifyError::Cancelled("Cancelled by user".to_string())
+        );
+        record.finish(&result);
+        assert!(
+            history::find(root.path(), "cancel-test-run")
+                .unwrap()
+                .cancelled
+        );
+    }
+
+    #[test]
+    fn url_inputs_are_removed_after_the_run_and_failures_are_recorded() {
+        let root = stub_project();
+        let served = tempfile::tempdir().unwrap();
+        write_png(&served.path().join("scan.png"));
+        let (mut server, base) = serve_folder(served.path());
+        let downloads = root.path().join("out").join(DOWNLOAD_DIR);
+
+        let mut args = stub_args(
+            root.path(),
+            Path::new("unused"),
+            serde_json::json!({ "runId": "from-url" }),
+        );
+        args.input_path = format!("{base}/scan.png");
+        let mut args = resolve_args(root.path(), &args);
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        let result = with_downloaded_input(root.path(), &args, &|_| {}, |local| {
+            assert!(Path::new(&local.input_path).starts_with(&downloads));
+            run_pipeline(root.path(), local, |_| {})
+        });
+        record.finish(&result);
+        assert!(result.is_ok(), "{result:?}");
+        assert!(!downloads.exists());
+        assert_eq!(
+            history::find(root.path(), "from-url")
+                .unwrap()
+                .args
+                .input_path,
+            format!("{base}/scan.png")
+        );
+
+        args.run_id = "missing-url".to_string();
+        args.input_path = format!("{base}/gone.png");
+        let record = history::RecordGuard::start(root.path(), args.clone());
+        let result = with_downloaded_input(root.path(), &args, &|_| {}, |_| {
+            unreachable!("ran without a download")
+        });
+        let _ = server.kill();
+        let _ = server.wait();
+        record.finish(&result);
+        let error = history::find(root.path(), "missing-url").unwrap().error;
+        assert!(error
+            .unwrap()
+            .ends_with("The server answered 404 File not found"));
+        assert!(!downloads.exists());
+    }
+
+    #[test]
+    fn export_sizes_skips_zero_and_upscaled_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("photo.png");
+        write_png(&source);
+
+        let export =
+            imaging::export_sizes(&source, &[16, 64, 0, 32], image::ImageFormat::Jpeg).unwrap();
+        assert_eq!(
+            export.outputs,
+            [
+                dir.path().join("photo_16.jpg"),
+                dir.path().join("photo_32.jpg")
+            ]
+        );
+        assert_eq!(export.skipped, [64, 0]);
+        let resized = imaging::open_image(&export.outputs[0]).unwrap();
+        assert_eq!((resized.width(), resized.height()), (16, 16));
+        assert!(!dir.path().join("photo_64.jpg").exists());
+        assert!(!dir.path().join("photo_0.jpg").exists());
+    }
+
+    #[test]
+    fn retries_pick_the_latest_failure_of_each_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("photo.png");
+        write_png(&input);
+        let args = stub_args(dir.path(), &input, serde_json::json!({}));
+        let record = |run_id: &str, success: bool, input: &Path| RunRecord {
+            run_id: run_id.to_string(),
+            started_at: 0,
+            finished_at: 0,
+            success,
+            cancelled: false,
+            stage_reached: None,
+            stage_durations: Vec::new(),
+            output_path: None,
+            error: None,
+            args: ModifyPhotoArgs {
+                input_path: input.to_string_lossy().to_string(),
+                ..args.clone()
             },
+        };
+        let history = vec![
+            // Failed, then succeeded under the same id.
+            record("fixed", false, &input),
+            record("fixed", true, &input),
+            // Succeeded, then failed under the same id.
+            record("broke", true, &input),
+            record("broke", false, &input),
+            // Failed, and its retry failed too: only the retry is retried.
+            record("flaky", false, &input),
+            record("flaky_retry_100", false, &input),
+            // Failed, and its retry succeeded.
+            record("healed", false, &input),
+            record("healed_retry_100", true, &input),
+            record("gone", false, &dir.path().join("deleted.png")),
+            record("remote", false, Path::new("https://example.com/scan.png")),
+        ];
+
+        let (retries, skipped) = plan_retries(history, 200);
+        let ids: Vec<_> = retries.iter().map(|a| a.run_id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "broke_retry_200",
+                "flaky_retry_100_retry_200",
+                "remote_retry_200"
+            ]
         );
+        assert_eq!(retries[0].input_path, input.to_string_lossy());
+        let skipped: Vec<_> = skipped.iter().map(|r| r.run_id.as_str()).collect();
+        assert_eq!(skipped, ["gone"]);
+    }
 
-        Ok(ModifyPhotoResult {
-            output_path: latest.to_string_lossy().to_string(),
-        })
-    })
-    .await
-    .map_err(|e| format!("Task failed: {e}"))?
-}
+    #[test]
+    fn gpu_values_are_normalized_and_checked_against_the_device_count() {
+        assert_eq!(GpuSelection::parse("").unwrap(), GpuSelection::Default);
+        assert_eq!(GpuSelection::parse("  ").unwrap(), GpuSelection::Default);
+        assert_eq!(
+            GpuSelection::parse(" 0, 1 ").unwrap(),
+            GpuSelection::Devices(vec![0, 1])
+        );
+        assert_eq!(
+            GpuSelection::parse("1,0,1").unwrap(),
+            GpuSelection::Devices(vec![1, 0])
+        );
+        for bad in ["gpu0", "0,", "cuda:0", "-2"] {
+            let err = GpuSelection::parse(bad).unwrap_err();
+            assert!(
+                err.starts_with(&format!("Invalid GPU value \"{bad}\"")),
+                "{err}"
+            );
+        }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    tauri::Builder::default()
-        .plugin(tauri_plugin_dialog::init())
-        .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![greet, modify_photo])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        let devices = GpuSelection::parse("0,2").unwrap();
+        assert_eq!(
+            devices.check_device_count(2, false).unwrap_err(),
+            "GPU 2 not found; detected 2 GPU(s) (valid indices 0-1)"
+        );
+        assert!(devices.check_device_count(3, false).is_ok());
+        assert!(GpuSelection::Cpu.check_device_count(0, false).is_ok());
+    }
+
+    #[test]
+    fn isolated_and_chunked_batches_keep_the_error_kind() {
+        let root = stub_project();
+        let input = root.path().join("batch");
+        fs::create_dir_all(&input).unwrap();
+        write_png(&input.join("bad_1.png"));
+        write_png(&input.join("bad_2.png"));
+
+        let run = |extra: serde_json::Value| {
+            let args = stub_args(root.path(), &input, extra);
+            run_pipeline(root.path(), &args, |_| {}).unwrap_err()
+        };
+        let all_failed = run(serde_json::json!({ "isolateFiles": true }));
+        assert!(
+            matches!(&all_failed, ModifyError::PythonExited { code: Some(3), message }
+                if message.starts_with("All 2 files failed; first error: ")),
+            "{all_failed:?}"
+        );
+        let stopped = run(serde_json::json!({ "isolateFiles": true, "stopOnError": true }));
+        assert!(
+            matches!(&stopped, ModifyError::StoppedOnError(message)
+                if message.starts_with("Stopped on first error in bad_1.png: ")),
+            "{stopped:?}"
+        );
+        let chunk = run(serde_json::json!({ "chunkSize": 1 }));
+        assert!(
+            matches!(&chunk, ModifyError::PythonExited { code: Some(3), message }
+                if message.starts_with("Chunk 1/2 (files 1-1) failed: ")),
+            "{chunk:?}"
+        );
+    }
 }
@@ -0,0 +1,112 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{has_image_extension, history, list_input_files};
+
+pub(crate) const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    run_id: String,
+    /// Seconds since the Unix epoch.
+    created_at: u64,
+    outputs: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEntry {
+    path: String,
+    /// 1-3 for intermediate stage results, 4 for final outputs.
+    stage: u8,
+    /// The input it was made from, when it can be matched by file stem.
+    source: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+    size_bytes: u64,
+}
+
+fn describe(path: &Path, stage: u8, sources: &HashMap<String, PathBuf>) -> ManifestEntry {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let reader = image::ImageReader::open(path).and_then(|r| r.with_guessed_format());
+    let format = reader
+        .as_ref()
+        .ok()
+        .and_then(|r| r.format())
+        .and_then(|f| f.extensions_str().first().copied())
+        .map(str::to_string);
+    let dimensions = reader.ok().and_then(|r| r.into_dimensions().ok());
+    ManifestEntry {
+        path: path.to_string_lossy().to_string(),
+        stage,
+        source: sources.get(&stem).map(|p| p.to_string_lossy().to_string()),
+        width: dimensions.map(|d| d.0),
+        height: dimensions.map(|d| d.1),
+        format,
+        size_bytes: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+    }
+}
+
+/// Writes `output_folder/manifest.json` listing every image the stages produced.
+/// `result_dirs` are the `(stage, folder)` pairs of [`crate::StageLayout`]; `sources`
+/// maps a file stem to the input it came from.
+pub(crate) fn write(
+    output_folder: &Path,
+    result_dirs: &[(u8, String)],
+    run_id: &str,
+    sources: &HashMap<String, PathBuf>,
+) -> Result<PathBuf, String> {
+    let mut outputs = Vec::new();
+    for (stage, dir) in result_dirs {
+        let dir = output_folder.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        for file in list_input_files(&dir)?
+            .into_iter()
+            .filter(|f| has_image_extension(f))
+        {
+            outputs.push(describe(&file, *stage, sources));
+        }
+    }
+
+    let manifest = Manifest {
+        run_id: run_id.to_string(),
+        created_at: history::unix_now(),
+        outputs,
+    };
+    let text = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| format!("Failed to serialize manifest: {e}"))?;
+    let path = output_folder.join(MANIFEST_NAME);
+    fs::write(&path, text).map_err(|e| format!("Failed to write manifest: {e}"))?;
+    Ok(path)
+}
+
+/// Rewrites the entry for `old` in the manifest at `path` to `new`. Returns false when
+/// the manifest has no such entry.
+pub(crate) fn relocate(path: &Path, old: &str, new: &str) -> Result<bool, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read manifest: {e}"))?;
+    let mut manifest: serde_json::Value =
+        serde_json::from_str(&text).map_err(|e| format!("Invalid manifest: {e}"))?;
+    let Some(outputs) = manifest["outputs"].as_array_mut() else {
+        return Ok(false);
+    };
+    let mut found = false;
+    for entry in outputs.iter_mut().filter(|e| e["path"] == old) {
+        entry["path"] = new.into();
+        found = true;
+    }
+    if found {
+        let text = serde_json::to_string_pretty(&manifest)
+            .map_err(|e| format!("Failed to serialize manifest: {e}"))?;
+        fs::write(path, text).map_err(|e| format!("Failed to write manifest: {e}"))?;
+    }
+    Ok(found)
+}
@@ -0,0 +1,280 @@
+use serde::Serialize;
+use std::io::BufReader;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::for_each_capped_line;
+
+/// Downloads one asset: `kind url target`, run from the project root. Prints
+/// `progress <bytes done> <bytes total>` lines (total is 0 when unknown) at least once
+/// a second while bytes arrive, and `done`.
+const DOWNLOAD_SNIPPET: &str = r#"
+import bz2, os, shutil, sys, tempfile, time, urllib.request, zipfile
+kind, url, target = sys.argv[1:4]
+
+with tempfile.TemporaryFile() as tmp:
+    with urllib.request.urlopen(url) as resp:
+        total = int(resp.headers.get("Content-Length") or 0)
+        done, last, last_time = 0, -1, 0.0
+        while True:
+            chunk = resp.read(1 << 16)
+            if not chunk:
+                break
+            tmp.write(chunk)
+            done += len(chunk)
+            percent = done * 100 // total if total else done >> 20
+            if percent != last or time.monotonic() - last_time >= 1:
+                last, last_time = percent, time.monotonic()
+                print("progress %d %d" % (done, total), flush=True)
+    tmp.seek(0)
+    if kind == "bz2":
+        part = target + ".part"
+        with open(part, "wb") as out:
+            shutil.copyfileobj(bz2.BZ2File(tmp), out)
+        os.replace(part, target)
+    elif kind == "zip":
+        zipfile.ZipFile(tmp).extractall(target)
+    else:
+        # A repository archive: copy out the one folder named like the target.
+        name = os.path.basename(target)
+        unpacked = tempfile.mkdtemp()
+        try:
+            zipfile.ZipFile(tmp).extractall(unpacked)
+            for dirpath, dirnames, _ in os.walk(unpacked):
+                if name in dirnames:
+                    shutil.copytree(os.path.join(dirpath, name), target, dirs_exist_ok=True)
+                    break
+            else:
+                sys.exit("%s not found in %s" % (name, url))
+        finally:
+            shutil.rmtree(unpacked)
+print("done", flush=True)
+"#;
+
+const SYNC_BATCHNORM_URL: &str =
+    "https://github.com/vacancy/Synchronized-BatchNorm-PyTorch/archive/refs/heads/master.zip";
+
+/// Something the pipeline needs that isn't in the repository, mirroring the steps of
+/// the `download-weights` script.
+#[derive(Debug)]
+pub(crate) struct Asset {
+    pub(crate) name: &'static str,
+    /// Paths relative to the project root; the asset is missing if any of them is.
+    pub(crate) required: &'static [&'static str],
+    /// `zip` (extract into `target`), `bz2` (decompress to `target`) or `folder` (copy
+    /// the folder named like `target` out of a repository archive).
+    pub(crate) kind: &'static str,
+    pub(crate) url: &'static str,
+    pub(crate) target: &'static str,
+}
+
+pub(crate) const ASSETS: [Asset; 5] = [
+    Asset {
+        name: "Synchronized-BatchNorm (face enhancement)",
+        required: &["Face_Enhancement/models/networks/sync_batchnorm"],
+        kind: "folder",
+        url: SYNC_BATCHNORM_URL,
+        target: "Face_Enhancement/models/networks/sync_batchnorm",
+    },
+    Asset {
+        name: "Synchronized-BatchNorm (scratch detection)",
+        required: &["Global/detection_models/sync_batchnorm"],
+        kind: "folder",
+        url: SYNC_BATCHNORM_URL,
+        target: "Global/detection_models/sync_batchnorm",
+    },
+    Asset {
+        name: "dlib face landmarks",
+        required: &["Face_Detection/shape_predictor_68_face_landmarks.dat"],
+        kind: "bz2",
+        url: "http://dlib.net/files/shape_predictor_68_face_landmarks.dat.bz2",
+        target: "Face_Detection/shape_predictor_68_face_landmarks.dat",
+    },
+    Asset {
+        name: "face enhancement checkpoints",
+        required: &[
+            "Face_Enhancement/checkpoints/Setting_9_epoch_100",
+            "Face_Enhancement/checkpoints/FaceSR_512",
+        ],
+        kind: "zip",
+        url: "https://facevc.blob.core.windows.net/zhanbo/old_photo/pretrain/Face_Enhancement/checkpoints.zip",
+        target: "Face_Enhancement",
+    },
+    Asset {
+        name: "restoration and detection checkpoints",
+        required: &[
+            "Global/checkpoints/detection/FT_Epoch_latest.pt",
+            "Global/checkpoints/restoration",
+        ],
+        kind: "zip",
+        url: "https://facevc.blob.core.windows.net/zhanbo/old_photo/pretrain/Global/checkpoints.zip",
+        target: "Global",
+    },
+];
+
+pub(crate) fn missing_assets(root: &Path) -> Vec<&'static Asset> {
+    ASSETS
+        .iter()
+        .filter(|asset| asset.required.iter().any(|p| !root.join(p).exists()))
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DownloadProgress {
+    pub(crate) asset: String,
+    pub(crate) message: String,
+    pub(crate) is_error: bool,
+    /// Set on progress lines when the server reported a size.
+    pub(crate) percent: Option<u8>,
+    /// Set on the warning sent when no bytes have arrived for [`STALL_AFTER`].
+    pub(crate) stalled: bool,
+}
+
+/// How long a download may go without receiving a byte before it is reported stalled.
+const STALL_AFTER: Duration = Duration::from_secs(30);
+const STALL_MESSAGE: &str = "Download appears stalled \u{2014} check your network/proxy";
+
+/// Notices when the byte count of a download stops advancing.
+pub(crate) struct StallWatch {
+    bytes: u64,
+    last_advance: Instant,
+    warned: bool,
+}
+
+impl StallWatch {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            bytes: 0,
+            last_advance: now,
+            warned: false,
+        }
+    }
+
+    pub(crate) fn progress(&mut self, bytes: u64, now: Instant) {
+        if bytes > self.bytes {
+            self.bytes = bytes;
+            self.last_advance = now;
+            self.warned = false;
+        }
+    }
+
+    /// True once per stall, when nothing has arrived for `limit`.
+    pub(crate) fn check(&mut self, now: Instant, limit: Duration) -> bool {
+        if self.warned || now.duration_since(self.last_advance) < limit {
+            return false;
+        }
+        self.warned = true;
+        true
+    }
+}
+
+/// `(done, total)` from a snippet `progress` line; total is 0 when unknown.
+fn parse_progress(line: &str) -> Option<(u64, u64)> {
+    let mut parts = line.strip_prefix("progress ")?.split(' ');
+    let done = parts.next()?.parse().ok()?;
+    let total = parts.next()?.parse().ok()?;
+    Some((done, total))
+}
+
+pub(crate) fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1u64 << 20) as f64
+}
+
+/// Downloads `asset` with `python`, forwarding the snippet's output as it arrives.
+pub(crate) fn download(
+    root: &Path,
+    python: &str,
+    asset: &Asset,
+    emit: &dyn Fn(DownloadProgress),
+) -> Result<(), String> {
+    let event = |message: String, is_error: bool, percent: Option<u8>| DownloadProgress {
+        asset: asset.name.to_string(),
+        message,
+        is_error,
+        percent,
+        stalled: false,
+    };
+    emit(event(format!("Downloading {}...", asset.name), false, None));
+
+    let mut child = Command::new(python)
+        .current_dir(root)
+        .arg("-c")
+        .arg(DOWNLOAD_SNIPPET)
+        .args([asset.kind, asset.url, asset.target])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to start python: {e}"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| "Failed to capture python stdout".to_string())?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| "Failed to capture python stderr".to_string())?;
+
+    let (tx, rx) = mpsc::channel::<(bool, String)>();
+    let tx_err = tx.clone();
+    let out_handle = thread::spawn(move || {
+        for_each_capped_line(BufReader::new(stdout), |line, _| {
+            let _ = tx.send((false, line));
+        });
+    });
+    let err_handle = thread::spawn(move || {
+        for_each_capped_line(BufReader::new(stderr), |line, _| {
+            let _ = tx_err.send((true, line));
+        });
+    });
+
+    let mut last_error = None;
+    let mut stall = StallWatch::new(Instant::now());
+    loop {
+        let (is_error, line) = match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(received) => received,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if stall.check(Instant::now(), STALL_AFTER) {
+                    emit(DownloadProgress {
+                        stalled: true,
+                        ..event(STALL_MESSAGE.to_string(), false, None)
+                    });
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if let Some((done, total)) = parse_progress(&line) {
+            stall.progress(done, Instant::now());
+            let (message, percent) = match (done * 100).checked_div(total) {
+                Some(percent) => (
+                    format!("{:.1} of {:.1} MiB", mib(done), mib(total)),
+                    Some(percent.min(100) as u8),
+                ),
+                None => (format!("{:.1} MiB", mib(done)), None),
+            };
+            emit(event(message, false, percent));
+        } else if line.trim() == "done" {
+            emit(event(format!("{} ready", asset.name), false, Some(100)));
+        } else if !line.trim().is_empty() {
+            if is_error {
+                last_error = Some(line.trim().to_string());
+            }
+            emit(event(line, is_error, None));
+        }
+    }
+    let _ = out_handle.join();
+    let _ = err_handle.join();
+
+    let status = child.wait().map_err(|e| format!("Process error: {e}"))?;
+    if !status.success() {
+        let detail = last_error.unwrap_or_else(|| format!("python exited with {status}"));
+        let message = format!("Failed to download {}: {detail}", asset.name);
+        emit(event(message.clone(), true, None));
+        return Err(message);
+    }
+    Ok(())
+}
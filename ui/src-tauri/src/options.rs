@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Probe results per interpreter. Only successful probes are kept, so a broken
+/// environment is retried once it's fixed.
+static OPTIONS_CACHE: Mutex<Option<HashMap<String, Vec<String>>>> = Mutex::new(None);
+
+/// Like [`probe`], but remembers the answer for each interpreter for the rest of the
+/// session.
+pub(crate) fn supported(root: &Path, python: &str) -> Result<Vec<String>, String> {
+    let key = python.trim().to_string();
+    if let Some(names) = OPTIONS_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|cache| cache.get(&key))
+    {
+        return Ok(names.clone());
+    }
+    let names = probe(root, python)?;
+    OPTIONS_CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(key, names.clone());
+    Ok(names)
+}
+
+/// Runs `python run.py --help` in `root` and returns the `--flag` names it lists.
+pub(crate) fn probe(root: &Path, python: &str) -> Result<Vec<String>, String> {
+    let output = Command::new(python)
+        .current_dir(root)
+        .arg(root.join("run.py"))
+        .arg("--help")
+        .output()
+        .map_err(|e| format!("Failed to start python: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last = stderr.lines().last().unwrap_or_default().trim().to_string();
+        return Err(format!("run.py --help failed: {last}"));
+    }
+    Ok(parse_help(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Long option names from argparse help text, in order. Only the option list is read,
+/// not the usage summary, and `--help` itself is left out.
+pub(crate) fn parse_help(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for line in text.lines().map(str::trim_start) {
+        if !line.starts_with('-') {
+            continue;
+        }
+        // "-h, --help  show this help", "--GPU GPU  0,1,2", "-m {a,b}, --mode {a,b}":
+        // argparse puts at least two spaces before the description.
+        let invocation = line.split("  ").next().unwrap_or(line);
+        for token in invocation.split_whitespace() {
+            let token = token.trim_end_matches(',');
+            let name = token.split('=').next().unwrap_or(token);
+            if name.starts_with("--") && name != "--help" && !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
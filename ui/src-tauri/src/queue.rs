@@ -0,0 +1,80 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Runs that write into the same output folder, one after another in the order they
+/// arrived, so a second run can't clear the stage folders under the first. Runs in
+/// different folders don't wait for each other.
+#[derive(Default)]
+pub(crate) struct FolderQueue {
+    /// Output folder -> tickets of the runs using it; the front one is running.
+    waiting: Mutex<HashMap<PathBuf, VecDeque<u64>>>,
+    changed: Condvar,
+    next_ticket: AtomicU64,
+}
+
+/// How often a queued run wakes up to check whether it was cancelled.
+const WAIT_SLICE: Duration = Duration::from_millis(200);
+
+impl FolderQueue {
+    /// Blocks until every earlier run in `folder` is done. `on_wait` is called once
+    /// if there is anything to wait for. `cancelled` is polled while waiting; when it
+    /// returns a message, the run leaves the queue and that message is the error.
+    pub(crate) fn wait_turn(
+        &self,
+        folder: &Path,
+        cancelled: impl Fn() -> Option<String>,
+        on_wait: impl FnOnce(),
+    ) -> Result<Turn<'_>, String> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        waiting
+            .entry(folder.to_path_buf())
+            .or_default()
+            .push_back(ticket);
+        let turn = Turn {
+            queue: self,
+            folder: folder.to_path_buf(),
+            ticket,
+        };
+        let mut on_wait = Some(on_wait);
+        while waiting[folder].front() != Some(&ticket) {
+            if let Some(on_wait) = on_wait.take() {
+                on_wait();
+            }
+            if let Some(message) = cancelled() {
+                drop(waiting);
+                return Err(message);
+            }
+            waiting = self
+                .changed
+                .wait_timeout(waiting, WAIT_SLICE)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        Ok(turn)
+    }
+}
+
+/// A place in a [`FolderQueue`]; dropping it, whether it got its turn or not, lets
+/// the next run go.
+pub(crate) struct Turn<'a> {
+    queue: &'a FolderQueue,
+    folder: PathBuf,
+    ticket: u64,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut waiting = self.queue.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tickets) = waiting.get_mut(&self.folder) {
+            tickets.retain(|&t| t != self.ticket);
+            if tickets.is_empty() {
+                waiting.remove(&self.folder);
+            }
+        }
+        self.queue.changed.notify_all();
+    }
+}
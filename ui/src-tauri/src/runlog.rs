@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{history, resolve_output_folder, run_dir_name};
+
+/// Under the output folder. No stage or temp folder cleanup touches it.
+pub(crate) const LOG_DIR: &str = "logs";
+
+struct RunLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// The log each in-flight run writes to.
+static LOGS: Mutex<Option<HashMap<String, Arc<RunLog>>>> = Mutex::new(None);
+
+pub(crate) fn log_path(output_folder: &Path, run_id: &str) -> PathBuf {
+    output_folder
+        .join(LOG_DIR)
+        .join(format!("run_{}.log", run_dir_name(run_id)))
+}
+
+/// Starts teeing `run_id`'s output into [`log_path`] until the guard is dropped. A run
+/// repeated with the same id appends to its earlier log.
+pub(crate) fn open(output_folder: &Path, run_id: &str) -> Result<LogGuard, String> {
+    let path = log_path(output_folder, run_id);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+    let log = Arc::new(RunLog {
+        path,
+        file: Mutex::new(file),
+    });
+    LOGS.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(run_id.to_string(), log.clone());
+    Ok(LogGuard {
+        run_id: run_id.to_string(),
+        log,
+    })
+}
+
+/// Appends one line of run.py's output to `run_id`'s log, if it has one. Each line is
+/// a single write, so a crash loses at most the line being written.
+pub(crate) fn write(run_id: &str, is_error: bool, line: &str) {
+    let log = LOGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|logs| logs.get(run_id).cloned());
+    let Some(log) = log else {
+        return;
+    };
+    let prefix = if is_error { "[err]" } else { "[out]" };
+    let entry = format!("{} {prefix} {line}\n", timestamp(SystemTime::now()));
+    let mut file = log.file.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = file.write_all(entry.as_bytes()) {
+        eprintln!("Failed to write {}: {e}", log.path.display());
+    }
+}
+
+/// The log of `run_id`: the one it is writing now, or the one its history record
+/// points at.
+pub(crate) fn find(root: &Path, run_id: &str) -> Result<PathBuf, String> {
+    let live = LOGS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .and_then(|logs| logs.get(run_id).map(|log| log.path.clone()));
+    if let Some(path) = live {
+        return Ok(path);
+    }
+    let record =
+        history::find(root, run_id).ok_or_else(|| format!("No run with id {run_id} in history"))?;
+    let path = log_path(&resolve_output_folder(root, &record.args), run_id);
+    if !path.is_file() {
+        return Err(format!("No log for run {run_id}: {}", path.display()));
+    }
+    Ok(path)
+}
+
+pub(crate) struct LogGuard {
+    run_id: String,
+    log: Arc<RunLog>,
+}
+
+impl LogGuard {
+    pub(crate) fn path(&self) -> &Path {
+        &self.log.path
+    }
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        let mut logs = LOGS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(logs) = logs.as_mut() {
+            // A newer run may have taken over the id; leave its log alone.
+            if logs
+                .get(&self.run_id)
+                .is_some_and(|l| Arc::ptr_eq(l, &self.log))
+            {
+                logs.remove(&self.run_id);
+            }
+        }
+    }
+}
+
+/// `time` as UTC, e.g. `2024-05-01T09:30:00.250Z`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Days to a civil date; see Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
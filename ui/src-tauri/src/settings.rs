@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Serializes read-modify-write cycles on the settings file.
+static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Saved run settings: partial [`crate::ModifyPhotoArgs`] objects with camelCase keys,
+/// as the frontend sends them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Settings {
+    #[serde(default)]
+    pub(crate) defaults: Map<String, Value>,
+    /// GPU index -> settings that override `defaults` when that GPU is selected.
+    #[serde(default)]
+    pub(crate) gpus: BTreeMap<u32, Map<String, Value>>,
+}
+
+impl Settings {
+    /// The defaults with `gpu`'s own entries, if any, laid over them.
+    pub(crate) fn for_gpu(&self, gpu: u32) -> Map<String, Value> {
+        let mut merged = self.defaults.clone();
+        if let Some(overrides) = self.gpus.get(&gpu) {
+            merged.extend(overrides.clone());
+        }
+        merged
+    }
+}
+
+pub(crate) fn settings_path(root: &Path) -> PathBuf {
+    root.join("output_gui").join(".settings.json")
+}
+
+/// A missing or corrupt file reads as empty settings.
+pub(crate) fn load(root: &Path) -> Settings {
+    fs::read_to_string(settings_path(root))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Replaces the defaults (`gpu` is `None`) or one GPU's settings. An empty `values`
+/// removes the GPU's entry, so it falls back to the defaults again.
+pub(crate) fn store(
+    root: &Path,
+    gpu: Option<u32>,
+    values: Map<String, Value>,
+) -> Result<Settings, String> {
+    let _guard = SETTINGS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut settings = load(root);
+    match gpu {
+        None => settings.defaults = values,
+        Some(gpu) if values.is_empty() => {
+            settings.gpus.remove(&gpu);
+        }
+        Some(gpu) => {
+            settings.gpus.insert(gpu, values);
+        }
+    }
+
+    let path = settings_path(root);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {e}", parent.display()))?;
+    }
+    let text = serde_json::to_string_pretty(&settings)
+        .map_err(|e| format!("Failed to serialize settings: {e}"))?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text).map_err(|e| format!("Failed to write settings: {e}"))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("Failed to write settings: {e}"))?;
+    Ok(settings)
+}
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ProgressEvent;
+
+/// Plain log lines sent per [`WINDOW`]; the rest wait in a queue.
+const LINES_PER_WINDOW: usize = 25;
+const WINDOW: Duration = Duration::from_millis(250);
+/// Queued lines beyond this drop the oldest one.
+const QUEUE_CAPACITY: usize = 100;
+
+/// Rate-limits the log lines of one run so a chatty run.py can't flood the webview.
+/// Stage changes, errors, diagnostics and status events always go through, in order,
+/// after whatever lines are still queued; lines dropped meanwhile are announced with
+/// one "(N log lines omitted)" event carrying the count in `omitted`. A queued in-place
+/// line is replaced by the next one, so a redrawn progress bar shows its latest state.
+pub(crate) struct EventThrottle {
+    state: Mutex<State>,
+}
+
+struct State {
+    window_start: Instant,
+    sent: usize,
+    queue: VecDeque<ProgressEvent>,
+    dropped: usize,
+    stage: Option<u8>,
+}
+
+impl EventThrottle {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                window_start: Instant::now(),
+                sent: 0,
+                queue: VecDeque::new(),
+                dropped: 0,
+                stage: None,
+            }),
+        }
+    }
+
+    pub(crate) fn submit(&self, event: ProgressEvent, send: impl Fn(ProgressEvent)) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.window_start.elapsed() >= WINDOW {
+            state.window_start = Instant::now();
+            state.sent = 0;
+        }
+        while state.sent < LINES_PER_WINDOW {
+            let Some(queued) = state.queue.pop_front() else {
+                break;
+            };
+            state.sent += 1;
+            send(queued);
+        }
+
+        let is_log_line = !event.is_error
+            && event.diagnostic.is_none()
+            && event.proc_stats.is_none()
+            && event.elapsed_secs.is_none()
+            && !event.skipped
+            && !event.message.is_empty()
+            && event.stage == state.stage;
+        if !is_log_line {
+            state.flush(&event.run_id, &send);
+            state.stage = event.stage;
+            send(event);
+        } else if state.queue.is_empty() && state.sent < LINES_PER_WINDOW {
+            state.sent += 1;
+            send(event);
+        } else if event.in_place && state.queue.back().is_some_and(|last| last.in_place) {
+            *state.queue.back_mut().unwrap() = event;
+        } else {
+            if state.queue.len() == QUEUE_CAPACITY {
+                state.queue.pop_front();
+                state.dropped += 1;
+            }
+            state.queue.push_back(event);
+        }
+    }
+
+    /// Sends everything still queued; call once the run is over.
+    pub(crate) fn finish(&self, run_id: &str, send: impl Fn(ProgressEvent)) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.flush(run_id, &send);
+    }
+}
+
+impl State {
+    fn flush(&mut self, run_id: &str, send: &impl Fn(ProgressEvent)) {
+        for queued in self.queue.drain(..) {
+            send(queued);
+        }
+        if self.dropped > 0 {
+            send(ProgressEvent {
+                run_id: run_id.to_string(),
+                stage: self.stage,
+                message: format!("({} log lines omitted)", self.dropped),
+                omitted: Some(self.dropped),
+                ..Default::default()
+            });
+            self.dropped = 0;
+        }
+    }
+}
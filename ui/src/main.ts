@@ -3,7 +3,10 @@ import { listen } from "@tauri-apps/api/event";
 import { open } from "@tauri-apps/plugin-dialog";
 
 type ModifyPhotoResult = {
+  runId: string;
   outputPath: string;
+  outputs: { input: string | null; output: string }[];
+  failed: [string, string][];
 };
 
 type ProgressEvent = {
@@ -11,6 +14,32 @@ type ProgressEvent = {
   stage: number | null;
   message: string;
   isError: boolean;
+  diagnostic: { kind: string; detail: string; hint: string } | null;
+  procStats: { cpuPercent: number; rssBytes: number } | null;
+  elapsedSecs: number | null;
+  hook: boolean;
+  skipped: boolean;
+  omitted: number | null;
+  deviceSelected: string | null;
+  percent: number | null;
+  stagePercent: number | null;
+  inPlace: boolean;
+  fileIndex: number | null;
+  fileTotal: number | null;
+  etaSecs: number | null;
+};
+
+/** What `modify_photo` rejects with; `code` is only set for `pythonExited`. */
+type RunError = {
+  kind: string;
+  message: string;
+  code?: number;
+};
+
+type PreviewEvent = {
+  runId: string;
+  stage: number;
+  imagePath: string;
 };
 
 function el<T extends HTMLElement>(selector: string): T {
@@ -37,6 +66,12 @@ function setPreview(img: HTMLImageElement, placeholder: HTMLElement, filePath: s
   placeholder.classList.remove("visible");
 }
 
+function formatEta(secs: number): string {
+  if (secs < 60) return `${secs}s`;
+  const minutes = Math.round(secs / 60);
+  return minutes < 60 ? `${minutes} min` : `${Math.floor(minutes / 60)} h ${minutes % 60} min`;
+}
+
 function normalizePath(p: unknown): string | null {
   if (!p) return null;
   if (typeof p === "string") return p;
@@ -66,6 +101,7 @@ window.addEventListener("DOMContentLoaded", () => {
   let lastStage: number | null = null;
   let pendingLogLines: string[] = [];
   let flushTimer: number | null = null;
+  let lastLineInPlace = false;
 
   function setProgress(stage: number | null, text: string) {
     const pct = stage == null ? 0 : Math.max(0, Math.min(4, stage)) * 25;
@@ -76,6 +112,7 @@ window.addEventListener("DOMContentLoaded", () => {
   function resetLog() {
     logLines = [];
     pendingLogLines = [];
+    lastLineInPlace = false;
     if (flushTimer != null) {
       window.clearTimeout(flushTimer);
       flushTimer = null;
@@ -83,7 +120,13 @@ window.addEventListener("DOMContentLoaded", () => {
     logBox.textContent = "";
   }
 
-  function appendLog(line: string) {
+  function appendLog(line: string, inPlace = false) {
+    // A redrawn progress bar replaces its previous state instead of adding a line.
+    if (inPlace && lastLineInPlace) {
+      if (pendingLogLines.length > 0) pendingLogLines.pop();
+      else logLines.pop();
+    }
+    lastLineInPlace = inPlace;
     pendingLogLines.push(line);
     if (flushTimer != null) return;
     flushTimer = window.setTimeout(() => {
@@ -108,13 +151,39 @@ window.addEventListener("DOMContentLoaded", () => {
       else if (payload.stage >= 4) setProgress(4, "Finishing...");
       else setProgress(payload.stage, `Stage ${payload.stage}/4`);
     }
+    if (payload.fileIndex != null && payload.fileTotal != null && lastStage != null) {
+      if (lastStage > 0 && lastStage < 4) {
+        progressText.textContent = `Photo ${payload.fileIndex}/${payload.fileTotal}, Stage ${lastStage}/4`;
+      }
+    }
+    if (payload.stagePercent != null && payload.stage != null && payload.stage > 0) {
+      const done = Math.min(4, payload.stage - 1 + payload.stagePercent / 100);
+      progressFill.style.width = `${done * 25}%`;
+    }
+    if (payload.percent != null) {
+      progressFill.style.width = `${payload.percent}%`;
+    }
+    if (payload.etaSecs != null && lastStage != null && lastStage < 4) {
+      setStatus(`Running... about ${formatEta(payload.etaSecs)} left`);
+    }
     if (payload.message) {
-      appendLog(payload.isError ? `[stderr] ${payload.message}` : payload.message);
+      appendLog(
+        payload.isError ? `[stderr] ${payload.message}` : payload.message,
+        payload.inPlace,
+      );
     }
   }).catch((e) => {
     setStatus(`Error: ${String(e)}`);
   });
 
+  listen<PreviewEvent>("stage_preview", (event) => {
+    const payload = event.payload;
+    if (!payload || !activeRunId || payload.runId !== activeRunId) return;
+    setPreview(outputImg, outputPlaceholder, payload.imagePath);
+  }).catch((e) => {
+    setStatus(`Error: ${String(e)}`);
+  });
+
   async function pickInputFile() {
     const picked = await open({
       multiple: false,
@@ -162,7 +231,8 @@ window.addEventListener("DOMContentLoaded", () => {
       setProgress(4, "Done");
       setStatus(`Done: ${res.outputPath.split("/").pop() ?? res.outputPath}`);
     } catch (e) {
-      const msg = e instanceof Error ? e.message : String(e);
+      const msg =
+        e instanceof Error ? e.message : ((e as RunError | null)?.message ?? String(e));
       setProgress(null, "Error");
       setStatus(`Error: ${msg}`);
     } finally {